@@ -1,12 +1,9 @@
-#[macro_use]
-extern crate serde_derive;
-
 use ramp::island::{Island, IslandEnv, IslandFactory};
+use ramp::simulation::SimulationBuilder;
 use uuid::Uuid;
 
 use crate::map_island::MapIsland;
 
-mod map_agent;
 mod map_island;
 
 struct MapIslandFactory;
@@ -19,6 +16,11 @@ impl IslandFactory for MapIslandFactory {
 }
 
 fn main() {
-    //let factory = MapIslandFactory {};
-    //Simulation::start_simulation(Box::new(factory));
+    let factory = MapIslandFactory {};
+    SimulationBuilder::new()
+        .settings_file("LocalSettings.toml")
+        .factory(Box::new(factory))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
 }
@@ -1,3 +0,0 @@
-struct MapAgent {
-    position: (u32, u32),
-}
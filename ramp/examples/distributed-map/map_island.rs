@@ -6,7 +6,7 @@ use uuid::Uuid;
 pub struct MapIsland {
     id: Uuid,
     island_env: IslandEnv,
-    map: Option<MapInstance>,
+    map: Option<MapInstance<i32>>,
 }
 
 impl Island for MapIsland {
@@ -16,16 +16,20 @@ impl Island for MapIsland {
         self.map = Some(map);
     }
 
-    fn do_turn(&mut self, _turn_number: u32, _messages: Vec<Message>) {
-        // here user has to receive MapSet msg and insert received value into map
-        //        for msg in messages {
-        //            match msg {
-        //                Message::MapSet(x, y, val) => {
-        //                    self.map.as_mut().map(|map| map.set(&mut self.island_env, x, y, val));
-        //                }
-        //                _ => println!("Other msg")
-        //            }
-        //        }
+    fn do_turn(&mut self, _turn_number: u32, mut messages: Vec<Message>) {
+        if let Some(map) = self.map.as_mut() {
+            map.handle_messages(&mut self.island_env, &mut messages);
+            for cell in map.take_values() {
+                log::info!(
+                    "Island {} read cell ({}, {}) holding {:?}",
+                    self.id,
+                    cell.x,
+                    cell.y,
+                    cell.value
+                );
+            }
+            map.flush(&mut self.island_env);
+        }
     }
 
     fn on_finish(&mut self) {}
@@ -0,0 +1,36 @@
+/// State of the grass in a cell of the map.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Grass {
+    Grown,
+    /// Eaten in this turn, starts growing in the next one.
+    Eaten,
+    /// Turns left until the grass is grown again.
+    Growing(i32),
+}
+
+impl Grass {
+    /// State of the grass in the next turn.
+    pub fn grow(self, grass_interval: i32) -> Grass {
+        match self {
+            Grass::Grown | Grass::Growing(1) => Grass::Grown,
+            Grass::Eaten => Grass::Growing(grass_interval + 1),
+            Grass::Growing(turns) => Grass::Growing(turns - 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Grass;
+
+    #[test]
+    fn test_grass_grows_back() {
+        let mut grass = Grass::Eaten;
+        for turns in (1..=3).rev() {
+            grass = grass.grow(2);
+            assert_eq!(grass, Grass::Growing(turns));
+        }
+        assert_eq!(grass.grow(2), Grass::Grown);
+        assert_eq!(Grass::Grown.grow(2), Grass::Grown);
+    }
+}
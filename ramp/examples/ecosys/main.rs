@@ -2,25 +2,50 @@
 extern crate serde_derive;
 
 use ramp::island::{Island, IslandEnv, IslandFactory};
-use ramp::simulation::Simulation;
+use ramp::simulation::SimulationBuilder;
 use ramp::utils;
+use std::process;
 use std::sync::Arc;
 use uuid::Uuid;
 
 use crate::settings::SimulationSettings;
 use crate::ws_island::WSIsland;
 use ramp::metrics::MetricHub;
+use ramp::outcome::{IslandResult, Reducer};
 
 mod agent_types;
+mod grass;
 mod settings;
 mod sheep;
 mod wolves;
 mod ws_island;
 mod ws_utils;
 
-struct WSIslandFactory;
+struct WSIslandFactory {
+    settings: SimulationSettings,
+}
+const LOGGER_LEVEL: &str = "info";
 const EXPECTED_ARGS_NUM: usize = 3;
 
+/// Counts sheep and wolves left on all islands.
+struct Population;
+
+impl Reducer for Population {
+    type Value = (u64, u64);
+
+    fn reduce(&self, results: &[IslandResult<(u64, u64)>]) -> String {
+        let (sheep, wolves) = results.iter().fold((0, 0), |(sheep, wolves), result| {
+            (sheep + result.value.0, wolves + result.value.1)
+        });
+        format!(
+            "{} sheep and {} wolves left on {} islands",
+            sheep,
+            wolves,
+            results.len()
+        )
+    }
+}
+
 // MN - metric name
 const WOLVES_MN: &str = "wolves";
 const SHEEP_MN: &str = "sheep";
@@ -30,16 +55,12 @@ const ISLAND_ID_LN: &str = "island_id";
 
 impl IslandFactory for WSIslandFactory {
     fn create(&self, id: Uuid, island_env: IslandEnv) -> Box<dyn Island> {
-        let args: Vec<String> = utils::parse_input_args(EXPECTED_ARGS_NUM);
-        let settings_file_name = args[2].clone();
-        let settings = load_settings(settings_file_name.clone());
-
         let island = WSIsland::new(
             id,
             island_env,
-            Arc::new(settings.island_settings),
-            Arc::new(settings.sheep_settings),
-            Arc::new(settings.wolf_settings),
+            Arc::new(self.settings.island_settings),
+            Arc::new(self.settings.sheep_settings),
+            Arc::new(self.settings.wolf_settings),
         );
 
         Box::new(island)
@@ -47,15 +68,32 @@ impl IslandFactory for WSIslandFactory {
 }
 
 fn main() {
+    let args: Vec<String> = utils::parse_input_args(EXPECTED_ARGS_NUM);
+    let settings = match SimulationSettings::new(args[2].clone()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid simulation settings: {}", e);
+            process::exit(1);
+        }
+    };
+
     let mut metrics = MetricHub::default();
     register_metrics(&mut metrics);
 
-    let factory = WSIslandFactory {};
-    Simulation::start_simulation(Box::new(factory), metrics);
-}
+    let factory = WSIslandFactory { settings };
+    let result = SimulationBuilder::new()
+        .settings_file(&args[1])
+        .factory(Box::new(factory))
+        .metrics(metrics)
+        .reducer(Population)
+        .logger_level(LOGGER_LEVEL)
+        .build()
+        .and_then(|simulation| simulation.run());
 
-fn load_settings(file_name: String) -> SimulationSettings {
-    SimulationSettings::new(file_name).unwrap()
+    if let Err(e) = result {
+        eprintln!("Simulation failed: {}", e);
+        process::exit(1);
+    }
 }
 
 fn register_metrics(metrics: &mut MetricHub) {
@@ -1,23 +1,26 @@
 use crate::ws_utils;
+use ramp::map::Map;
+use rand::Rng;
 use std::collections::HashMap;
 use std::ops::Range;
 use uuid::Uuid;
 
 type Position = (i64, i64);
 
+#[derive(Serialize, Deserialize)]
 pub struct Sheep {
     pub id: Vec<Uuid>,
     pub energy: HashMap<Uuid, i64>,
     pub position: HashMap<Uuid, Position>,
 }
 impl Sheep {
-    pub fn new(init_num: u32, init_energy: i64) -> Self {
+    pub fn new(init_num: u32, init_energy: i64, mut new_id: impl FnMut() -> Uuid) -> Self {
         let mut id = vec![];
         let mut energy = HashMap::new();
         let position = HashMap::new();
 
         for _i in 0..init_num {
-            let new_sheep = Uuid::new_v4();
+            let new_sheep = new_id();
             id.push(new_sheep);
             energy.insert(new_sheep, init_energy);
         }
@@ -40,22 +43,17 @@ impl Sheep {
         self.energy.remove(id);
     }
 
-    pub fn set_initial_sheep_positions(&mut self, range: Range<u64>, chunk_len: i64) {
+    pub fn set_initial_sheep_positions(
+        &mut self,
+        range: Range<u64>,
+        map: &Map,
+        rng: &mut impl Rng,
+    ) {
         for id in self.id.iter() {
-            let (x, y) = ws_utils::generate_random_position(&range, chunk_len);
+            let (x, y) = ws_utils::generate_random_position(&range, map, rng);
             self.position.insert(*id, (x, y));
         }
     }
-
-    pub fn print_sheep(&self, id: &Uuid) {
-        println!(
-            "<--------------- Sheep {} -------------------->",
-            &id.to_string()[..8]
-        );
-        println!("Energy: {:?}", self.energy.get(id).unwrap());
-        println!("Position: {:?}", self.position.get(id).unwrap());
-        println!("<---------------------------------------------------->");
-    }
 }
 
 #[cfg(test)]
@@ -65,7 +63,7 @@ mod tests {
 
     #[test]
     fn test_add_remove_sheep() {
-        let mut sheep = Sheep::new(0, 10);
+        let mut sheep = Sheep::new(0, 10, Uuid::new_v4);
 
         assert_eq!(sheep.id.len(), 0);
         assert_eq!(sheep.energy.len(), 0);
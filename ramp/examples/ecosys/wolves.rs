@@ -1,10 +1,13 @@
 use crate::ws_utils;
+use ramp::map::Map;
+use rand::Rng;
 use std::collections::HashMap;
 use std::ops::Range;
 use uuid::Uuid;
 
 type Position = (i64, i64);
 
+#[derive(Serialize, Deserialize)]
 pub struct Wolves {
     pub id: Vec<Uuid>,
     pub energy: HashMap<Uuid, i64>,
@@ -12,13 +15,13 @@ pub struct Wolves {
 }
 
 impl Wolves {
-    pub fn new(init_num: u32, init_energy: i64) -> Self {
+    pub fn new(init_num: u32, init_energy: i64, mut new_id: impl FnMut() -> Uuid) -> Self {
         let mut id = vec![];
         let mut energy = HashMap::new();
         let position = HashMap::new();
 
         for _i in 0..init_num {
-            let new_sheep = Uuid::new_v4();
+            let new_sheep = new_id();
             id.push(new_sheep);
             energy.insert(new_sheep, init_energy);
         }
@@ -41,9 +44,9 @@ impl Wolves {
         self.energy.remove(id);
     }
 
-    pub fn set_initial_wolf_positions(&mut self, range: Range<u64>, chunk_len: i64) {
+    pub fn set_initial_wolf_positions(&mut self, range: Range<u64>, map: &Map, rng: &mut impl Rng) {
         for id in self.id.iter() {
-            let (x, y) = ws_utils::generate_random_position(&range, chunk_len);
+            let (x, y) = ws_utils::generate_random_position(&range, map, rng);
             self.position.insert(*id, (x, y));
         }
     }
@@ -56,7 +59,7 @@ mod tests {
 
     #[test]
     fn test_add_remove_wolf() {
-        let mut wolves = Wolves::new(0, 10);
+        let mut wolves = Wolves::new(0, 10, Uuid::new_v4);
 
         assert_eq!(wolves.id.len(), 0);
         assert_eq!(wolves.energy.len(), 0);
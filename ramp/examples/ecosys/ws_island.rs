@@ -1,23 +1,29 @@
 use crate::agent_types::AgentType;
+use crate::grass::Grass;
 use crate::settings::{IslandSettings, SheepSettings, WolfSettings};
 use crate::sheep::Sheep;
 use crate::utils;
 use crate::wolves::Wolves;
-use crate::ws_utils;
+use crate::ws_utils::{self, SerializedAgent};
 use crate::SHEEP_MN;
 use crate::WOLVES_MN;
 use rand::Rng;
+use ramp::error::RampError;
 use ramp::island::{Island, IslandEnv};
-use ramp::map::{FragmentOwner, MapInstance};
+use ramp::map::{FragmentOwner, Location, MapInstance};
 use ramp::message::Message;
-use std::ops::Range;
+use ramp::migration::{MigrationEnv, MigrationTarget};
+use ramp::topology::{Direction, Position};
+use std::mem;
 use std::sync::Arc;
 use uuid::Uuid;
 
+const AGENT_KIND: &str = "ecosys/agent";
+
 pub struct WSIsland {
     pub id: Uuid,
-    island_env: IslandEnv,
-    map: Option<MapInstance>,
+    island_env: MigrationEnv<SerializedAgent>,
+    map: Option<MapInstance<Grass>>,
     pub island_settings: Arc<IslandSettings>,
     pub sheep_settings: Arc<SheepSettings>,
     pub wolf_settings: Arc<WolfSettings>,
@@ -33,41 +39,38 @@ pub struct WSIsland {
     pub new_sheep: Vec<Uuid>,
     pub new_wolves: Vec<Uuid>,
 }
-enum MoveVector {
-    North,
-    East,
-    South,
-    West,
-    None,
-}
-enum BoundaryCheck {
-    InBoundary,
-    OutBoundaryLocal(FragmentOwner),
-    OutBoundaryGlobal(FragmentOwner),
-    Impossible,
-}
 
 impl Island for WSIsland {
     fn on_start(&mut self) {
-        let map = MapInstance::get_instance(&self.island_env);
+        let map = MapInstance::get_instance(&self.island_env).with_agent_index();
         log::warn!("{:#?}", map);
         self.map = Some(map);
 
-        let range = self.map.as_ref().unwrap().get_my_range();
+        let range = self.map.as_ref().unwrap().get_my_range().unwrap();
         self.sheep.set_initial_sheep_positions(
             range.clone(),
-            self.map.as_ref().unwrap().map.chunk_len.clone(),
+            &self.map.as_ref().unwrap().map,
+            self.island_env.rng(),
         );
         self.wolves.set_initial_wolf_positions(
             range.clone(),
-            self.map.as_ref().unwrap().map.chunk_len.clone(),
+            &self.map.as_ref().unwrap().map,
+            self.island_env.rng(),
         );
 
-        self.map.as_mut().unwrap().init_with_val(0);
+        self.map
+            .as_mut()
+            .unwrap()
+            .init_with_val(Grass::Grown)
+            .unwrap();
+        self.index_sheep();
     }
 
-    fn do_turn(&mut self, turn_number: u32, messages: Vec<Message>) {
+    fn do_turn(&mut self, turn_number: u32, mut messages: Vec<Message>) {
         log::warn!("================================== TURN {} ON ISLAND {}: ===============================", turn_number, &self.id.to_string()[..8]);
+        if let Some(map) = self.map.as_mut() {
+            map.handle_messages(&mut self.island_env, &mut messages);
+        }
         self.receive_migrants(messages);
         self.do_sheep_turn();
         self.do_wolf_turn();
@@ -76,33 +79,71 @@ impl Island for WSIsland {
         self.send_global_migrants();
         self.add_new_agents();
         self.remove_dead_agents();
+        if let Some(map) = self.map.as_mut() {
+            map.flush(&mut self.island_env);
+        }
         self.clear_queues();
         self.display_turn_stats();
         self.update_metrics();
+        self.record_results(turn_number);
     }
 
     fn on_finish(&mut self) {
         let _duration = self.island_env.start_time.elapsed().as_secs();
         self.display_final_stats();
     }
+
+    fn result(&self) -> Option<Vec<u8>> {
+        let population = (self.sheep.id.len() as u64, self.wolves.id.len() as u64);
+        self.island_env.encode_result(&population).ok()
+    }
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        let map_data = &self.map.as_ref()?.data;
+        self.island_env
+            .encode_snapshot(&(&self.sheep, &self.wolves, map_data))
+            .ok()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), RampError> {
+        let (sheep, wolves, map_data): (Sheep, Wolves, Vec<Grass>) =
+            self.island_env.decode_snapshot(snapshot)?;
+        let mut map = MapInstance::get_instance(&self.island_env).with_agent_index();
+        map.data = map_data;
+        self.map = Some(map);
+        self.sheep = sheep;
+        self.wolves = wolves;
+        self.index_sheep();
+        Ok(())
+    }
+
+    fn agent_count(&self) -> Option<u64> {
+        Some((self.sheep.id.len() + self.wolves.id.len()) as u64)
+    }
 }
 impl WSIsland {
     pub fn new(
         id: Uuid,
-        island_env: IslandEnv,
+        mut island_env: IslandEnv,
         island_settings: Arc<IslandSettings>,
         sheep_settings: Arc<SheepSettings>,
         wolf_settings: Arc<WolfSettings>,
     ) -> Self {
+        let sheep = Sheep::new(sheep_settings.init_num, sheep_settings.init_energy, || {
+            island_env.new_id()
+        });
+        let wolves = Wolves::new(wolf_settings.init_num, wolf_settings.init_energy, || {
+            island_env.new_id()
+        });
         Self {
             id,
-            island_env,
+            island_env: MigrationEnv::new(island_env, AGENT_KIND),
             map: None,
             island_settings: island_settings.clone(),
             sheep_settings: sheep_settings.clone(),
             wolf_settings: wolf_settings.clone(),
-            sheep: Sheep::new(sheep_settings.init_num, sheep_settings.init_energy),
-            wolves: Wolves::new(wolf_settings.init_num, wolf_settings.init_energy),
+            sheep,
+            wolves,
             remove_sheep: vec![],
             remove_wolves: vec![],
             outgoing_local: vec![],
@@ -116,18 +157,23 @@ impl WSIsland {
     //========================================= Turn methods ==========================================
     //=================================================================================================
 
-    fn receive_migrants(&mut self, messages: Vec<Message>) {
+    fn receive_migrants(&mut self, mut messages: Vec<Message>) {
         log::info!("Receiving migrants in {}", &self.id.to_string()[..8]);
         let mut migrants_num = 0;
-        for message in messages {
-            match message {
-                Message::Agent(migrant) => {
+        for migrant in self.island_env.received_migrants(&mut messages) {
+            match migrant {
+                Ok(SerializedAgent {
+                    agent_type,
+                    id,
+                    energy,
+                    position,
+                }) => {
                     migrants_num += 1;
-                    let (agent_type, id, energy, position) = ws_utils::deserialize(migrant);
                     match agent_type {
                         AgentType::Sheep => {
                             log::debug!("Received new sheep {} with position {:?}", id, position);
-                            self.sheep.add_sheep(id, energy, position)
+                            self.sheep.add_sheep(id, energy, position);
+                            self.place_sheep(id, position);
                         }
                         AgentType::Wolf => {
                             log::debug!("Received new wolf {} with position {:?}", id, position);
@@ -135,9 +181,12 @@ impl WSIsland {
                         }
                     }
                 }
-                _ => log::error!("Unexpected msg"),
+                Err(e) => log::error!("Cannot receive migrant: {}", e),
             }
         }
+        for _message in messages {
+            log::error!("Unexpected msg");
+        }
         log::info!("Total number of migrants received: {}", migrants_num);
     }
 
@@ -150,55 +199,57 @@ impl WSIsland {
             );
             return;
         }
-        let range = self.map.as_ref().unwrap().get_my_range();
         for sheep in &self.sheep.id {
-            //self.sheep.print_sheep(sheep);
-
             let grass = self
                 .map
                 .as_ref()
                 .unwrap()
                 .get_value(*self.sheep.position.get(sheep).unwrap());
-            if grass == 0 {
+            if grass == Some(&Grass::Grown) {
                 *self.sheep.energy.get_mut(sheep).unwrap() += self.sheep_settings.energy_gain;
                 self.map
                     .as_mut()
                     .unwrap()
-                    .update_value(-1, *self.sheep.position.get(sheep).unwrap());
+                    .update_value(Grass::Eaten, *self.sheep.position.get(sheep).unwrap());
             }
 
-            if self.is_sheep_reproducing() {
+            if Self::is_reproducing(
+                self.island_env.rng(),
+                self.sheep_settings.reproduction_chance,
+            ) {
                 log::debug!("Sheep {} is reproducing", &sheep.to_string()[..8]);
-                self.new_sheep.push(Uuid::new_v4());
+                self.new_sheep.push(self.island_env.new_id());
             }
 
-            let curr_pos = *self.sheep.position.get(&sheep).unwrap();
-            let move_vector = self.get_random_movement_dir();
-            let new_pos = self.get_new_position(curr_pos, move_vector);
+            let curr_pos = *self.sheep.position.get(sheep).unwrap();
+            let direction = Self::get_random_movement_dir(self.island_env.rng());
+            let map = self.map.as_mut().unwrap();
+            let new_pos = map.map.neighbour(curr_pos, direction).unwrap_or(curr_pos);
+            let location = match map.move_agent(*sheep, new_pos) {
+                Ok(location) => location,
+                // Sheep forwarded to the owner of its cell
+                Err(_) => continue,
+            };
             log::debug!("The new position for this sheep is to be {:?}", new_pos);
 
-            log::debug!("Verifying range {:?} for pos {:?}", &range, &new_pos);
-            let move_action =
-                self.determine_move_action(&new_pos, &range, &self.map.as_ref().unwrap());
-
-            *self.sheep.position.get_mut(&sheep).unwrap() = new_pos;
+            *self.sheep.position.get_mut(sheep).unwrap() = new_pos;
             *self.sheep.energy.get_mut(sheep).unwrap() -= self.sheep_settings.energy_loss;
-            if *self.sheep.energy.get(&sheep).unwrap() <= 0 {
+            if *self.sheep.energy.get(sheep).unwrap() <= 0 {
                 self.remove_sheep.push(*sheep);
                 return;
             }
 
-            match move_action {
-                BoundaryCheck::InBoundary => {
+            match location {
+                Location::Own => {
                     log::debug!("This position is in the current range ");
                 }
-                BoundaryCheck::OutBoundaryLocal(owner) => {
+                Location::Local(owner) => {
                     log::debug!("Sending to local island {} ", &owner.2.to_string()[..8]);
                     self.outgoing_local.push((AgentType::Sheep, *sheep, owner));
                     self.remove_sheep.push(*sheep);
                 }
-                BoundaryCheck::OutBoundaryGlobal(owner) => {
-                    let address = &format!("{}:{}", owner.0.to_string(), owner.1.to_string());
+                Location::Remote(owner) => {
+                    let address = &format!("{}:{}", owner.0, owner.1);
                     log::debug!(
                         "Sent to host {} to island {}",
                         address,
@@ -207,9 +258,9 @@ impl WSIsland {
                     self.outgoing_global.push((AgentType::Sheep, *sheep, owner));
                     self.remove_sheep.push(*sheep);
                 }
-                _ => {
+                Location::Unreachable => {
                     log::debug!("Move out of bounds - sheep stays where it is");
-                    *self.sheep.position.get_mut(&sheep).unwrap() = curr_pos;
+                    *self.sheep.position.get_mut(sheep).unwrap() = curr_pos;
                 }
             }
         }
@@ -224,46 +275,43 @@ impl WSIsland {
             );
             return;
         }
-        let range = self.map.as_ref().unwrap().get_my_range();
         for wolf in &self.wolves.id {
             let prey = self.check_for_sheep_at_position(*self.wolves.position.get(wolf).unwrap());
-            if prey != None {
+            if let Some(prey) = prey {
                 log::debug!(
                     "Wolf {} is consuming sheep {}",
                     &wolf.to_string()[..8],
-                    &prey.unwrap().to_string()[..8]
+                    &prey.to_string()[..8]
                 );
-                self.remove_sheep.push(prey.unwrap());
+                self.remove_sheep.push(prey);
                 *self.wolves.energy.get_mut(wolf).unwrap() += self.wolf_settings.energy_gain;
             }
 
-            if self.is_wolf_reproducing() {
+            if Self::is_reproducing(
+                self.island_env.rng(),
+                self.wolf_settings.reproduction_chance,
+            ) {
                 log::debug!("Wolf {} is reproducing", &wolf.to_string()[..8]);
-                self.new_wolves.push(Uuid::new_v4());
+                self.new_wolves.push(self.island_env.new_id());
             }
 
-            let curr_pos = *self.wolves.position.get(&wolf).unwrap();
-            let move_vector = self.get_random_movement_dir();
-            let new_pos = self.get_new_position(curr_pos, move_vector);
+            let curr_pos = *self.wolves.position.get(wolf).unwrap();
+            let direction = Self::get_random_movement_dir(self.island_env.rng());
+            let (new_pos, location) = self.locate_move(curr_pos, direction);
             log::debug!("The new position for this wolf is to be {:?}", new_pos);
 
-            log::debug!("Verifying range {:?} for pos {:?}", &range, &new_pos);
-
-            let move_action =
-                self.determine_move_action(&new_pos, &range, &self.map.as_ref().unwrap());
-
-            *self.wolves.position.get_mut(&wolf).unwrap() = new_pos;
+            *self.wolves.position.get_mut(wolf).unwrap() = new_pos;
             *self.wolves.energy.get_mut(wolf).unwrap() -= self.wolf_settings.energy_loss;
-            if *self.wolves.energy.get(&wolf).unwrap() <= 0 {
+            if *self.wolves.energy.get(wolf).unwrap() <= 0 {
                 self.remove_wolves.push(*wolf);
                 return;
             }
 
-            match move_action {
-                BoundaryCheck::InBoundary => {
+            match location {
+                Location::Own => {
                     log::debug!("This position is in the current range ");
                 }
-                BoundaryCheck::OutBoundaryLocal(owner) => {
+                Location::Local(owner) => {
                     log::debug!(
                         "This should be sent to local island {} ",
                         &owner.2.to_string()[..8]
@@ -271,8 +319,8 @@ impl WSIsland {
                     self.outgoing_local.push((AgentType::Wolf, *wolf, owner));
                     self.remove_wolves.push(*wolf);
                 }
-                BoundaryCheck::OutBoundaryGlobal(owner) => {
-                    let address = &format!("{}:{}", owner.0.to_string(), owner.1.to_string());
+                Location::Remote(owner) => {
+                    let address = &format!("{}:{}", owner.0, owner.1);
                     log::debug!(
                         "This should be sent to host {} to island {}",
                         address,
@@ -281,9 +329,9 @@ impl WSIsland {
                     self.outgoing_global.push((AgentType::Wolf, *wolf, owner));
                     self.remove_wolves.push(*wolf);
                 }
-                _ => {
-                    log::debug!("Move out of bounds - sheep therefore stays where it is");
-                    *self.wolves.position.get_mut(&wolf).unwrap() = curr_pos;
+                Location::Unreachable => {
+                    log::debug!("Move out of bounds - wolf therefore stays where it is");
+                    *self.wolves.position.get_mut(wolf).unwrap() = curr_pos;
                 }
             }
         }
@@ -291,111 +339,106 @@ impl WSIsland {
 
     fn update_grass(&mut self) {
         log::debug!("Updating grass");
-        for val in self.map.as_mut().unwrap().data.iter_mut() {
-            log::debug!("Value before: {}", *val);
-            if *val == -1 {
-                *val = self.island_settings.grass_interval + 1;
-                log::debug!("Value after: {}", *val);
-            } else if *val == 0 {
-                continue;
-            } else if 0 < *val && *val <= self.island_settings.grass_interval + 1 {
-                *val = *val - 1;
-                log::debug!("Value after: {}", *val);
-            }
+        for grass in self.map.as_mut().unwrap().data.iter_mut() {
+            *grass = grass.grow(self.island_settings.grass_interval);
         }
     }
 
     fn send_local_migrants(&mut self) {
         for (agent_type, id, (_, _, island_id)) in self.outgoing_local.iter() {
-            let serialized;
-            match agent_type {
+            let serialized = match agent_type {
                 AgentType::Sheep => {
                     log::debug!(
                         "Sending sheep {} with position {:?} to local island",
                         &id.to_string()[..8],
-                        *self.sheep.position.get(&id).unwrap()
+                        *self.sheep.position.get(id).unwrap()
                     );
-                    serialized = ws_utils::serialize(
+                    ws_utils::serialize(
                         AgentType::Sheep,
                         *id,
                         *self.sheep.energy.get(id).unwrap(),
                         *self.sheep.position.get(id).unwrap(),
-                    );
+                    )
                 }
                 AgentType::Wolf => {
                     log::debug!(
                         "Sending wolf {} with position {:?} to local island",
                         &id.to_string()[..8],
-                        *self.wolves.position.get(&id).unwrap()
+                        *self.wolves.position.get(id).unwrap()
                     );
-                    serialized = ws_utils::serialize(
+                    ws_utils::serialize(
                         AgentType::Wolf,
                         *id,
                         *self.wolves.energy.get(id).unwrap(),
                         *self.wolves.position.get(id).unwrap(),
-                    );
+                    )
                 }
-            }
+            };
             self.island_env
-                .send_to_local(*island_id, Message::Agent(serialized))
+                .migrate(&serialized, MigrationTarget::Local(*island_id))
                 .expect("Error sending local migrant");
         }
     }
 
     fn send_global_migrants(&mut self) {
         for (agent_type, id, (ip, port, _island_id)) in self.outgoing_global.iter() {
-            let serialized;
-            match agent_type {
+            let serialized = match agent_type {
                 AgentType::Sheep => {
                     log::debug!(
                         "Sending sheep {} with position {:?} to another host",
                         &id.to_string()[..8],
-                        *self.sheep.position.get(&id).unwrap()
+                        *self.sheep.position.get(id).unwrap()
                     );
-                    serialized = ws_utils::serialize(
+                    ws_utils::serialize(
                         AgentType::Sheep,
                         *id,
                         *self.sheep.energy.get(id).unwrap(),
                         *self.sheep.position.get(id).unwrap(),
-                    );
+                    )
                 }
                 AgentType::Wolf => {
                     log::debug!(
                         "Sending wolf {} with position {:?} to another host",
                         &id.to_string()[..8],
-                        *self.wolves.position.get(&id).unwrap()
+                        *self.wolves.position.get(id).unwrap()
                     );
-                    serialized = ws_utils::serialize(
+                    ws_utils::serialize(
                         AgentType::Wolf,
                         *id,
                         *self.wolves.energy.get(id).unwrap(),
                         *self.wolves.position.get(id).unwrap(),
-                    );
+                    )
                 }
-            }
+            };
             self.island_env
-                .send_to_global((ip.clone(), *port), Message::Agent(serialized));
+                .migrate(&serialized, MigrationTarget::Host((ip.clone(), *port)))
+                .expect("Error sending global migrant");
         }
     }
 
     fn add_new_agents(&mut self) {
-        for new_sheep in self.new_sheep.iter_mut() {
-            self.sheep.add_sheep(
-                *new_sheep,
-                self.sheep_settings.init_energy,
-                ws_utils::generate_random_position(
-                    &self.map.as_ref().unwrap().get_my_range(),
-                    self.map.as_ref().unwrap().map.chunk_len.clone(),
-                ),
+        let range = match self.map.as_ref().unwrap().get_my_range() {
+            Some(range) => range,
+            None => return,
+        };
+        for new_sheep in mem::take(&mut self.new_sheep) {
+            let position = ws_utils::generate_random_position(
+                &range,
+                &self.map.as_ref().unwrap().map,
+                self.island_env.rng(),
             );
+            self.sheep
+                .add_sheep(new_sheep, self.sheep_settings.init_energy, position);
+            self.place_sheep(new_sheep, position);
         }
         for new_wolf in self.new_wolves.iter_mut() {
             self.wolves.add_wolf(
                 *new_wolf,
                 self.wolf_settings.init_energy,
                 ws_utils::generate_random_position(
-                    &self.map.as_ref().unwrap().get_my_range(),
-                    self.map.as_ref().unwrap().map.chunk_len.clone(),
+                    &range,
+                    &self.map.as_ref().unwrap().map,
+                    self.island_env.rng(),
                 ),
             );
         }
@@ -405,6 +448,7 @@ impl WSIsland {
         log::debug!("Agents to be removed {:?}", &self.remove_sheep);
         for dead_agent in self.remove_sheep.iter() {
             self.sheep.remove_sheep(dead_agent);
+            self.map.as_mut().unwrap().remove_agent(*dead_agent);
         }
         log::debug!("Sheep after removal: {:?}", &self.sheep.id);
         for dead_agent in self.remove_wolves.iter() {
@@ -426,79 +470,58 @@ impl WSIsland {
     //========================================= Helper methods ========================================
     //=================================================================================================
 
-    fn is_sheep_reproducing(&self) -> bool {
-        let mut rng = rand::thread_rng();
+    fn is_reproducing(rng: &mut impl Rng, reproduction_chance: f64) -> bool {
         let chance = rng.gen_range(0.0, 1.0);
-        chance <= self.sheep_settings.reproduction_chance
+        chance <= reproduction_chance
     }
 
-    fn is_wolf_reproducing(&self) -> bool {
-        let mut rng = rand::thread_rng();
-        let chance = rng.gen_range(0.0, 1.0);
-        chance <= self.wolf_settings.reproduction_chance
+    fn get_random_movement_dir(rng: &mut impl Rng) -> Direction {
+        Direction::CARDINAL[rng.gen_range(0, Direction::CARDINAL.len())]
     }
 
-    fn get_random_movement_dir(&self) -> MoveVector {
-        let mut rng = rand::thread_rng();
-        let dir = rng.gen_range(0, 4);
-        match dir {
-            0 => MoveVector::North,
-            1 => MoveVector::East,
-            2 => MoveVector::South,
-            3 => MoveVector::West,
-            _ => MoveVector::None,
+    /// Cell next to `pos` and where it is, the agent stays
+    /// at `pos` when there is no cell in that direction.
+    fn locate_move(&self, pos: Position, direction: Direction) -> (Position, Location) {
+        let map = self.map.as_ref().unwrap();
+        match map.map.neighbour(pos, direction) {
+            Some(new_pos) => (new_pos, map.locate(new_pos)),
+            None => (pos, Location::Unreachable),
         }
     }
 
-    fn get_new_position(&self, pos: (i64, i64), delta: MoveVector) -> (i64, i64) {
-        match delta {
-            MoveVector::North => (pos.0, pos.1 + 1),
-            MoveVector::East => (pos.0 + 1, pos.1),
-            MoveVector::South => (pos.0 - 1, pos.1),
-            MoveVector::West => (pos.0, pos.1 - 1),
-            MoveVector::None => pos,
-        }
+    fn check_for_sheep_at_position(&self, pos: (i64, i64)) -> Option<Uuid> {
+        // The sheep which entered the cell first is eaten
+        self.map.as_ref()?.agents_at(pos).first().copied()
     }
 
-    fn determine_move_action(
-        &self,
-        pos: &(i64, i64),
-        range: &Range<u64>,
-        map_instance: &MapInstance,
-    ) -> BoundaryCheck {
-        if pos.0 < 0 || pos.1 < 0 || pos.0 > map_instance.map.chunk_len - 1 {
-            return BoundaryCheck::Impossible;
-        }
-        let offset = map_instance.pos_to_offset(pos.0, pos.1) as u64;
-        if range.contains(&offset) {
-            return BoundaryCheck::InBoundary;
-        } else {
-            let owner = map_instance
-                .map
-                .owners
-                .keys()
-                .find(|&r| r.contains(&offset));
-
-            if owner != None {
-                let destination = map_instance.map.owners.get(owner.unwrap()).unwrap();
-                let dest_copy = (destination.0.clone(), destination.1, destination.2);
-                if destination.0 == map_instance.fragment_owner.0 {
-                    return BoundaryCheck::OutBoundaryLocal(dest_copy);
-                } else {
-                    return BoundaryCheck::OutBoundaryGlobal(dest_copy);
-                }
-            }
+    /// Puts all sheep in the index of agents of the map.
+    fn index_sheep(&mut self) {
+        for id in self.sheep.id.clone() {
+            let position = self.sheep.position[&id];
+            self.place_sheep(id, position);
         }
-        BoundaryCheck::Impossible
     }
 
-    fn check_for_sheep_at_position(&self, pos: (i64, i64)) -> Option<Uuid> {
-        for (k, v) in self.sheep.position.iter() {
-            if *v == pos {
-                return Some(*k);
+    /// Puts the sheep in the index of agents of the map. Migrants from other
+    /// hosts arrive at a random island, so sheep at cells of other islands
+    /// are forwarded to their owners.
+    fn place_sheep(&mut self, id: Uuid, position: Position) {
+        match self.map.as_mut().unwrap().place_agent(id, position) {
+            Ok(Location::Own) => (),
+            Ok(Location::Local(owner)) => {
+                self.outgoing_local.push((AgentType::Sheep, id, owner));
+                self.remove_sheep.push(id);
+            }
+            Ok(Location::Remote(owner)) => {
+                self.outgoing_global.push((AgentType::Sheep, id, owner));
+                self.remove_sheep.push(id);
+            }
+            Ok(Location::Unreachable) => {
+                log::warn!("Sheep {} is outside of the map", id);
+                self.remove_sheep.push(id);
             }
+            Err(e) => log::error!("Cannot place sheep {}: {}", id, e),
         }
-        return None;
     }
 
     fn update_metrics(&self) {
@@ -514,6 +537,14 @@ impl WSIsland {
         );
     }
 
+    fn record_results(&mut self, turn_number: u32) {
+        let sheep = self.sheep.id.len() as f64;
+        let wolves = self.wolves.id.len() as f64;
+        let recorder = self.island_env.recorder();
+        recorder.record(turn_number, "sheep", sheep);
+        recorder.record(turn_number, "wolves", wolves);
+    }
+
     fn display_turn_stats(&self) {
         println!("AT THE END OF THE TURN:");
         println!("Number of sheep: {}", self.sheep.id.len());
@@ -1,4 +1,5 @@
 use crate::agent_types::AgentType;
+use ramp::map::Map;
 use rand::Rng;
 use std::ops::Range;
 use uuid::Uuid;
@@ -13,25 +14,21 @@ pub struct SerializedAgent {
     pub position: Position,
 }
 
-pub fn serialize(agent_type: AgentType, id: Uuid, energy: i64, position: Position) -> Vec<u8> {
-    let agent = SerializedAgent {
+pub fn serialize(
+    agent_type: AgentType,
+    id: Uuid,
+    energy: i64,
+    position: Position,
+) -> SerializedAgent {
+    SerializedAgent {
         agent_type,
         id,
         energy,
         position,
-    };
-    bincode::serialize(&agent).unwrap()
+    }
 }
 
-pub fn deserialize(agent: Vec<u8>) -> (AgentType, Uuid, i64, Position) {
-    let d: SerializedAgent = bincode::deserialize(&agent).unwrap();
-    (d.agent_type, d.id, d.energy, d.position)
-}
-
-pub fn generate_random_position(range: &Range<u64>, chunk_len: i64) -> Position {
-    let mut rng = rand::thread_rng();
-    let offset = rng.gen_range(range.start, range.end) as i64;
-    let x = offset % chunk_len;
-    let y = offset / chunk_len;
-    (x, y)
+pub fn generate_random_position(range: &Range<u64>, map: &Map, rng: &mut impl Rng) -> Position {
+    let offset = rng.gen_range(range.start, range.end);
+    map.cell_at(offset).expect("Fragment outside of the map")
 }
@@ -35,12 +35,10 @@ impl PartialEq for Action {
     fn eq(&self, other: &Self) -> bool {
         use Action::*;
         match (self, other) {
-            (&Death(ref uuid1), Death(ref uuid2)) => uuid1 == uuid2,
-            (&Migration(ref uuid1), &Migration(ref uuid2)) => uuid1 == uuid2,
-            (&Meeting(ref uuid1, ref uuid2), &Meeting(ref uuid3, ref uuid4)) => {
-                uuid1 == uuid3 && uuid2 == uuid4
-            }
-            (&Procreation(ref uuid1, ref uuid2), &Procreation(ref uuid3, ref uuid4)) => {
+            (Death(uuid1), Death(uuid2)) => uuid1 == uuid2,
+            (Migration(uuid1), Migration(uuid2)) => uuid1 == uuid2,
+            (Meeting(uuid1, uuid2), Meeting(uuid3, uuid4)) => uuid1 == uuid3 && uuid2 == uuid4,
+            (Procreation(uuid1, uuid2), Procreation(uuid3, uuid4)) => {
                 uuid1 == uuid3 && uuid2 == uuid4
             }
             _ => false,
@@ -1,4 +1,4 @@
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use std::f64;
 use std::fmt;
 use std::sync::Arc;
@@ -40,7 +40,12 @@ impl Agent {
         })
     }
 
-    pub fn procreate(&mut self, partner: &mut Agent) -> (Uuid, RefCell<Agent>) {
+    pub fn procreate(
+        &mut self,
+        partner: &mut Agent,
+        id: Uuid,
+        rng: &mut impl Rng,
+    ) -> RefCell<Agent> {
         let penalty = self.settings.procreation_penalty;
 
         self.energy = (f64::from(self.energy) * (1.0 - penalty)) as i32;
@@ -48,18 +53,16 @@ impl Agent {
 
         let child_energy = self.energy + partner.energy;
 
-        let mut new_genotype = Agent::crossover(&self.genotype, &partner.genotype);
-        Agent::mutate_genotype(&self.settings, &mut new_genotype);
+        let mut new_genotype = Agent::crossover(&self.genotype, &partner.genotype, rng);
+        Agent::mutate_genotype(&self.settings, &mut new_genotype, rng);
 
-        let uuid = Uuid::new_v4();
-        let new_agent = Agent::new(
-            uuid,
+        Agent::new(
+            id,
             self.settings.clone(),
             new_genotype,
             &functions::rastrigin,
             child_energy,
-        );
-        (uuid, new_agent)
+        )
     }
 
     pub fn meet(&mut self, partner: &mut Agent) {
@@ -74,19 +77,19 @@ impl Agent {
         }
     }
 
-    pub fn mutate_genotype(config: &AgentSettings, genotype: &mut Vec<f64>) {
+    pub fn mutate_genotype(config: &AgentSettings, genotype: &mut [f64], rng: &mut impl Rng) {
         let left_bound = config.lower_bound / 10.0;
         let right_bound = config.upper_bound / 10.0;
 
         for gene in genotype.iter_mut() {
-            if thread_rng().gen_range(0.0, 1.0) <= config.mutation_rate {
-                *gene += thread_rng().gen_range(left_bound, right_bound);
+            if rng.gen_range(0.0, 1.0) <= config.mutation_rate {
+                *gene += rng.gen_range(left_bound, right_bound);
             }
         }
     }
 
-    pub fn crossover(genotype1: &[f64], genotype2: &[f64]) -> Vec<f64> {
-        let division_point = thread_rng().gen_range(1, genotype1.len());
+    pub fn crossover(genotype1: &[f64], genotype2: &[f64], rng: &mut impl Rng) -> Vec<f64> {
+        let division_point = rng.gen_range(1, genotype1.len());
         let mut new_genotype = vec![];
         let head = &genotype1[..division_point];
         let tail = &genotype2[division_point..];
@@ -95,8 +98,8 @@ impl Agent {
         new_genotype
     }
 
-    pub fn get_action(&self) -> Action {
-        let prob = thread_rng().gen_range(1, 100);
+    pub fn get_action(&self, rng: &mut impl Rng) -> Action {
+        let prob = rng.gen_range(1, 100);
         if self.energy <= 0 {
             Action::Death(self.id)
         } else if prob == 1 {
@@ -129,6 +132,7 @@ mod tests {
     use super::Agent;
     use crate::functions;
     use crate::settings::AgentSettings;
+    use rand::thread_rng;
     use std::sync::Arc;
     use uuid::Uuid;
 
@@ -137,14 +141,14 @@ mod tests {
         let genotype1 = [0.0, 0.0, 0.0, 0.0];
         let genotype2 = [1.0, 1.0, 1.0, 1.0];
 
-        let new = Agent::crossover(&genotype1, &genotype2);
+        let new = Agent::crossover(&genotype1, &genotype2, &mut thread_rng());
         assert_ne!(genotype1, &new[..]);
         assert_ne!(genotype2, &new[..]);
 
         let genotype1 = [0.0, 0.0];
         let genotype2 = [1.0, 1.0];
 
-        let new = Agent::crossover(&genotype1, &genotype2);
+        let new = Agent::crossover(&genotype1, &genotype2, &mut thread_rng());
         assert_ne!(genotype1, &new[..]);
         assert_ne!(genotype2, &new[..]);
     }
@@ -164,7 +168,7 @@ mod tests {
         };
         let mut genotype = vec![0.0, 0.3, 1.0, 0.5];
         let genotype_copy = genotype.clone();
-        Agent::mutate_genotype(&config, &mut genotype);
+        Agent::mutate_genotype(&config, &mut genotype, &mut thread_rng());
         assert_ne!(genotype, genotype_copy);
     }
 
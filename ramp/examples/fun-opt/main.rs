@@ -3,8 +3,10 @@ extern crate serde_derive;
 
 use ramp::island::{Island, IslandEnv, IslandFactory};
 use ramp::metrics::MetricHub;
-use ramp::simulation::Simulation;
+use ramp::outcome::{IslandResult, Reducer};
+use ramp::simulation::SimulationBuilder;
 use ramp::utils;
+use std::process;
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -17,9 +19,30 @@ mod functions;
 mod myisland;
 mod settings;
 
+const LOGGER_LEVEL: &str = "info";
 const EXPECTED_ARGS_NUM: usize = 3;
 
-struct MyIslandFactory;
+struct MyIslandFactory {
+    settings: SimulationSettings,
+}
+
+/// Finds the best fitness of all islands.
+struct BestFitness;
+
+impl Reducer for BestFitness {
+    type Value = f64;
+
+    fn reduce(&self, results: &[IslandResult<f64>]) -> String {
+        let best = results.iter().max_by(|a, b| a.value.total_cmp(&b.value));
+        match best {
+            Some(result) => format!(
+                "best fitness {} on island {} ({})",
+                result.value, result.island_id, result.host
+            ),
+            None => String::from("no island has agents left"),
+        }
+    }
+}
 
 // MN - metric name
 const PROCREATIONS_MN: &str = "procreations";
@@ -36,40 +59,53 @@ const ISLAND_ID_LN: &str = "island_id";
 
 impl IslandFactory for MyIslandFactory {
     fn create(&self, id: Uuid, island_env: IslandEnv) -> Box<dyn Island> {
-        let args: Vec<String> = utils::parse_input_args(EXPECTED_ARGS_NUM);
-        let settings_file_name = args[2].clone();
-        let settings = load_settings(settings_file_name.clone());
-
         let island = MyIsland::new(
             id,
             island_env,
             &functions::rastrigin,
-            settings.island_settings.agents_number,
-            Arc::new(settings.agent_settings),
+            self.settings.island_settings.agents_number,
+            Arc::new(self.settings.agent_settings),
         );
         Box::new(island)
     }
 }
 
 fn main() {
+    let args: Vec<String> = utils::parse_input_args(EXPECTED_ARGS_NUM);
+    let settings = match SimulationSettings::new(args[2].clone()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid simulation settings: {}", e);
+            process::exit(1);
+        }
+    };
+
     let mut metrics = MetricHub::default();
     register_metrics(&mut metrics);
 
-    let factory = MyIslandFactory {};
-    Simulation::start_simulation(Box::new(factory), metrics);
-}
+    let factory = MyIslandFactory { settings };
+    let result = SimulationBuilder::new()
+        .settings_file(&args[1])
+        .factory(Box::new(factory))
+        .metrics(metrics)
+        .reducer(BestFitness)
+        .logger_level(LOGGER_LEVEL)
+        .build()
+        .and_then(|simulation| simulation.run());
 
-fn load_settings(file_name: String) -> SimulationSettings {
-    SimulationSettings::new(file_name).unwrap()
+    if let Err(e) = result {
+        eprintln!("Simulation failed: {}", e);
+        process::exit(1);
+    }
 }
 
 fn register_metrics(metrics: &mut MetricHub) {
-    metrics.register_int_gauge_vec(PROCREATIONS_MN, "procreations per turn", &[ISLAND_ID_LN]);
-    metrics.register_int_gauge_vec(DEADS_MN, "deads per turn", &[ISLAND_ID_LN]);
-    metrics.register_int_gauge_vec(MEETINGS_MN, "meetings per turn", &[ISLAND_ID_LN]);
+    metrics.register_int_counter_vec(PROCREATIONS_MN, "procreations", &[ISLAND_ID_LN]);
+    metrics.register_int_counter_vec(DEADS_MN, "deads", &[ISLAND_ID_LN]);
+    metrics.register_int_counter_vec(MEETINGS_MN, "meetings", &[ISLAND_ID_LN]);
     metrics.register_gauge_vec(BEST_FITNESS_MN, "best fitness in turn", &[ISLAND_ID_LN]);
-    metrics.register_int_gauge_vec(ALL_RECV_MIGR_MN, "all recv migrations", &[ISLAND_ID_LN]);
-    metrics.register_int_gauge_vec(ALL_SENT_MIGR_MN, "all sent migrations", &[ISLAND_ID_LN]);
-    metrics.register_int_gauge_vec(LOC_RECV_MIGR_MN, "local sent migrations", &[ISLAND_ID_LN]);
-    metrics.register_int_gauge_vec(GLOB_RECV_MIGR_MN, "global sent migrations", &[ISLAND_ID_LN]);
+    metrics.register_int_counter_vec(ALL_RECV_MIGR_MN, "all recv migrations", &[ISLAND_ID_LN]);
+    metrics.register_int_counter_vec(ALL_SENT_MIGR_MN, "all sent migrations", &[ISLAND_ID_LN]);
+    metrics.register_int_counter_vec(LOC_RECV_MIGR_MN, "local sent migrations", &[ISLAND_ID_LN]);
+    metrics.register_int_counter_vec(GLOB_RECV_MIGR_MN, "global sent migrations", &[ISLAND_ID_LN]);
 }
@@ -3,13 +3,13 @@ use crate::{
     LOC_RECV_MIGR_MN, MEETINGS_MN, PROCREATIONS_MN,
 };
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::process;
 use std::sync::Arc;
 
 use colored::*;
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use uuid::Uuid;
 
 use crate::action::Action;
@@ -18,8 +18,10 @@ use crate::settings::AgentSettings;
 use crate::utils;
 use ramp::island::{Island, IslandEnv};
 use ramp::message::Message;
+use ramp::migration::{MigrationEnv, MigrationTarget};
 
 const LOCAL_MIGRATION_THRESHOLD: u32 = 50;
+const AGENT_KIND: &str = "fun-opt/agent";
 
 struct IdQueues {
     pub dead_ids: Vec<Uuid>,
@@ -41,10 +43,10 @@ impl IdQueues {
 
 pub struct MyIsland {
     pub id: Uuid,
-    pub id_agent_map: HashMap<Uuid, RefCell<Agent>>,
+    // Ordered, so agents act in the same order in every seeded run
+    pub id_agent_map: BTreeMap<Uuid, RefCell<Agent>>,
     pub action_queue: Vec<Action>,
-    pub agent_settings: Arc<AgentSettings>,
-    island_env: IslandEnv,
+    island_env: MigrationEnv<Agent>,
     id_queues: IdQueues,
 }
 impl Island for MyIsland {
@@ -62,6 +64,7 @@ impl Island for MyIsland {
         self.resolve_deads();
 
         self.log_turn_end_and_update_best_agent();
+        self.record_results(turn_number);
     }
 
     fn on_finish(&mut self) {
@@ -74,6 +77,11 @@ impl Island for MyIsland {
             self.get_best_fitness().unwrap()
         );
     }
+
+    fn result(&self) -> Option<Vec<u8>> {
+        let fitness = self.get_best_fitness()?;
+        self.island_env.encode_result(&fitness).ok()
+    }
 }
 
 impl MyIsland {
@@ -85,25 +93,23 @@ impl MyIsland {
         agents_number: u32,
         agent_settings: Arc<AgentSettings>,
     ) -> Self {
+        let mut island_env = MigrationEnv::new(island_env, AGENT_KIND);
         MyIsland {
             id,
             id_agent_map: MyIsland::create_id_agent_map(
                 agents_number,
                 &agent_settings,
                 calculate_fitness,
+                &mut island_env,
             ),
             action_queue: Vec::new(),
-            agent_settings,
             island_env,
             id_queues: IdQueues::new(),
         }
     }
 
     pub fn get_best_fitness(&self) -> Option<f64> {
-        let mut top_guy = match self.id_agent_map.values().take(1).last() {
-            Some(a) => a,
-            None => return None,
-        };
+        let mut top_guy = self.id_agent_map.values().take(1).last()?;
         for agent in self.id_agent_map.values() {
             if agent.borrow().fitness > top_guy.borrow().fitness {
                 top_guy = agent;
@@ -123,6 +129,16 @@ impl MyIsland {
         );
     }
 
+    fn record_results(&mut self, turn_number: u32) {
+        let best_fitness = self.get_best_fitness();
+        let agents = self.id_agent_map.len() as f64;
+        let recorder = self.island_env.recorder();
+        if let Some(fitness) = best_fitness {
+            recorder.record(turn_number, "best_fitness", fitness);
+        }
+        recorder.record(turn_number, "agents", agents);
+    }
+
     // TODO: handle no agents properly
     fn log_turn_end_and_update_best_agent(&mut self) {
         match self.get_best_fitness() {
@@ -150,7 +166,7 @@ impl MyIsland {
 
     pub fn create_action_queues(&mut self) {
         for agent in self.id_agent_map.values() {
-            let action = agent.borrow().get_action();
+            let action = agent.borrow().get_action(self.island_env.rng());
             match action {
                 Action::Death(id) => self.id_queues.dead_ids.push(id),
                 Action::Meeting(id, _) => self
@@ -181,14 +197,14 @@ impl MyIsland {
         }
 
         let mut procreating_num = 0;
-        while !self.id_queues.procreating_ids.is_empty() {
-            let (id1, _) = self.id_queues.procreating_ids.pop().unwrap();
+        while let Some((id1, _)) = self.id_queues.procreating_ids.pop() {
             let (id2, _) = self.id_queues.procreating_ids.pop().unwrap();
 
             let mut agent1 = self.id_agent_map[&id1].borrow_mut();
             let mut agent2 = self.id_agent_map[&id2].borrow_mut();
 
-            let (uuid, new_agent) = agent1.procreate(&mut agent2);
+            let uuid = self.island_env.new_id();
+            let new_agent = agent1.procreate(&mut agent2, uuid, self.island_env.rng());
             drop(agent1);
             drop(agent2);
 
@@ -196,7 +212,7 @@ impl MyIsland {
             procreating_num += 1;
         }
 
-        self.island_env.metric_hub.add_int_gauge_vec(
+        self.island_env.metric_hub.add_int_counter_vec(
             PROCREATIONS_MN,
             &[&utils::short_id(&self.id)],
             procreating_num,
@@ -217,8 +233,7 @@ impl MyIsland {
         }
 
         let mut meeting_num = 0;
-        while !self.id_queues.meeting_ids.is_empty() {
-            let (id1, _) = self.id_queues.meeting_ids.pop().unwrap();
+        while let Some((id1, _)) = self.id_queues.meeting_ids.pop() {
             let (id2, _) = self.id_queues.meeting_ids.pop().unwrap();
 
             let mut agent1 = self.id_agent_map[&id1].borrow_mut();
@@ -227,7 +242,7 @@ impl MyIsland {
             agent1.meet(&mut agent2);
             meeting_num += 1;
         }
-        self.island_env.metric_hub.add_int_gauge_vec(
+        self.island_env.metric_hub.add_int_counter_vec(
             MEETINGS_MN,
             &[&utils::short_id(&self.id)],
             meeting_num,
@@ -239,47 +254,43 @@ impl MyIsland {
             "Number of migrating agents this turn: {}",
             self.id_queues.migrating_ids.len()
         );
-        let mut local_migrations_num = 0;
-        let mut global_migrations_num = 0;
+        let before = self.island_env.stats();
         for id in &self.id_queues.migrating_ids {
-            let prob = thread_rng().gen_range(0, 100);
+            let prob = self.island_env.rng().gen_range(0, 100);
             match self.id_agent_map.remove(id) {
                 Some(agent) => {
-                    let s_agent = bincode::serialize(&agent.into_inner()).unwrap();
-                    if prob <= LOCAL_MIGRATION_THRESHOLD {
-                        match self.island_env.send_to_rnd_local(Message::Agent(s_agent)) {
-                            Ok(()) => local_migrations_num += 1,
-                            Err(e) => match e.0 {
-                                Message::Agent(s_agent) => {
-                                    let d_agent: Agent = bincode::deserialize(&s_agent).unwrap();
-                                    self.id_agent_map.insert(*id, RefCell::new(d_agent));
-                                }
-                                _ => log::info!("Bad return message"),
-                            },
-                        }
+                    let target = if prob <= LOCAL_MIGRATION_THRESHOLD {
+                        MigrationTarget::RandomLocal
                     } else {
-                        self.island_env.send_to_rnd_global(Message::Agent(s_agent));
-                        global_migrations_num += 1;
+                        MigrationTarget::RandomGlobal
+                    };
+                    let migration = self.island_env.migrate(&agent.borrow(), target);
+                    if let Err(e) = migration {
+                        log::info!("Agent {} stays on the island: {}", id, e);
+                        self.id_agent_map.insert(*id, agent);
                     }
                 }
                 None => log::info!("No agent with id {}", id),
             }
         }
 
-        self.island_env.metric_hub.add_int_gauge_vec(
+        let stats = self.island_env.stats();
+        let sent_local = stats.sent_local - before.sent_local;
+        let sent_global = stats.sent_global - before.sent_global;
+        self.island_env.metric_hub.add_int_counter_vec(
             ALL_SENT_MIGR_MN,
             &[&utils::short_id(&self.id)],
-            local_migrations_num + global_migrations_num,
+            sent_local + sent_global,
         );
-        self.island_env.metric_hub.add_int_gauge_vec(
+        self.island_env.metric_hub.add_int_counter_vec(
             LOC_RECV_MIGR_MN,
             &[&utils::short_id(&self.id)],
-            local_migrations_num,
+            sent_local,
         );
-        self.island_env.metric_hub.add_int_gauge_vec(
+        self.island_env.metric_hub.add_int_counter_vec(
             GLOB_RECV_MIGR_MN,
             &[&utils::short_id(&self.id)],
-            global_migrations_num,
+            sent_global,
         );
         self.id_queues.migrating_ids.clear();
     }
@@ -293,31 +304,31 @@ impl MyIsland {
         for id in &self.id_queues.dead_ids {
             self.id_agent_map.remove(id);
         }
-        self.island_env.metric_hub.add_int_gauge_vec(
+        self.island_env.metric_hub.add_int_counter_vec(
             DEADS_MN,
             &[&utils::short_id(&self.id)],
-            deads_in_turn as i64,
+            deads_in_turn as u64,
         );
         self.id_queues.dead_ids.clear();
     }
 
-    fn resolve_messages(&mut self, messages: Vec<Message>) {
-        let mut migrants_num = 0;
-        for message in messages {
-            match message {
-                Message::Agent(migrant) => {
-                    migrants_num += 1;
-                    let d_migrant: Agent = bincode::deserialize(&migrant).unwrap();
-                    self.id_agent_map
-                        .insert(d_migrant.id, RefCell::new(d_migrant));
+    fn resolve_messages(&mut self, mut messages: Vec<Message>) {
+        let before = self.island_env.stats();
+        for migrant in self.island_env.received_migrants(&mut messages) {
+            match migrant {
+                Ok(agent) => {
+                    self.id_agent_map.insert(agent.id, RefCell::new(agent));
                 }
-                _ => log::error!("Unexpected msg"),
+                Err(e) => log::error!("Cannot receive migrant: {}", e),
             }
         }
-        self.island_env.metric_hub.add_int_gauge_vec(
+        for _message in messages {
+            log::error!("Unexpected msg");
+        }
+        self.island_env.metric_hub.add_int_counter_vec(
             ALL_RECV_MIGR_MN,
             &[&utils::short_id(&self.id)],
-            migrants_num,
+            self.island_env.stats().received - before.received,
         );
     }
 
@@ -329,13 +340,15 @@ impl MyIsland {
         agents_number: u32,
         agent_config: &Arc<AgentSettings>,
         calculate_fitness: &dyn Fn(&[f64]) -> f64,
-    ) -> HashMap<Uuid, RefCell<Agent>> {
-        let mut id_agent_map: HashMap<Uuid, RefCell<Agent>> = HashMap::new();
+        island_env: &mut IslandEnv,
+    ) -> BTreeMap<Uuid, RefCell<Agent>> {
+        let mut id_agent_map: BTreeMap<Uuid, RefCell<Agent>> = BTreeMap::new();
         for _i in 0..agents_number {
+            let rng = island_env.rng();
             let genotype: Vec<f64> = (0..agent_config.genotype_dim)
-                .map(|_| thread_rng().gen_range(agent_config.lower_bound, agent_config.upper_bound))
+                .map(|_| rng.gen_range(agent_config.lower_bound, agent_config.upper_bound))
                 .collect();
-            let id = Uuid::new_v4();
+            let id = island_env.new_id();
             id_agent_map.insert(
                 id,
                 Agent::new(
@@ -1,71 +1,28 @@
-use ramp::message::Message;
-use ramp::network::recv_rr;
-use ramp::settings::ServerSettings;
-use ramp::{metrics, network, utils};
+use ramp::error::Result;
+use ramp::settings::ClientSettings;
+use ramp::{metrics, server, utils};
+use std::process;
 use std::thread;
-use zmq::Socket;
 
 const LOGGER_LEVEL: &str = "info";
 const EXPECTED_ARGS_NUM: usize = 2;
 
 fn main() {
-    utils::init_logger(LOGGER_LEVEL);
     let args: Vec<String> = utils::parse_input_args(EXPECTED_ARGS_NUM);
-    let settings_file_name = args[1].clone();
-    let settings = load_settings(settings_file_name.clone());
-
-    let context = zmq::Context::new();
-    let rep_sock = context.socket(zmq::REP).unwrap();
-    let pub_sock = context.socket(zmq::PUB).unwrap();
-
-    network::bind_sock(&rep_sock, settings.ip.clone(), settings.rep_port);
-    network::bind_sock(&pub_sock, settings.ip.clone(), settings.pub_port);
-
-    let metrics_addr = format!("{}:{}", settings.ip, settings.metrics_port);
-    thread::spawn(move || metrics::start_server(metrics_addr));
-
-    let from = settings.ip.clone();
-    let ip_table = network::wait_for_hosts(&rep_sock, &from, settings.hosts, true);
-    network::publish_ip_table(&pub_sock, &from, &ip_table);
-    network::wait_for_confirmations(&rep_sock, &from, settings.hosts, true);
-
-    run(rep_sock, pub_sock, from, settings.hosts, settings.turns);
-}
-
-fn run(rep_sock: Socket, pub_sock: Socket, from: String, hosts: u32, turns: u32) {
-    log::info!("START SIM");
-    let key = String::from(network::SERVER_INFO_KEY);
-    let mut turn = 0;
-    while turn < turns {
-        log::info!("TURN {}", turn + 1);
-        network::send_ps(
-            &pub_sock,
-            key.clone(),
-            from.clone(),
-            Message::NextTurn(turn + 1),
-        );
-        turn += 1;
-        wait_for_confirmations(&rep_sock, from.clone(), hosts);
+    if let Err(e) = start(args[1].clone()) {
+        eprintln!("Server failed: {}", e);
+        process::exit(1);
     }
-    log::info!("FIN SIM");
-    network::send_ps(&pub_sock, key, from.clone(), Message::FinSim);
 }
 
-fn wait_for_confirmations(rep_sock: &Socket, identity: String, hosts: u32) {
-    let mut confirmation = 0;
-    while confirmation < hosts {
-        let (_from, msg) = recv_rr(rep_sock);
-        // metrics::inc_received_messages(from.clone(), identity.clone(),  String::from("200"));
-        match msg {
-            Message::TurnDone => {
-                confirmation += 1;
-                network::send_rr(rep_sock, identity.clone(), Message::Ok);
-            }
-            _ => log::warn!("Unexpected message {:#?}", msg),
-        }
-    }
-}
+/// Runs the server with the settings file of the coordinator host.
+fn start(settings_file_name: String) -> Result<()> {
+    utils::init_logger(LOGGER_LEVEL)?;
+    let settings = ClientSettings::new(settings_file_name)?;
+    let network = server::sync_settings(&settings)?;
+
+    let metrics_addr = format!("{}:{}", network.global_sync.server_ip, network.metrics_port);
+    thread::spawn(move || metrics::start_server(metrics_addr));
 
-fn load_settings(file_name: String) -> ServerSettings {
-    ServerSettings::new(file_name).unwrap()
+    server::run(&settings)
 }
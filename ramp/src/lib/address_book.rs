@@ -1,6 +1,10 @@
 use crate::dispatcher::{Addr, DispatcherMessage};
-use rand::{thread_rng, Rng};
+use crate::metrics;
+use crate::rng::IslandRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
 
 use uuid::Uuid;
 
@@ -9,37 +13,134 @@ use crate::message::Message;
 #[derive(Debug)]
 pub struct SendError<Message>(pub Message);
 
+/// Islands of a host with their channels. Address books of the host share
+/// them, so an island moved to another host is replaced for all of them.
+pub type LocalIslands = Arc<RwLock<Vec<(Uuid, Sender<Message>)>>>;
+
+pub fn local_islands(island_ids: &[Uuid], txes: Vec<Sender<Message>>) -> LocalIslands {
+    Arc::new(RwLock::new(island_ids.iter().copied().zip(txes).collect()))
+}
+
+/// Turn of an island, stamped on messages it sends when islands run with
+/// bounded staleness (see [`crate::pace`]).
+pub type TurnClock = Arc<AtomicU32>;
+
 pub struct AddressBook {
     pub dispatcher_tx: Sender<DispatcherMessage>,
-    pub addresses: Vec<Sender<Message>>,
-    pub islands: Vec<Uuid>,
+    islands: LocalIslands,
+    /// Island owning the book, it never sends messages to itself.
+    owner: Option<Uuid>,
+    clock: Option<TurnClock>,
+    /// Number of messages sent so far if they are sequenced.
+    sent: Option<u64>,
+    /// Messages for other hosts come back to local islands.
+    loopback: bool,
+    rng: IslandRng,
 }
 
 impl AddressBook {
+    /// Random recipients are drawn from a stream seeded with `seed`.
     pub fn new(
         dispatcher_tx: Sender<DispatcherMessage>,
-        addresses: Vec<Sender<Message>>,
-        islands: Vec<Uuid>,
+        islands: LocalIslands,
+        owner: Option<Uuid>,
+        seed: u64,
     ) -> AddressBook {
         AddressBook {
             dispatcher_tx,
-            addresses,
             islands,
+            owner,
+            clock: None,
+            sent: None,
+            loopback: false,
+            rng: IslandRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Stamps sent messages with the turn of the island.
+    pub fn with_clock(mut self, clock: TurnClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Tags sent messages with the owner and their number, so receivers
+    /// can put them in an order independent of thread scheduling, see
+    /// [`in_send_order`].
+    pub fn with_sequence(mut self) -> Self {
+        self.sent = Some(0);
+        self
+    }
+
+    /// Messages for other hosts are delivered to local islands by
+    /// [`crate::dispatcher::LocalDispatcher`], so they are counted as local.
+    pub fn with_loopback(mut self) -> Self {
+        self.loopback = true;
+        self
+    }
+
+    /// Local islands messages can be sent to.
+    pub fn islands(&self) -> Vec<Uuid> {
+        self.read()
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| self.is_recipient(id))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.read()
+            .iter()
+            .filter(|(id, _)| self.is_recipient(id))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gives the channel of island `old_id` to island `new_id`, which takes
+    /// its place on this host.
+    pub fn replace(&self, old_id: Uuid, new_id: Uuid) {
+        let mut islands = self.islands.write().unwrap_or_else(PoisonError::into_inner);
+        for (id, _) in islands.iter_mut().filter(|(id, _)| *id == old_id) {
+            *id = new_id;
         }
     }
 
     pub fn send_to_rnd_local(&mut self, msg: Message) -> Result<(), SendError<Message>> {
-        if self.addresses.is_empty() {
+        let pick = self.rng.gen();
+        self.send_to_picked_local(pick, msg)
+    }
+
+    /// Sends to the island chosen by a random `pick` drawn by the sender,
+    /// so the choice does not depend on the order messages are routed in.
+    pub fn send_to_picked_local(
+        &mut self,
+        pick: u64,
+        msg: Message,
+    ) -> Result<(), SendError<Message>> {
+        self.count(&msg, false);
+        let msg = self.stamp(msg);
+        self.send_to_picked(pick, msg)
+    }
+
+    fn send_to_picked(&mut self, pick: u64, msg: Message) -> Result<(), SendError<Message>> {
+        let len = self.len();
+        if len == 0 {
             return Err(SendError(msg));
         }
-        let island = thread_rng().gen_range(0, self.addresses.len());
-        match self.addresses.get(island) {
-            Some(tx) => match tx.send(msg) {
+        let picked = self
+            .read()
+            .iter()
+            .filter(|(id, _)| self.is_recipient(id))
+            .nth((pick % len as u64) as usize)
+            .cloned();
+        match picked {
+            Some((island_id, tx)) => match tx.send(msg) {
                 Ok(()) => Ok(()),
                 Err(e) => {
-                    self.addresses.remove(island);
-                    self.islands.remove(island);
-                    self.send_to_rnd_local(e.0)
+                    self.remove(&[island_id]);
+                    self.send_to_picked(pick, e.0)
                 }
             },
             None => Err(SendError(msg)),
@@ -51,9 +152,14 @@ impl AddressBook {
         island_id: Uuid,
         msg: Message,
     ) -> Result<(), SendError<Message>> {
-        let island = self.islands.iter().position(|&id| id == island_id).unwrap();
-        match self.addresses.get(island) {
-            Some(tx) => match tx.send(msg) {
+        self.count(&msg, false);
+        let msg = self.stamp(msg);
+        let islands = self.read();
+        match islands
+            .iter()
+            .find(|(id, _)| *id == island_id && self.is_recipient(id))
+        {
+            Some((_, tx)) => match tx.send(msg) {
                 Ok(()) => Ok(()),
                 Err(e) => Err(SendError(e.0)),
             },
@@ -62,22 +168,21 @@ impl AddressBook {
     }
 
     pub fn send_to_all_local(&mut self, msg: Message) -> Result<(), SendError<Message>> {
+        self.count(&msg, false);
+        let msg = self.stamp(msg);
         let mut counter = 0;
         let mut id_to_remove = vec![];
 
-        for i in 0..self.addresses.len() {
-            if let Some(tx) = self.addresses.get(i) {
+        for (island_id, tx) in self.read().iter() {
+            if self.is_recipient(island_id) {
                 match tx.send(msg.clone()) {
                     Ok(()) => counter += 1,
-                    Err(_) => id_to_remove.push(i),
+                    Err(_) => id_to_remove.push(*island_id),
                 }
             }
         }
 
-        id_to_remove.iter().for_each(|index| {
-            self.addresses.remove(*index);
-            self.islands.remove(*index);
-        });
+        self.remove(&id_to_remove);
 
         if counter == 0 {
             Err(SendError(msg))
@@ -87,27 +192,91 @@ impl AddressBook {
     }
 
     pub fn send_to_global(&mut self, addr: Addr, msg: Message) {
-        self.dispatcher_tx
-            .send(DispatcherMessage::Unicast(msg, addr))
-            .unwrap();
+        self.count(&msg, true);
+        let msg = self.stamp(msg);
+        self.send_to_dispatcher(DispatcherMessage::Unicast(msg, addr));
     }
 
     pub fn send_to_rnd_global(&mut self, msg: Message) {
-        self.dispatcher_tx
-            .send(DispatcherMessage::UnicastRandom(msg))
-            .unwrap();
+        self.count(&msg, true);
+        let msg = self.stamp(msg);
+        let pick = self.rng.gen();
+        self.send_to_dispatcher(DispatcherMessage::UnicastRandom(msg, pick));
     }
 
     pub fn send_to_all_global(&mut self, msg: Message) {
-        self.dispatcher_tx
-            .send(DispatcherMessage::Broadcast(msg))
-            .unwrap();
+        self.count(&msg, true);
+        let msg = self.stamp(msg);
+        self.send_to_dispatcher(DispatcherMessage::Broadcast(msg));
+    }
+
+    fn send_to_dispatcher(&self, msg: DispatcherMessage) {
+        // Dispatcher is gone only when the simulation is being finished
+        if let Err(e) = self.dispatcher_tx.send(msg) {
+            log::warn!("Dropping {:?} (dispatcher already finished)", e.0);
+        }
+    }
+
+    /// Only messages of islands are counted, not the ones the collector
+    /// passes on to them. The route is where messages are delivered.
+    fn count(&self, msg: &Message, global: bool) {
+        if self.owner.is_some() {
+            metrics::inc_island_messages(msg.kind(), global && !self.loopback);
+        }
+    }
+
+    fn stamp(&mut self, msg: Message) -> Message {
+        let msg = match &self.clock {
+            Some(clock) => Message::Stamped(clock.load(Ordering::SeqCst), Box::new(msg)),
+            None => msg,
+        };
+        match (self.owner, self.sent.as_mut()) {
+            (Some(owner), Some(sent)) => {
+                *sent += 1;
+                Message::Sequenced(owner, *sent, Box::new(msg))
+            }
+            _ => msg,
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Vec<(Uuid, Sender<Message>)>> {
+        self.islands.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn is_recipient(&self, island_id: &Uuid) -> bool {
+        self.owner != Some(*island_id)
+    }
+
+    /// Forgets islands which have finished.
+    fn remove(&self, island_ids: &[Uuid]) {
+        if island_ids.is_empty() {
+            return;
+        }
+        let mut islands = self.islands.write().unwrap_or_else(PoisonError::into_inner);
+        islands.retain(|(id, _)| !island_ids.contains(id));
     }
 }
 
+/// Orders messages tagged by [`AddressBook::with_sequence`] by their sender
+/// and the order they were sent in, and removes the tags. Untagged messages
+/// come first in the order they were received.
+pub fn in_send_order(mut messages: Vec<Message>) -> Vec<Message> {
+    messages.sort_by_key(|msg| match msg {
+        Message::Sequenced(sender, sent, _) => Some((*sender, *sent)),
+        _ => None,
+    });
+    messages
+        .into_iter()
+        .map(|msg| match msg {
+            Message::Sequenced(_, _, msg) => *msg,
+            msg => msg,
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::AddressBook;
+    use super::{in_send_order, local_islands, AddressBook};
     use crate::message::Message;
     use std::sync::mpsc;
     use uuid::Uuid;
@@ -119,7 +288,8 @@ mod tests {
         let addresses = vec![tx1];
         let islands = vec![Uuid::new_v4()];
 
-        let mut address_book = AddressBook::new(dispatcher_tx, addresses, islands);
+        let mut address_book =
+            AddressBook::new(dispatcher_tx, local_islands(&islands, addresses), None, 0);
         address_book.send_to_rnd_local(Message::Ok).unwrap();
         if let Some(Message::Ok) = rx1.try_iter().next() {
             Ok(())
@@ -140,7 +310,8 @@ mod tests {
         let id3 = Uuid::new_v4();
         let islands = vec![id1, id2, id3];
 
-        let mut address_book = AddressBook::new(dispatcher_tx, addresses, islands);
+        let mut address_book =
+            AddressBook::new(dispatcher_tx, local_islands(&islands, addresses), None, 0);
         address_book.send_to_local(id1, Message::Ok).unwrap();
         address_book.send_to_local(id3, Message::Ok).unwrap();
 
@@ -151,7 +322,7 @@ mod tests {
         if let Some(Message::Ok) = rx3.try_iter().next() {
             counter += 1;
         }
-        if let None = rx2.try_iter().next() {
+        if rx2.try_iter().next().is_none() {
             counter += 1;
         }
 
@@ -170,7 +341,8 @@ mod tests {
         let addresses = vec![tx1, tx2];
         let islands = vec![Uuid::new_v4(), Uuid::new_v4()];
 
-        let mut address_book = AddressBook::new(dispatcher_tx, addresses, islands);
+        let mut address_book =
+            AddressBook::new(dispatcher_tx, local_islands(&islands, addresses), None, 0);
         address_book.send_to_all_local(Message::Ok).unwrap();
 
         let mut counter = 0;
@@ -187,4 +359,66 @@ mod tests {
             Err(())
         }
     }
+
+    #[test]
+    fn test_sequenced_messages_are_put_in_send_order() {
+        let (dispatcher_tx, _dispatcher_rx) = mpsc::channel();
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, _rx2) = mpsc::channel();
+        let (tx3, _rx3) = mpsc::channel();
+        let (receiver, first, second) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let islands = local_islands(&[receiver, first, second], vec![tx1, tx2, tx3]);
+        let book = |owner| {
+            AddressBook::new(dispatcher_tx.clone(), islands.clone(), Some(owner), 0).with_sequence()
+        };
+        let (mut first_book, mut second_book) = (book(first), book(second));
+
+        second_book
+            .send_to_local(receiver, Message::NextTurn(3))
+            .unwrap();
+        first_book
+            .send_to_local(receiver, Message::NextTurn(1))
+            .unwrap();
+        second_book
+            .send_to_local(receiver, Message::NextTurn(4))
+            .unwrap();
+        first_book
+            .send_to_local(receiver, Message::NextTurn(2))
+            .unwrap();
+        let mut received: Vec<Message> = rx1.try_iter().collect();
+        received.push(Message::Ok);
+
+        let turns: Vec<Option<u32>> = in_send_order(received)
+            .into_iter()
+            .map(|msg| match msg {
+                Message::NextTurn(turn) => Some(turn),
+                _ => None,
+            })
+            .collect();
+        let (low, high) = if first < second { (1, 3) } else { (3, 1) };
+        assert_eq!(
+            turns,
+            vec![None, Some(low), Some(low + 1), Some(high), Some(high + 1)]
+        );
+    }
+
+    #[test]
+    fn test_replaced_island_is_reached_by_new_id() {
+        let (dispatcher_tx, _dispatcher_rx) = mpsc::channel();
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, _rx2) = mpsc::channel();
+        let (old_id, owner, new_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let islands = local_islands(&[old_id, owner], vec![tx1, tx2]);
+
+        let mut collector_book = AddressBook::new(dispatcher_tx.clone(), islands.clone(), None, 0);
+        let mut island_book = AddressBook::new(dispatcher_tx, islands, Some(owner), 0);
+        assert_eq!(island_book.islands(), vec![old_id]);
+
+        collector_book.replace(old_id, new_id);
+        assert!(island_book.send_to_local(old_id, Message::Ok).is_err());
+        island_book.send_to_local(new_id, Message::Ok).unwrap();
+        assert!(collector_book.send_to_local(owner, Message::Ok).is_ok());
+        assert!(island_book.send_to_local(owner, Message::Ok).is_err());
+        assert_eq!(rx1.try_iter().count(), 1);
+    }
 }
@@ -0,0 +1,218 @@
+//! Load balancing between hosts.
+//!
+//! With global sync every island reports how long its turn took and how many
+//! agents it has. The server sums loads of islands on each host and every few
+//! turns lets an island of the most loaded host trade places with an island
+//! of the least loaded one, so every host keeps its number of islands.
+//! A moved island takes its map fragments with it and is recreated on the
+//! other host from [`crate::island::Island::snapshot`], so islands without
+//! a snapshot never move.
+
+use crate::dispatcher::Addr;
+use crate::map::{FragmentOwner, MapOwners};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use uuid::Uuid;
+
+/// Load of an island in one turn.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct IslandLoad {
+    pub island_id: Uuid,
+    /// Time of the turn in microseconds.
+    pub turn_us: u64,
+    /// See [`crate::island::Island::agent_count`].
+    pub agents: Option<u64>,
+    /// The island has a snapshot to be recreated from on another host.
+    pub movable: bool,
+}
+
+/// What the load of an island is measured with, turn time when missing.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadMetric {
+    #[default]
+    Time,
+    /// Islands which do not count agents have no load.
+    Agents,
+}
+
+impl LoadMetric {
+    fn of(self, load: &IslandLoad) -> u64 {
+        match self {
+            LoadMetric::Time => load.turn_us,
+            LoadMetric::Agents => load.agents.unwrap_or(0),
+        }
+    }
+}
+
+/// Loads of islands summed over turns since the last exchange.
+#[derive(Debug, Default)]
+pub struct LoadTracker {
+    metric: LoadMetric,
+    loads: HashMap<Uuid, u64>,
+    /// Islands which reported they can move since the last exchange.
+    movable: HashSet<Uuid>,
+}
+
+impl LoadTracker {
+    pub fn new(metric: LoadMetric) -> Self {
+        LoadTracker {
+            metric,
+            loads: HashMap::new(),
+            movable: HashSet::new(),
+        }
+    }
+
+    pub fn record(&mut self, loads: &[IslandLoad]) {
+        for load in loads {
+            *self.loads.entry(load.island_id).or_default() += self.metric.of(load);
+            if load.movable {
+                self.movable.insert(load.island_id);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.loads.clear();
+        self.movable.clear();
+    }
+
+    /// Islands of the most and the least loaded host which should trade
+    /// places, if the first host has more than `1 + tolerance` times the
+    /// load of the second one. The pair is chosen among movable islands so
+    /// that loads of both hosts end up closest to each other.
+    pub fn plan(
+        &self,
+        owners: &MapOwners,
+        tolerance: f64,
+    ) -> Option<(FragmentOwner, FragmentOwner)> {
+        // Sorted, so ties are broken the same way in every run
+        let mut hosts: BTreeMap<Addr, BTreeMap<Uuid, u64>> = BTreeMap::new();
+        for (ip, port, island_id) in owners.values() {
+            let load = self.loads.get(island_id).copied().unwrap_or(0);
+            hosts
+                .entry((ip.clone(), *port))
+                .or_default()
+                .insert(*island_id, load);
+        }
+        let host_load = |islands: &BTreeMap<Uuid, u64>| islands.values().sum::<u64>();
+        let (heavy, heavy_islands) = hosts.iter().max_by_key(|(_, islands)| host_load(islands))?;
+        let (light, light_islands) = hosts.iter().min_by_key(|(_, islands)| host_load(islands))?;
+        let (heavy_load, light_load) = (host_load(heavy_islands), host_load(light_islands));
+        if heavy == light || heavy_load as f64 <= light_load as f64 * (1.0 + tolerance) {
+            return None;
+        }
+
+        let gap = heavy_load - light_load;
+        let mut best: Option<(u64, Uuid, Uuid)> = None;
+        let movable = |islands: &BTreeMap<Uuid, u64>| {
+            islands
+                .iter()
+                .filter(|(island_id, _)| self.movable.contains(*island_id))
+                .map(|(island_id, load)| (*island_id, *load))
+                .collect::<Vec<_>>()
+        };
+        let arriving_islands = movable(light_islands);
+        for (leaving, leaving_load) in movable(heavy_islands) {
+            for &(arriving, arriving_load) in &arriving_islands {
+                // Moving no load or all of the gap (or more) does not help
+                if leaving_load <= arriving_load || leaving_load - arriving_load >= gap {
+                    continue;
+                }
+                let moved = leaving_load - arriving_load;
+                let imbalance = (gap as i64 - 2 * moved as i64).unsigned_abs();
+                if best.is_none_or(|(lowest, _, _)| imbalance < lowest) {
+                    best = Some((imbalance, leaving, arriving));
+                }
+            }
+        }
+        best.map(|(_, leaving, arriving)| {
+            (
+                (heavy.0.clone(), heavy.1, leaving),
+                (light.0.clone(), light.1, arriving),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IslandLoad, LoadMetric, LoadTracker};
+    use crate::map::{self, MapOwners};
+    use uuid::Uuid;
+
+    fn owners(hosts: &[(&str, &[Uuid])]) -> MapOwners {
+        let mut owners = MapOwners::new();
+        let mut start = 0;
+        for (ip, islands) in hosts {
+            for island_id in islands.iter() {
+                owners.insert(start..start + 10, (ip.to_string(), 1, *island_id));
+                start += 10;
+            }
+        }
+        owners
+    }
+
+    fn load(island_id: Uuid, turn_us: u64) -> IslandLoad {
+        IslandLoad {
+            island_id,
+            turn_us,
+            agents: None,
+            movable: true,
+        }
+    }
+
+    #[test]
+    fn test_heavy_island_trades_places_with_light_one() {
+        let islands: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let mut owners = owners(&[("a", &islands[..2]), ("b", &islands[2..])]);
+        let mut tracker = LoadTracker::new(LoadMetric::Time);
+        tracker.record(&[load(islands[0], 30), load(islands[1], 20)]);
+        tracker.record(&[load(islands[0], 20), load(islands[1], 20)]);
+        tracker.record(&[load(islands[2], 10)]);
+
+        // Loads 90 and 10, swapping 50 for 10 gives 50 on both hosts
+        let (leaving, arriving) = tracker.plan(&owners, 0.2).unwrap();
+        assert_eq!(leaving, (String::from("a"), 1, islands[0]));
+        assert_eq!(arriving, (String::from("b"), 1, islands[2]));
+
+        map::exchange_hosts(&mut owners, &leaving, &arriving);
+        assert_eq!(tracker.plan(&owners, 0.2), None);
+    }
+
+    #[test]
+    fn test_balanced_hosts_stay() {
+        let islands: Vec<Uuid> = (0..2).map(|_| Uuid::new_v4()).collect();
+        let owners = owners(&[("a", &islands[..1]), ("b", &islands[1..])]);
+        let mut tracker = LoadTracker::new(LoadMetric::Agents);
+        tracker.record(&[load(islands[0], 100), load(islands[1], 10)]);
+        // Without agent counts there is no load at all
+        assert_eq!(tracker.plan(&owners, 0.0), None);
+
+        let mut tracker = LoadTracker::new(LoadMetric::Time);
+        tracker.record(&[load(islands[0], 100), load(islands[1], 10)]);
+        // A single island on each host would only move the load around
+        assert_eq!(tracker.plan(&owners, 0.0), None);
+    }
+
+    #[test]
+    fn test_islands_without_snapshot_stay() {
+        let islands: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let owners = owners(&[("a", &islands[..2]), ("b", &islands[2..])]);
+        let mut tracker = LoadTracker::new(LoadMetric::Time);
+        let fixed = IslandLoad {
+            movable: false,
+            ..load(islands[0], 50)
+        };
+        tracker.record(&[fixed, load(islands[1], 40), load(islands[2], 10)]);
+
+        // Swapping 50 for 10 would be best, but only the island of 40 can move
+        let (leaving, arriving) = tracker.plan(&owners, 0.2).unwrap();
+        assert_eq!(leaving.2, islands[1]);
+        assert_eq!(arriving.2, islands[2]);
+
+        tracker.clear();
+        tracker.record(&[load(islands[0], 50), load(islands[1], 40)]);
+        // Islands which did not report since the last exchange stay as well
+        assert_eq!(tracker.plan(&owners, 0.2), None);
+    }
+}
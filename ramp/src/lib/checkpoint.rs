@@ -0,0 +1,164 @@
+//! Periodic checkpoints of islands.
+//!
+//! Each island is stored in its own file `<dir>/turn-<turn>/<host>/<island_id>.ckpt`
+//! together with messages it received for that turn. To restart a simulation
+//! point [`crate::simulation::SimulationBuilder::restore_from`] at a directory
+//! containing checkpoints of islands which should run on the host. Islands
+//! can be moved to other hosts simply by copying their files.
+
+use crate::error::{RampError, Result};
+use crate::island::Island;
+use crate::message::{Message, TurnNumber};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const CHECKPOINT_EXT: &str = "ckpt";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IslandCheckpoint {
+    pub island_id: Uuid,
+    pub turn: TurnNumber,
+    pub state: Vec<u8>,
+    pub messages: Vec<Message>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Checkpointer {
+    dir: PathBuf,
+    host: String,
+    interval: u32,
+}
+
+impl Checkpointer {
+    pub fn new(dir: &str, host: String, interval: u32) -> Self {
+        Checkpointer {
+            dir: PathBuf::from(dir),
+            host,
+            interval,
+        }
+    }
+
+    pub fn is_due(&self, turn: TurnNumber) -> bool {
+        self.interval != 0 && turn != 0 && turn.is_multiple_of(self.interval)
+    }
+
+    /// Saves island state and messages it is about to process in `turn`.
+    /// Islands without [`Island::snapshot`] are skipped.
+    pub fn save(
+        &self,
+        island_id: Uuid,
+        turn: TurnNumber,
+        island: &dyn Island,
+        messages: &[Message],
+    ) -> Result<()> {
+        let state = match island.snapshot() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        let checkpoint = IslandCheckpoint {
+            island_id,
+            turn,
+            state,
+            messages: messages.to_vec(),
+        };
+
+        let dir = self.dir.join(format!("turn-{:06}", turn)).join(&self.host);
+        fs::create_dir_all(&dir)?;
+        let file = dir.join(format!("{}.{}", island_id, CHECKPOINT_EXT));
+        fs::write(&file, bincode::serialize(&checkpoint)?)?;
+        log::info!("Island {} checkpointed to {:?}", island_id, file);
+        Ok(())
+    }
+}
+
+/// Loads all island checkpoints found under `path`.
+pub fn load(path: &Path) -> Result<Vec<IslandCheckpoint>> {
+    let mut files = vec![];
+    find_checkpoints(path, &mut files)?;
+    files.sort();
+
+    let checkpoints = files
+        .iter()
+        .map(|file| Ok(bincode::deserialize(&fs::read(file)?)?))
+        .collect::<Result<Vec<IslandCheckpoint>>>()?;
+    if checkpoints.is_empty() {
+        return Err(RampError::Checkpoint(format!(
+            "no checkpoints found in {:?}",
+            path
+        )));
+    }
+    if checkpoints.iter().any(|c| c.turn != checkpoints[0].turn) {
+        return Err(RampError::Checkpoint(format!(
+            "checkpoints in {:?} come from different turns",
+            path
+        )));
+    }
+    Ok(checkpoints)
+}
+
+fn find_checkpoints(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_checkpoints(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == CHECKPOINT_EXT) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, Checkpointer};
+    use crate::codec::CodecKind;
+    use crate::island::Island;
+    use crate::message::Message;
+    use crate::migration::Migrant;
+    use std::env;
+    use std::fs;
+    use uuid::Uuid;
+
+    struct TestIsland(Vec<u8>);
+
+    impl Island for TestIsland {
+        fn on_start(&mut self) {}
+
+        fn do_turn(&mut self, _turn_number: u32, _messages: Vec<Message>) {}
+
+        fn on_finish(&mut self) {}
+
+        fn snapshot(&self) -> Option<Vec<u8>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = env::temp_dir().join(format!("ramp-checkpoint-{}", Uuid::new_v4()));
+        let checkpointer = Checkpointer::new(dir.to_str().unwrap(), String::from("host"), 5);
+        assert!(!checkpointer.is_due(0));
+        assert!(!checkpointer.is_due(3));
+        assert!(checkpointer.is_due(10));
+
+        let island_id = Uuid::new_v4();
+        let island = TestIsland(vec![1, 2, 3]);
+        let migrant = Migrant::encode(CodecKind::Bincode, "byte", &4u8).unwrap();
+        checkpointer
+            .save(island_id, 10, &island, &[Message::Agent(migrant.clone())])
+            .unwrap();
+
+        let checkpoints = load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].island_id, island_id);
+        assert_eq!(checkpoints[0].turn, 10);
+        assert_eq!(checkpoints[0].state, vec![1, 2, 3]);
+        match checkpoints[0].messages.as_slice() {
+            [Message::Agent(agent)] => assert_eq!(agent, &migrant),
+            _ => panic!("Unexpected messages in checkpoint"),
+        }
+    }
+}
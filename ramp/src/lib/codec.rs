@@ -0,0 +1,183 @@
+//! Wire formats of messages.
+//!
+//! Every frame carrying a message starts with a header holding
+//! [`PROTOCOL_VERSION`] and the codec of the payload. Hosts built from
+//! different versions or configured with different codecs fail with
+//! [`RampError::IncompatiblePeer`] instead of decoding garbage.
+
+use crate::error::{RampError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Version of the messages exchanged by hosts and the server.
+/// Bumped whenever [`crate::message::Message`] changes.
+pub const PROTOCOL_VERSION: u16 = 12;
+
+/// Protocol version (big endian) followed by the codec id.
+const HEADER_LEN: usize = 3;
+
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_cbor::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+}
+
+/// Codec selected in settings, bincode when missing.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    MessagePack,
+    Cbor,
+}
+
+impl CodecKind {
+    fn id(self) -> u8 {
+        match self {
+            CodecKind::Bincode => 0,
+            CodecKind::MessagePack => 1,
+            CodecKind::Cbor => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CodecKind::Bincode),
+            1 => Some(CodecKind::MessagePack),
+            2 => Some(CodecKind::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Encodes `value` preceded by the frame header.
+    pub fn encode_frame<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(HEADER_LEN);
+        frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        frame.push(self.id());
+        frame.append(&mut self.encode(value)?);
+        Ok(frame)
+    }
+
+    /// Checks the frame header and decodes the payload.
+    pub fn decode_frame<T: DeserializeOwned>(self, frame: &[u8]) -> Result<T> {
+        if frame.len() < HEADER_LEN {
+            return Err(RampError::InvalidFrame(String::from("missing header")));
+        }
+        let version = u16::from_be_bytes([frame[0], frame[1]]);
+        if version != PROTOCOL_VERSION {
+            return Err(RampError::IncompatiblePeer(format!(
+                "peer speaks protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            )));
+        }
+        match CodecKind::from_id(frame[2]) {
+            Some(codec) if codec == self => self.decode(&frame[HEADER_LEN..]),
+            Some(codec) => Err(RampError::IncompatiblePeer(format!(
+                "peer uses {:?} codec, expected {:?}",
+                codec, self
+            ))),
+            None => Err(RampError::IncompatiblePeer(format!(
+                "peer uses unknown codec {}",
+                frame[2]
+            ))),
+        }
+    }
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            CodecKind::Bincode => Bincode.encode(value),
+            CodecKind::MessagePack => MessagePack.encode(value),
+            CodecKind::Cbor => Cbor.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            CodecKind::Bincode => Bincode.decode(bytes),
+            CodecKind::MessagePack => MessagePack.decode(bytes),
+            CodecKind::Cbor => Cbor.decode(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodecKind, PROTOCOL_VERSION};
+    use crate::error::RampError;
+    use crate::map::MapOwners;
+    use crate::message::Message;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let codecs = [CodecKind::Bincode, CodecKind::MessagePack, CodecKind::Cbor];
+        for codec in codecs.iter() {
+            let frame = codec
+                .encode_frame(&Message::Hello(String::from("10.0.0.1"), 5000))
+                .unwrap();
+            match codec.decode_frame(&frame).unwrap() {
+                Message::Hello(ip, port) => assert_eq!((ip.as_str(), port), ("10.0.0.1", 5000)),
+                msg => panic!("unexpected message {:?}", msg),
+            }
+
+            let mut owners = MapOwners::new();
+            owners.insert(0..10, (String::from("10.0.0.1"), 5000, Uuid::new_v4()));
+            let frame = codec
+                .encode_frame(&Message::Owners(owners.clone()))
+                .unwrap();
+            match codec.decode_frame(&frame).unwrap() {
+                Message::Owners(decoded) => assert_eq!(decoded, owners),
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn test_incompatible_frames() {
+        let mut frame = CodecKind::Bincode.encode_frame(&Message::Ok).unwrap();
+        let result = CodecKind::Cbor.decode_frame::<Message>(&frame);
+        assert!(matches!(result, Err(RampError::IncompatiblePeer(_))));
+
+        frame[..2].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        let result = CodecKind::Bincode.decode_frame::<Message>(&frame);
+        assert!(matches!(result, Err(RampError::IncompatiblePeer(_))));
+    }
+}
@@ -1,16 +1,38 @@
 use crate::address_book::AddressBook;
-use crate::dispatcher::DispatcherMessage;
-use crate::message::Message;
+use crate::dispatcher::{Addr, DispatcherMessage};
+use crate::error::RampError;
+use crate::map::{FragmentOwner, Map};
+use crate::message::{Message, TurnNumber};
 use crate::metrics;
 use crate::network;
 use crate::network::CollectorNetworkCtx;
+use crate::outcome::IslandResult;
+use std::collections::{HashMap, HashSet};
+use std::mem;
 use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long the collector blocks on the network socket before it checks
+/// messages from the simulation.
+const POLL_TIMEOUT_MS: i64 = 100;
 
 pub struct Collector {
     rx: Receiver<Message>,
     nt_ctx: CollectorNetworkCtx,
     address_book: AddressBook,
+    map: Map,
     identity: String,
+    /// Set with global sync, when the server is expected to send heartbeats.
+    server_timeout: Option<Duration>,
+    last_server_msg: Instant,
+    /// States of islands moving here which came before the server
+    /// told this host about the exchange.
+    early_states: HashMap<Uuid, Message>,
+    /// Results of islands of other hosts, gathered by the coordinator.
+    results: Vec<IslandResult>,
+    /// Hosts which have sent results of their islands.
+    reported: HashSet<Addr>,
 }
 
 impl Collector {
@@ -18,13 +40,29 @@ impl Collector {
         self_rx: Receiver<Message>,
         nt_ctx: CollectorNetworkCtx,
         address_book: AddressBook,
+        map: Map,
     ) -> Collector {
-        let identity = nt_ctx.nt_sett.host_ip.clone();
+        let identity = network::host_key(&nt_ctx.nt_sett.host_ip, nt_ctx.nt_sett.pub_port);
+        let global_sync = &nt_ctx.nt_sett.global_sync;
+        let server_timeout = if global_sync.sync {
+            let timeout_ms = global_sync
+                .server_timeout_ms
+                .unwrap_or(network::DEFAULT_PEER_TIMEOUT_MS);
+            Some(Duration::from_millis(timeout_ms))
+        } else {
+            None
+        };
         Collector {
             rx: self_rx,
             nt_ctx,
             address_book,
+            map,
             identity,
+            server_timeout,
+            last_server_msg: Instant::now(),
+            early_states: HashMap::new(),
+            results: vec![],
+            reported: HashSet::new(),
         }
     }
 
@@ -47,20 +85,59 @@ impl Collector {
                 }
             }
 
-            //Next step: non-blocking check if there are any new agents waiting to be added to our system
-            let mut items = [self.nt_ctx.sub_sock.as_poll_item(zmq::POLLIN)];
-            zmq::poll(&mut items, -1).unwrap();
-            if items[0].is_readable() {
-                let (_, from, msg) = network::recv_ps(&self.nt_ctx.sub_sock);
-                metrics::inc_received_messages(
-                    from.clone(),
-                    self.identity.clone(),
-                    String::from("200"),
-                );
+            //Next step: wait for new agents that should be added to our system
+            let readable = match network::poll_readable(&self.nt_ctx.sub_sock, POLL_TIMEOUT_MS) {
+                Ok(readable) => readable,
+                Err(e) => {
+                    log::error!("Cannot poll network socket: {}", e);
+                    self.abort();
+                    break;
+                }
+            };
+            if !readable {
+                let server_lost = self
+                    .server_timeout
+                    .is_some_and(|timeout| self.last_server_msg.elapsed() > timeout);
+                if server_lost {
+                    log::error!("No message from the server for too long");
+                    self.abort_without_server();
+                    break;
+                }
+            } else {
+                let (from_server, msg) =
+                    match network::recv_ps_sized(&self.nt_ctx.sub_sock, self.nt_ctx.codec) {
+                        Ok((key, from, msg, bytes)) => {
+                            let from_server = key == network::SERVER_INFO_KEY;
+                            if from_server {
+                                self.last_server_msg = Instant::now();
+                            }
+                            metrics::inc_received_messages(
+                                &from,
+                                &self.identity,
+                                msg.kind(),
+                                bytes,
+                            );
+                            (from_server, msg)
+                        }
+                        Err(RampError::Network(e)) => {
+                            log::error!("Cannot receive from network socket: {}", e);
+                            self.abort();
+                            break;
+                        }
+                        Err(e) => {
+                            log::warn!("Dropping malformed message: {}", e);
+                            continue;
+                        }
+                    };
                 match msg {
-                    Message::NextTurn(_) => {
+                    Message::NextTurn(turn) if self.is_leaving(turn) => {
+                        log::info!("Leaving simulation before turn {}", turn);
+                        self.leave();
+                        break;
+                    }
+                    Message::NextTurn(_) | Message::Checkpoint(_) => {
                         if self.address_book.send_to_all_local(msg).is_err() {
-                            log::error!("No more active islands while sending NextTurn msg");
+                            log::error!("No more active islands while sending turn msg");
                         }
                     }
                     Message::FinSim => {
@@ -68,26 +145,328 @@ impl Collector {
                         if self.address_book.send_to_all_local(msg.clone()).is_err() {
                             log::error!("No more active islands while sending FinSim msg");
                         }
-                        self.address_book
+                        if self
+                            .address_book
                             .dispatcher_tx
                             .send(DispatcherMessage::Info(msg))
-                            .unwrap();
+                            .is_err()
+                        {
+                            log::info!("Dispatcher already finished");
+                        }
                         break;
                     }
-                    Message::Agent(_) => {
-                        if let Err(e) = self.address_book.send_to_rnd_local(msg) {
-                            log::info!("{:?} (No more active islands in system)", e);
+                    Message::Heartbeat => {}
+                    Message::IpTable(ip_table) if from_server => {
+                        let own_addr = (
+                            self.nt_ctx.nt_sett.host_ip.as_str(),
+                            self.nt_ctx.nt_sett.pub_port,
+                        );
+                        if !ip_table
+                            .iter()
+                            .any(|(ip, port)| (ip.as_str(), *port) == own_addr)
+                        {
+                            log::info!("This host is no longer part of the simulation");
+                            self.finish_locally();
+                            break;
+                        }
+                        if let Err(e) = network::update_peers(
+                            &self.nt_ctx.sub_sock,
+                            &mut self.nt_ctx.ip_table,
+                            ip_table,
+                            own_addr,
+                        ) {
+                            log::error!("Cannot connect to new hosts: {}", e);
+                            self.abort();
+                            break;
+                        }
+                        log::info!("Hosts changed: {:?}", self.nt_ctx.ip_table);
+                        let ip_table = self.nt_ctx.ip_table.clone();
+                        if self
+                            .address_book
+                            .dispatcher_tx
+                            .send(DispatcherMessage::Info(Message::IpTable(ip_table)))
+                            .is_err()
+                        {
+                            log::info!("Dispatcher already finished");
                         }
                     }
-                    Message::MapSet(_, _, _) => {
-                        if let Err(e) = self.address_book.send_to_rnd_local(msg) {
-                            log::info!("{:?} (No more active ilsands in system)", e);
+                    Message::Owners(owners) if from_server => self.map.set_owners(owners),
+                    Message::Exchange(first, second) if from_server => {
+                        self.exchange(first.clone(), second.clone());
+                        self.exchange(second, first);
+                    }
+                    Message::IslandState(island_id, _, _) => {
+                        if self.address_book.islands().contains(&island_id) {
+                            self.send_to_island(island_id, msg);
+                        } else {
+                            self.early_states.insert(island_id, msg);
                         }
                     }
-                    _ => log::debug!("Unexpected message in collector {:#?}", msg),
+                    Message::HostDown(ip, port) => {
+                        if ip == self.nt_ctx.nt_sett.host_ip && port == self.nt_ctx.nt_sett.pub_port
+                        {
+                            log::error!("This host has been declared dead by the server");
+                            if self
+                                .address_book
+                                .send_to_all_local(Message::FinSim)
+                                .is_err()
+                            {
+                                log::info!("Islands already finished");
+                            }
+                            let _ = self
+                                .address_book
+                                .dispatcher_tx
+                                .send(DispatcherMessage::Info(Message::HostDown(ip, port)));
+                            break;
+                        }
+                        let fragments = self.map.remove_host(&ip, port);
+                        log::warn!(
+                            "Host {}:{} is down, dropped {} map fragments",
+                            ip,
+                            port,
+                            fragments
+                        );
+                        if self
+                            .address_book
+                            .dispatcher_tx
+                            .send(DispatcherMessage::Info(Message::HostDown(ip, port)))
+                            .is_err()
+                        {
+                            log::info!("Dispatcher already finished");
+                        }
+                    }
+                    Message::Results(ip, port, results) => {
+                        self.receive_results((ip, port), results)
+                    }
+                    Message::Islands(island_ids) if self.nt_ctx.nt_sett.is_coordinator => {
+                        self.resend_owners(&island_ids)
+                    }
+                    _ => self.deliver(msg),
                 }
             }
         }
         log::info!("Collector finished");
     }
+
+    /// Waits for results of islands of the given hosts once the simulation
+    /// is finished. Gives up when nothing has been received for `timeout`.
+    pub fn gather_results(&mut self, hosts: &[Addr], timeout: Duration) -> Vec<IslandResult> {
+        let mut last_msg = Instant::now();
+        loop {
+            let missing: Vec<String> = hosts
+                .iter()
+                .filter(|addr| !self.reported.contains(*addr))
+                .map(|(ip, port)| format!("{}:{}", ip, port))
+                .collect();
+            if missing.is_empty() {
+                break;
+            }
+            if last_msg.elapsed() > timeout {
+                log::warn!("No results from hosts {}", missing.join(", "));
+                break;
+            }
+            match network::poll_readable(&self.nt_ctx.sub_sock, POLL_TIMEOUT_MS) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    log::error!("Cannot poll network socket: {}", e);
+                    break;
+                }
+            }
+            match network::recv_ps_sized(&self.nt_ctx.sub_sock, self.nt_ctx.codec) {
+                Ok((_, from, msg, bytes)) => {
+                    metrics::inc_received_messages(&from, &self.identity, msg.kind(), bytes);
+                    last_msg = Instant::now();
+                    // Hosts still running send other messages as well
+                    if let Message::Results(ip, port, results) = msg {
+                        self.receive_results((ip, port), results);
+                    }
+                }
+                Err(RampError::Network(e)) => {
+                    log::error!("Cannot receive from network socket: {}", e);
+                    break;
+                }
+                Err(e) => log::warn!("Dropping malformed message: {}", e),
+            }
+        }
+        mem::take(&mut self.results)
+    }
+
+    fn receive_results(&mut self, host: Addr, mut results: Vec<IslandResult>) {
+        log::info!(
+            "Results of {} islands from {}:{}",
+            results.len(),
+            host.0,
+            host.1
+        );
+        self.reported.insert(host);
+        self.results.append(&mut results);
+    }
+
+    /// Hosts resend their islands until they get map owners, so a host
+    /// sending them now has missed the owners broadcast by the coordinator.
+    fn resend_owners(&mut self, island_ids: &[Uuid]) {
+        let owner = island_ids
+            .first()
+            .and_then(|island_id| self.map.owner_of_island(*island_id));
+        let (ip, port, _) = match owner {
+            Some(owner) => owner,
+            None => {
+                log::warn!("Islands of an unknown host: {:?}", island_ids);
+                return;
+            }
+        };
+        let owners = self.map.owners().clone();
+        if self
+            .address_book
+            .dispatcher_tx
+            .send(DispatcherMessage::Unicast(
+                Message::Owners(owners),
+                (ip, port),
+            ))
+            .is_err()
+        {
+            log::info!("Dispatcher already finished");
+        }
+    }
+
+    /// Passes a message sent by an island to the local islands it is for.
+    fn deliver(&mut self, msg: Message) {
+        match *msg.unstamped() {
+            Message::Agent(_) => {
+                if let Err(e) = self.address_book.send_to_rnd_local(msg) {
+                    log::info!("{:?} (No more active islands in system)", e);
+                }
+            }
+            Message::MapGet(_, _, x, y) => match self.map.owner_of((x, y)) {
+                Some((_, _, island_id)) => self.send_to_island(island_id, msg),
+                None => log::warn!("No fragment owns cell ({}, {})", x, y),
+            },
+            Message::MapValue(_, island_id, _, _, _)
+            | Message::MapBatch(island_id, _)
+            | Message::Halo(island_id, _) => self.send_to_island(island_id, msg),
+            Message::Progress(_, _) => {
+                if self.address_book.send_to_all_local(msg).is_err() {
+                    log::info!("Islands already finished");
+                }
+            }
+            _ => log::debug!("Unexpected message in collector {:#?}", msg),
+        }
+    }
+
+    /// Messages for islands which moved to another host follow them there.
+    fn send_to_island(&mut self, island_id: Uuid, msg: Message) {
+        let msg = match self.address_book.send_to_local(island_id, msg) {
+            Ok(()) => return,
+            Err(e) => e.0,
+        };
+        match self.map.owner_of_island(island_id) {
+            Some(owner) if !self.is_local(&owner) => {
+                let addr = (owner.0, owner.1);
+                if self
+                    .address_book
+                    .dispatcher_tx
+                    .send(DispatcherMessage::Unicast(msg, addr))
+                    .is_err()
+                {
+                    log::info!("Dispatcher already finished");
+                }
+            }
+            _ => log::info!("Island {} already finished", island_id),
+        }
+    }
+
+    /// Tells the local island `leaving` to move to the host of `arriving`,
+    /// which takes over its channel.
+    fn exchange(&mut self, leaving: FragmentOwner, arriving: FragmentOwner) {
+        if !self.is_local(&leaving) || self.is_local(&arriving) {
+            return;
+        }
+        let (leaving_id, arriving_id) = (leaving.2, arriving.2);
+        self.send_to_island(leaving_id, Message::Exchange(leaving, arriving));
+        self.address_book.replace(leaving_id, arriving_id);
+        if let Some(state) = self.early_states.remove(&arriving_id) {
+            self.send_to_island(arriving_id, state);
+        }
+    }
+
+    fn is_local(&self, (ip, port, _): &FragmentOwner) -> bool {
+        *ip == self.nt_ctx.nt_sett.host_ip && *port == self.nt_ctx.nt_sett.pub_port
+    }
+
+    /// Finishes local islands and lets the dispatcher tell peers about it.
+    fn abort(&mut self) {
+        if self
+            .address_book
+            .send_to_all_local(Message::FinSim)
+            .is_err()
+        {
+            log::info!("Islands already finished");
+        }
+        if self
+            .address_book
+            .dispatcher_tx
+            .send(DispatcherMessage::Broadcast(Message::FinSim))
+            .is_err()
+        {
+            log::info!("Dispatcher already finished");
+        }
+    }
+
+    fn is_leaving(&self, turn: TurnNumber) -> bool {
+        self.nt_ctx
+            .nt_sett
+            .leave_after_turn
+            .is_some_and(|last_turn| turn > last_turn)
+    }
+
+    /// Tells the server this host leaves and finishes it.
+    fn leave(&mut self) {
+        let ip = self.nt_ctx.nt_sett.host_ip.clone();
+        let port = self.nt_ctx.nt_sett.pub_port;
+        let leave = DispatcherMessage::Server(Message::Leave(ip, port));
+        if self.address_book.dispatcher_tx.send(leave).is_err() {
+            log::info!("Dispatcher already finished");
+        }
+        self.finish_locally();
+    }
+
+    /// Finishes local islands without finishing the rest of the simulation.
+    fn finish_locally(&mut self) {
+        if self
+            .address_book
+            .send_to_all_local(Message::FinSim)
+            .is_err()
+        {
+            log::info!("Islands already finished");
+        }
+        if self
+            .address_book
+            .dispatcher_tx
+            .send(DispatcherMessage::Info(Message::FinSim))
+            .is_err()
+        {
+            log::info!("Dispatcher already finished");
+        }
+    }
+
+    /// Finishes local islands when the server is gone. The dispatcher tells
+    /// peers about it, but does not wait for the server to answer.
+    fn abort_without_server(&mut self) {
+        if self
+            .address_book
+            .send_to_all_local(Message::FinSim)
+            .is_err()
+        {
+            log::info!("Islands already finished");
+        }
+        if self
+            .address_book
+            .dispatcher_tx
+            .send(DispatcherMessage::Info(Message::Err))
+            .is_err()
+        {
+            log::info!("Dispatcher already finished");
+        }
+    }
 }
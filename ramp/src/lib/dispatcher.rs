@@ -1,15 +1,21 @@
-use crate::message::Message;
-use crate::network;
+use crate::address_book::{AddressBook, SendError};
+use crate::error::{RampError, Result};
+use crate::message::{Message, TurnNumber};
 use crate::network::{DispatcherNetworkCtx, Ip, Port};
-use rand::{thread_rng, Rng};
-use std::sync::mpsc::Receiver;
+use crate::outcome::IslandResult;
+use crate::{metrics, network};
+use std::collections::HashSet;
+use std::mem;
 use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 pub type Addr = (Ip, Port);
 
 #[derive(Debug)]
 pub enum DispatcherMessage {
-    UnicastRandom(Message),
+    /// Message for a random host chosen by a pick drawn by the sending island.
+    UnicastRandom(Message, u64),
     Unicast(Message, Addr),
     Broadcast(Message),
     Info(Message),
@@ -21,6 +27,12 @@ pub struct Dispatcher {
     nt_ctx: DispatcherNetworkCtx,
     islands: u32,
     sim_tx: Sender<Message>,
+    collector_tx: Sender<Message>,
+    /// Hosts declared dead by the server.
+    dead_hosts: HashSet<Addr>,
+    /// A request was sent to the server without getting a reply, so the
+    /// REQ socket cannot send another one.
+    awaiting_reply: bool,
 }
 
 impl Dispatcher {
@@ -29,96 +41,384 @@ impl Dispatcher {
         nt_ctx: DispatcherNetworkCtx,
         islands: u32,
         sim_tx: Sender<Message>,
+        collector_tx: Sender<Message>,
     ) -> Dispatcher {
         Dispatcher {
             rx,
             nt_ctx,
             islands,
             sim_tx,
+            collector_tx,
+            dead_hosts: HashSet::new(),
+            awaiting_reply: false,
         }
     }
 
-    pub fn start(&self) {
+    pub fn start(&mut self) {
         log::info!("Dispatcher started");
-        let mut fin_sim = false;
         let mut confirmations = 0;
-        let from = self.nt_ctx.nt_sett.host_ip.clone();
+        // Loads of local islands, sent to the server with the confirmation
+        let mut turn_loads = vec![];
+        let from = self.identity();
+        let heartbeat_interval = Duration::from_millis(network::HEARTBEAT_INTERVAL_MS);
+        let mut last_heartbeat = Instant::now();
         self.sim_tx
             .send(Message::Ok)
             .expect("Error sending to sim_tx");
-        while !fin_sim {
-            let incoming = self.rx.try_iter();
-            for msg in incoming {
-                match msg {
-                    DispatcherMessage::UnicastRandom(Message::Agent(_)) => {
-                        let random_index = thread_rng().gen_range(0, self.nt_ctx.ip_table.len());
-                        let (ip, port) = &self.nt_ctx.ip_table[random_index];
-                        let key = format!("{}:{}", ip, port);
-
-                        network::send_ps(
-                            &self.nt_ctx.pub_sock,
-                            key.clone(),
-                            from.clone(),
-                            msg.into(),
-                        );
-                    }
-                    DispatcherMessage::Unicast(msg, addr) => {
-                        let key = format!("{}:{}", addr.0, addr.1);
-                        network::send_ps(&self.nt_ctx.pub_sock, key, from.clone(), msg)
-                    }
-                    DispatcherMessage::Broadcast(Message::Agent(_)) => {
-                        let key = String::from(network::BROADCAST_KEY);
-                        network::send_ps(&self.nt_ctx.pub_sock, key, from.clone(), msg.into())
-                    }
-                    DispatcherMessage::Broadcast(Message::Islands(island_ids)) => {
-                        log::info!("ISLANDS MSG");
-                        let key = String::from(network::BROADCAST_KEY);
-                        network::send_ps(
-                            &self.nt_ctx.pub_sock,
-                            key.clone(),
-                            from.clone(),
-                            Message::Islands(island_ids.clone()),
-                        );
-                    }
-                    DispatcherMessage::Broadcast(Message::Owners(_)) => {
-                        log::info!("OWNERS MSG");
-                        let key = String::from(network::BROADCAST_KEY);
-                        network::send_ps(&self.nt_ctx.pub_sock, key, from.clone(), msg.into())
-                    }
-                    DispatcherMessage::Info(Message::HostReady) => {
-                        network::send_rr(&self.nt_ctx.s_req_sock, from.clone(), Message::HostReady);
-                        let (_, _) = network::recv_rr(&self.nt_ctx.s_req_sock);
+        loop {
+            // With global sync the server expects a heartbeat even when
+            // islands have nothing to send
+            if self.nt_ctx.nt_sett.global_sync.sync
+                && last_heartbeat.elapsed() >= heartbeat_interval
+            {
+                let key = String::from(network::HEARTBEAT_KEY);
+                if let Err(e) = self.publish(key, &from, Message::Heartbeat) {
+                    log::warn!("Cannot send heartbeat: {}", e);
+                }
+                last_heartbeat = Instant::now();
+            }
+            let msg = match self.rx.recv_timeout(heartbeat_interval) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let result = match msg {
+                DispatcherMessage::UnicastRandom(ref agent, _)
+                    if is_agent(agent) && self.nt_ctx.ip_table.is_empty() =>
+                {
+                    log::debug!("Dropping agent, no other hosts left");
+                    Ok(())
+                }
+                DispatcherMessage::UnicastRandom(ref agent, pick) if is_agent(agent) => {
+                    let index = (pick % self.nt_ctx.ip_table.len() as u64) as usize;
+                    let (ip, port) = &self.nt_ctx.ip_table[index];
+                    let key = format!("{}:{}", ip, port);
+
+                    self.publish(key, &from, msg.into())
+                }
+                DispatcherMessage::Unicast(msg, addr) if self.dead_hosts.contains(&addr) => {
+                    log::debug!(
+                        "Dropping {} for dead host {}:{}",
+                        msg.as_string(),
+                        addr.0,
+                        addr.1
+                    );
+                    Ok(())
+                }
+                DispatcherMessage::Unicast(msg, addr) => {
+                    let key = format!("{}:{}", addr.0, addr.1);
+                    self.publish(key, &from, msg)
+                }
+                DispatcherMessage::Broadcast(Message::Islands(island_ids)) => {
+                    log::info!("ISLANDS MSG");
+                    let key = String::from(network::BROADCAST_KEY);
+                    self.publish(key, &from, Message::Islands(island_ids))
+                }
+                DispatcherMessage::Broadcast(Message::Owners(_)) => {
+                    log::info!("OWNERS MSG");
+                    let key = String::from(network::BROADCAST_KEY);
+                    self.publish(key, &from, msg.into())
+                }
+                DispatcherMessage::Broadcast(Message::FinSim) => {
+                    self.abort(&from, true);
+                    break;
+                }
+                DispatcherMessage::Broadcast(msg) => {
+                    let key = String::from(network::BROADCAST_KEY);
+                    self.publish(key, &from, msg)
+                }
+                DispatcherMessage::Info(Message::HostReady)
+                | DispatcherMessage::Info(Message::Resume(_))
+                    if !self.nt_ctx.nt_sett.global_sync.sync =>
+                {
+                    // Without global sync there is no server waiting for hosts
+                    Ok(())
+                }
+                DispatcherMessage::Info(ready @ Message::HostReady)
+                | DispatcherMessage::Info(ready @ Message::Resume(_)) => {
+                    if let Err(e) = self.request_server(ready) {
+                        log::error!("Cannot send ready message to the server: {}", e);
+                        self.abort(&from, false);
+                        break;
                     }
+                    Ok(())
+                }
 
-                    DispatcherMessage::Info(Message::TurnDone) => {
-                        confirmations += 1;
-                        if confirmations == self.islands {
-                            network::send_rr(
-                                &self.nt_ctx.s_req_sock,
-                                from.clone(),
-                                Message::TurnDone,
-                            );
-                            let (_, _) = network::recv_rr(&self.nt_ctx.s_req_sock);
-                            confirmations = 0;
+                DispatcherMessage::Info(Message::TurnDone(mut loads)) => {
+                    confirmations += 1;
+                    turn_loads.append(&mut loads);
+                    if confirmations == self.islands {
+                        confirmations = 0;
+                        let done = Message::TurnDone(mem::take(&mut turn_loads));
+                        if let Err(e) = self.request_server(done) {
+                            log::error!("Cannot confirm turn to the server: {}", e);
+                            self.abort(&from, false);
+                            break;
                         }
                     }
-                    DispatcherMessage::Info(Message::FinSim) => {
-                        log::info!("Finishing simulation in dispatcher ");
-                        fin_sim = true;
+                    Ok(())
+                }
+                DispatcherMessage::Info(Message::FinSim) => {
+                    log::info!("Finishing simulation in dispatcher ");
+                    break;
+                }
+                DispatcherMessage::Info(Message::Err) => {
+                    self.abort(&from, false);
+                    break;
+                }
+                DispatcherMessage::Info(Message::IpTable(ip_table)) => {
+                    self.dead_hosts.retain(|addr| !ip_table.contains(addr));
+                    self.nt_ctx.ip_table = ip_table;
+                    Ok(())
+                }
+                DispatcherMessage::Server(msg) => match self.request_server(msg) {
+                    Ok(Message::Ok) => Ok(()),
+                    Ok(reply) => {
+                        log::error!("Server refused request: {}", reply.as_string());
+                        self.abort(&from, false);
+                        break;
+                    }
+                    Err(e) => {
+                        log::error!("Cannot send request to the server: {}", e);
+                        self.abort(&from, false);
                         break;
                     }
-                    _ => log::warn!("Unexpected msg in dispatcher {:#?}", msg),
+                },
+                DispatcherMessage::Info(Message::HostDown(ip, port)) => {
+                    if ip == self.nt_ctx.nt_sett.host_ip && port == self.nt_ctx.nt_sett.pub_port {
+                        let _ = self.sim_tx.send(Message::HostDown(ip, port));
+                        break;
+                    }
+                    self.nt_ctx
+                        .ip_table
+                        .retain(|(other_ip, other_port)| !(*other_ip == ip && *other_port == port));
+                    self.dead_hosts.insert((ip, port));
+                    Ok(())
+                }
+                _ => {
+                    log::warn!("Unexpected msg in dispatcher {:#?}", msg);
+                    Ok(())
                 }
+            };
+            if let Err(e) = result {
+                log::warn!("Dropping message which could not be sent: {}", e);
             }
         }
         log::info!("Dispatcher finished")
     }
+
+    /// Other hosts still taking part in the simulation.
+    pub fn hosts(&self) -> Vec<Addr> {
+        let nt_sett = &self.nt_ctx.nt_sett;
+        self.nt_ctx
+            .ip_table
+            .iter()
+            .filter(|(ip, port)| !(*ip == nt_sett.host_ip && *port == nt_sett.pub_port))
+            .cloned()
+            .collect()
+    }
+
+    /// Sends results of local islands to the coordinator, see [`crate::outcome`].
+    pub fn send_results(&self, results: Vec<IslandResult>) -> Result<()> {
+        let nt_sett = &self.nt_ctx.nt_sett;
+        let key = format!(
+            "{}:{}",
+            nt_sett.coordinator_ip, nt_sett.coordinator_pub_port
+        );
+        let msg = Message::Results(nt_sett.host_ip.clone(), nt_sett.pub_port, results);
+        self.publish(key, &self.identity(), msg)
+    }
+
+    /// Publishes the message and counts it in metrics.
+    fn publish(&self, key: String, from: &str, msg: Message) -> Result<()> {
+        let (target, kind) = (key.clone(), msg.kind());
+        let bytes = network::send_ps_sized(
+            &self.nt_ctx.pub_sock,
+            self.nt_ctx.codec,
+            key,
+            from.to_string(),
+            msg,
+        )?;
+        metrics::inc_sent_messages(&target, kind, bytes);
+        Ok(())
+    }
+
+    /// Identity of this host in requests to the server and heartbeats,
+    /// which tells apart hosts sharing an ip.
+    fn identity(&self) -> String {
+        network::host_key(&self.nt_ctx.nt_sett.host_ip, self.nt_ctx.nt_sett.pub_port)
+    }
+
+    fn request_server(&mut self, msg: Message) -> Result<Message> {
+        let kind = msg.kind();
+        let bytes = network::send_rr_sized(
+            &self.nt_ctx.s_req_sock,
+            self.nt_ctx.codec,
+            self.identity(),
+            msg,
+        )?;
+        self.awaiting_reply = true;
+        metrics::inc_sent_messages("server", kind, bytes);
+        let timeout_ms = self
+            .nt_ctx
+            .nt_sett
+            .global_sync
+            .server_timeout_ms
+            .unwrap_or(network::DEFAULT_PEER_TIMEOUT_MS);
+        if !network::poll_readable(&self.nt_ctx.s_req_sock, timeout_ms as i64)? {
+            return Err(RampError::Aborted(String::from("server does not answer")));
+        }
+        let (_, reply) = network::recv_rr(&self.nt_ctx.s_req_sock, self.nt_ctx.codec)?;
+        self.awaiting_reply = false;
+        Ok(reply)
+    }
+
+    /// Finishes the simulation on this host and tells peers to do the same.
+    /// The server is not told if it has not answered the last request.
+    fn abort(&mut self, from: &str, notify_server: bool) {
+        log::error!("Aborting simulation");
+        let key = String::from(network::BROADCAST_KEY);
+        if let Err(e) = self.publish(key, from, Message::FinSim) {
+            log::error!("Cannot send FinSim to peers: {}", e);
+        }
+        if notify_server && self.nt_ctx.nt_sett.global_sync.sync && !self.awaiting_reply {
+            if let Err(e) = self.request_server(Message::FinSim) {
+                log::error!("Cannot send FinSim to the server: {}", e);
+            }
+        }
+        // Collector and simulation are already gone if they finished first
+        let _ = self.collector_tx.send(Message::FinSim);
+        let _ = self.sim_tx.send(Message::Err);
+    }
+}
+
+/// Turns started by [`LocalDispatcher`] when islands are synchronized.
+#[derive(Debug, Clone)]
+pub struct LocalTurns {
+    pub first_turn: TurnNumber,
+    pub last_turn: TurnNumber,
+    pub checkpoint_interval: u32,
+}
+
+/// Dispatcher used when the simulation runs in a single process. Instead of
+/// publishing messages to other hosts it loops them back to the local islands.
+pub struct LocalDispatcher {
+    rx: Receiver<DispatcherMessage>,
+    address_book: AddressBook,
+    host_addr: Addr,
+    turns: Option<LocalTurns>,
+}
+
+impl LocalDispatcher {
+    /// With `turns` given the dispatcher plays the role of the sync server.
+    /// The next turn starts once every island has finished the previous one,
+    /// so all messages sent during a turn are delivered before the next one.
+    /// Messages sent to a host other than `host_addr` are dropped.
+    pub fn new(
+        rx: Receiver<DispatcherMessage>,
+        address_book: AddressBook,
+        host_addr: Addr,
+        turns: Option<LocalTurns>,
+    ) -> LocalDispatcher {
+        LocalDispatcher {
+            rx,
+            address_book,
+            host_addr,
+            turns,
+        }
+    }
+
+    pub fn start(&mut self) {
+        log::info!("Local dispatcher started");
+        let mut confirmations = 0;
+        let mut turn = self.turns.as_ref().map_or(0, |turns| turns.first_turn);
+        if let Err(e) = start_turn(&mut self.address_book, self.turns.as_ref(), turn) {
+            log::info!("{:?} (No more active islands in system)", e);
+        }
+        for msg in self.rx.iter() {
+            let result = match msg {
+                DispatcherMessage::UnicastRandom(msg, pick) => {
+                    self.address_book.send_to_picked_local(pick, msg)
+                }
+                DispatcherMessage::Unicast(msg, addr) if addr == self.host_addr => {
+                    deliver_local(&mut self.address_book, msg)
+                }
+                DispatcherMessage::Unicast(msg, addr) => {
+                    log::warn!(
+                        "Dropping {} for unknown host {}:{}",
+                        msg.as_string(),
+                        addr.0,
+                        addr.1
+                    );
+                    Ok(())
+                }
+                DispatcherMessage::Broadcast(msg) => self.address_book.send_to_all_local(msg),
+                DispatcherMessage::Info(Message::TurnDone(_)) => {
+                    confirmations += 1;
+                    if confirmations >= self.address_book.len() {
+                        confirmations = 0;
+                        turn += 1;
+                        start_turn(&mut self.address_book, self.turns.as_ref(), turn)
+                    } else {
+                        Ok(())
+                    }
+                }
+                DispatcherMessage::Info(Message::FinSim) => {
+                    log::info!("Finishing simulation in local dispatcher");
+                    break;
+                }
+                _ => {
+                    log::debug!("Ignoring msg in local dispatcher {:#?}", msg);
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                log::info!("{:?} (No more active islands in system)", e);
+            }
+        }
+        log::info!("Local dispatcher finished")
+    }
+}
+
+fn start_turn(
+    address_book: &mut AddressBook,
+    turns: Option<&LocalTurns>,
+    turn: TurnNumber,
+) -> std::result::Result<(), SendError<Message>> {
+    let turns = match turns {
+        Some(turns) => turns,
+        None => return Ok(()),
+    };
+    if turn > turns.last_turn {
+        return address_book.send_to_all_local(Message::FinSim);
+    }
+    if turns.checkpoint_interval != 0 && turn.is_multiple_of(turns.checkpoint_interval) {
+        address_book.send_to_all_local(Message::Checkpoint(turn))?;
+    }
+    address_book.send_to_all_local(Message::NextTurn(turn))
+}
+
+/// Messages for an island go to it, agents to a random local island.
+fn deliver_local(
+    address_book: &mut AddressBook,
+    msg: Message,
+) -> std::result::Result<(), SendError<Message>> {
+    match *msg.unstamped() {
+        Message::MapValue(_, island_id, _, _, _)
+        | Message::MapBatch(island_id, _)
+        | Message::Halo(island_id, _) => address_book.send_to_local(island_id, msg),
+        _ => address_book.send_to_rnd_local(msg),
+    }
+}
+
+fn is_agent(msg: &Message) -> bool {
+    matches!(msg.unstamped(), Message::Agent(_))
 }
 
-impl Into<Message> for DispatcherMessage {
-    fn into(self) -> Message {
-        match self {
-            DispatcherMessage::UnicastRandom(msg) => msg,
+impl From<DispatcherMessage> for Message {
+    fn from(msg: DispatcherMessage) -> Message {
+        match msg {
+            DispatcherMessage::UnicastRandom(msg, _pick) => msg,
             DispatcherMessage::Unicast(msg, _addr) => msg,
             DispatcherMessage::Broadcast(msg) => msg,
             DispatcherMessage::Info(msg) => msg,
@@ -0,0 +1,126 @@
+//! Errors returned by ramp.
+//!
+//! Failures are handled according to where they happen:
+//! * setup (settings, logger, binding and connecting sockets) aborts the
+//!   simulation before any island is started and the error is returned
+//!   from [`crate::simulation::Simulation::run`],
+//! * a malformed packet received during the run is logged and dropped,
+//! * lost handshake messages (ip table, island ids) are sent again,
+//! * a broken connection to the server aborts the simulation and `FinSim`
+//!   is sent to peers so they can finish cleanly.
+
+use config::ConfigError;
+use flexi_logger::FlexiLoggerError;
+use std::fmt;
+use std::io;
+use std::sync::mpsc::SendError;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum RampError {
+    MissingSettings,
+    MissingFactory,
+    Settings(ConfigError),
+    Logger(FlexiLoggerError),
+    Network(zmq::Error),
+    Serialization(String),
+    IncompatiblePeer(String),
+    Io(io::Error),
+    InvalidFrame(String),
+    UnknownCell(i64, i64),
+    MissingFragment(Uuid),
+    IslandUnreachable(Uuid),
+    UnexpectedAgent(String),
+    UnknownAgent(Uuid),
+    MissingAgentIndex,
+    ChannelClosed,
+    Checkpoint(String),
+    Aborted(String),
+    Panicked,
+}
+
+pub type Result<T> = std::result::Result<T, RampError>;
+
+impl fmt::Display for RampError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RampError::MissingSettings => write!(f, "simulation settings not provided"),
+            RampError::MissingFactory => write!(f, "island factory not provided"),
+            RampError::Settings(e) => write!(f, "invalid settings: {}", e),
+            RampError::Logger(e) => write!(f, "cannot initialize logger: {}", e),
+            RampError::Network(e) => write!(f, "network error: {}", e),
+            RampError::Serialization(e) => write!(f, "cannot (de)serialize message: {}", e),
+            RampError::IncompatiblePeer(reason) => write!(f, "incompatible peer: {}", reason),
+            RampError::Io(e) => write!(f, "io error: {}", e),
+            RampError::InvalidFrame(reason) => write!(f, "invalid frame: {}", reason),
+            RampError::UnknownCell(x, y) => write!(f, "no fragment owns cell ({}, {})", x, y),
+            RampError::MissingFragment(island_id) => {
+                write!(f, "island {} owns no fragment of the map", island_id)
+            }
+            RampError::IslandUnreachable(id) => write!(f, "island {} is unreachable", id),
+            RampError::UnexpectedAgent(kind) => write!(f, "unexpected agent of type {}", kind),
+            RampError::UnknownAgent(id) => write!(f, "agent {} is not on the map", id),
+            RampError::MissingAgentIndex => write!(f, "map instance does not index agents"),
+            RampError::ChannelClosed => write!(f, "internal channel closed"),
+            RampError::Checkpoint(reason) => write!(f, "invalid checkpoint: {}", reason),
+            RampError::Aborted(reason) => write!(f, "simulation aborted: {}", reason),
+            RampError::Panicked => write!(f, "simulation thread panicked"),
+        }
+    }
+}
+
+impl std::error::Error for RampError {}
+
+impl From<ConfigError> for RampError {
+    fn from(e: ConfigError) -> Self {
+        RampError::Settings(e)
+    }
+}
+
+impl From<FlexiLoggerError> for RampError {
+    fn from(e: FlexiLoggerError) -> Self {
+        RampError::Logger(e)
+    }
+}
+
+impl From<zmq::Error> for RampError {
+    fn from(e: zmq::Error) -> Self {
+        RampError::Network(e)
+    }
+}
+
+impl From<bincode::Error> for RampError {
+    fn from(e: bincode::Error) -> Self {
+        RampError::Serialization(e.to_string())
+    }
+}
+
+impl From<rmp_serde::encode::Error> for RampError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        RampError::Serialization(e.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for RampError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        RampError::Serialization(e.to_string())
+    }
+}
+
+impl From<serde_cbor::Error> for RampError {
+    fn from(e: serde_cbor::Error) -> Self {
+        RampError::Serialization(e.to_string())
+    }
+}
+
+impl From<io::Error> for RampError {
+    fn from(e: io::Error) -> Self {
+        RampError::Io(e)
+    }
+}
+
+impl<T> From<SendError<T>> for RampError {
+    fn from(_: SendError<T>) -> Self {
+        RampError::ChannelClosed
+    }
+}
@@ -1,5 +1,9 @@
 use crate::address_book::SendError;
+use crate::codec::{Codec, CodecKind};
+use crate::error::RampError;
 use crate::message::Message;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::sync::Arc;
 
 use uuid::Uuid;
@@ -8,6 +12,8 @@ use crate::address_book::AddressBook;
 use crate::dispatcher::Addr;
 use crate::map::{FragmentOwner, Map};
 use crate::metrics::MetricHub;
+use crate::recorder::Recorder;
+use crate::rng::{self, IdGenerator, IslandRng};
 use std::time::Instant;
 
 pub struct IslandEnv {
@@ -16,6 +22,10 @@ pub struct IslandEnv {
     pub map: Map,
     pub metric_hub: Arc<MetricHub>,
     pub start_time: Instant,
+    rng: IslandRng,
+    id_generator: IdGenerator,
+    codec: CodecKind,
+    recorder: Recorder,
 }
 
 impl IslandEnv {
@@ -25,6 +35,8 @@ impl IslandEnv {
         fragment_owner: FragmentOwner,
         metric_hub: Arc<MetricHub>,
         start_time: Instant,
+        island_seed: u64,
+        codec: CodecKind,
     ) -> IslandEnv {
         IslandEnv {
             address_book,
@@ -32,9 +44,57 @@ impl IslandEnv {
             fragment_owner,
             metric_hub,
             start_time,
+            rng: rng::island_rng(island_seed),
+            id_generator: rng::island_id_generator(island_seed),
+            codec,
+            recorder: Recorder::disabled(),
         }
     }
 
+    /// Values recorded by the island are written to files, see [`crate::recorder`].
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Random number generator of the island derived from the simulation
+    /// seed. Use it instead of `thread_rng` to keep runs reproducible.
+    pub fn rng(&mut self) -> &mut IslandRng {
+        &mut self.rng
+    }
+
+    /// Records values of the island per turn, e.g. its best fitness.
+    pub fn recorder(&mut self) -> &mut Recorder {
+        &mut self.recorder
+    }
+
+    /// Generates a new id (e.g. for an agent) from a seeded stream.
+    pub fn new_id(&mut self) -> Uuid {
+        self.id_generator.next_id()
+    }
+
+    pub(crate) fn codec(&self) -> CodecKind {
+        self.codec
+    }
+
+    /// Encodes a result of the island with the codec of the simulation,
+    /// to be returned from [`Island::result`].
+    pub fn encode_result<T: Serialize>(&self, result: &T) -> Result<Vec<u8>, RampError> {
+        self.codec.encode(result)
+    }
+
+    /// Encodes state of the island with the codec of the simulation,
+    /// to be returned from [`Island::snapshot`].
+    pub fn encode_snapshot<T: Serialize>(&self, state: &T) -> Result<Vec<u8>, RampError> {
+        self.codec.encode(state)
+    }
+
+    /// Decodes state encoded by [`IslandEnv::encode_snapshot`], e.g. in
+    /// [`Island::restore`].
+    pub fn decode_snapshot<T: DeserializeOwned>(&self, snapshot: &[u8]) -> Result<T, RampError> {
+        self.codec.decode(snapshot)
+    }
+
     pub fn send_to_rnd_local(&mut self, msg: Message) -> Result<(), SendError<Message>> {
         self.address_book.send_to_rnd_local(msg)
     }
@@ -64,7 +124,7 @@ impl IslandEnv {
     }
 
     pub fn get_active_islands_number(&self) -> i32 {
-        self.address_book.islands.len() as i32
+        self.address_book.len() as i32
     }
 }
 
@@ -74,8 +134,34 @@ pub trait Island: Send {
     fn do_turn(&mut self, turn_number: u32, messages: Vec<Message>);
 
     fn on_finish(&mut self);
+
+    /// Result of the island taken after [`Island::on_finish`], encoded with
+    /// [`IslandEnv::encode_result`]. Results are gathered by the coordinator,
+    /// see [`crate::outcome`].
+    fn result(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Serialized state of the island stored in checkpoints, encoded with
+    /// [`IslandEnv::encode_snapshot`]. Islands returning `None` are never
+    /// checkpointed.
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Restores state returned by [`Island::snapshot`]. It is called
+    /// instead of [`Island::on_start`] when the simulation is restored.
+    fn restore(&mut self, _snapshot: &[u8]) -> Result<(), RampError> {
+        Ok(())
+    }
+
+    /// Number of agents on the island, reported to the server with the
+    /// time of each turn to balance load between hosts.
+    fn agent_count(&self) -> Option<u64> {
+        None
+    }
 }
 
-pub trait IslandFactory {
+pub trait IslandFactory: Send {
     fn create(&self, island_id: Uuid, island_env: IslandEnv) -> Box<dyn Island>;
 }
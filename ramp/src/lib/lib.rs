@@ -6,13 +6,26 @@ extern crate lazy_static;
 extern crate prometheus;
 
 pub mod address_book;
+pub mod balance;
+pub mod checkpoint;
+pub mod codec;
 pub mod collector;
 pub mod dispatcher;
+pub mod error;
 pub mod island;
 pub mod map;
 pub mod message;
 pub mod metrics;
+pub mod migration;
 pub mod network;
+pub mod outcome;
+pub mod pace;
+pub mod partition;
+pub mod recorder;
+pub mod rng;
+pub mod server;
 pub mod settings;
 pub mod simulation;
+pub mod spatial;
+pub mod topology;
 pub mod utils;
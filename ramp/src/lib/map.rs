@@ -1,35 +1,334 @@
+use crate::codec::Codec;
 use crate::island::IslandEnv;
 use crate::network::{Ip, Port};
 
+use crate::error::{RampError, Result};
 use crate::message::Message;
-use std::collections::HashMap;
-use std::convert::TryInto;
+use crate::partition::{BoundingBox, Partitioner, Strips};
+use crate::spatial::AgentIndex;
+use crate::topology::{Bounded, Direction, Position, Topology};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
 use uuid::Uuid;
 
 pub type Fragment = std::ops::Range<u64>;
 pub type FragmentOwner = (Ip, Port, Uuid);
 pub type MapOwners = HashMap<Fragment, FragmentOwner>;
+/// Correlation id of a read, unique among reads of an island.
+pub type RequestId = u64;
 
+/// Answer to [`MapInstance::request_value`]. `value` is `None` when the
+/// cell is not owned by the island which received the request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellValue<T> {
+    pub request_id: RequestId,
+    pub x: i64,
+    pub y: i64,
+    pub value: Option<T>,
+}
+
+/// Where a cell is, as seen by an island.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    /// Cell of the island itself.
+    Own,
+    /// Cell of another island of the same host.
+    Local(FragmentOwner),
+    /// Cell of an island of another host.
+    Remote(FragmentOwner),
+    /// Cell outside of the map or of a dropped fragment.
+    Unreachable,
+}
+
+/// Map shared by all islands of a host. Clones share the owners, so
+/// fragments of a failed host are dropped for every island at once.
 #[derive(Debug, Clone)]
 pub struct Map {
-    pub owners: MapOwners,
+    owners: Arc<RwLock<MapOwners>>,
+    index: Arc<RwLock<FragmentIndex>>,
+    partitioner: Arc<dyn Partitioner>,
+    topology: Arc<dyn Topology>,
     pub chunk_len: i64,
+    /// Cells of neighbouring fragments mirrored by every island.
+    pub halo: i64,
+}
+
+/// Fragments by their first offset, to find owners without a scan.
+#[derive(Debug, Default)]
+struct FragmentIndex {
+    /// Changes whenever owners change.
+    generation: u64,
+    fragments: BTreeMap<u64, (Fragment, FragmentOwner, Option<BoundingBox>)>,
+    /// First offset of the fragment of each island.
+    islands: HashMap<Uuid, u64>,
+    /// Box containing all fragments seen so far. Cells of dropped fragments
+    /// stay in the map, so wrapping around it does not change.
+    extent: Option<BoundingBox>,
 }
 
 impl Map {
     pub fn new(chunk_len: i64, owners: MapOwners) -> Self {
-        Self { chunk_len, owners }
+        let map = Self {
+            chunk_len,
+            halo: 0,
+            owners: Arc::new(RwLock::new(owners)),
+            index: Arc::default(),
+            partitioner: Arc::new(Strips { width: chunk_len }),
+            topology: Arc::new(Bounded),
+        };
+        map.update_index(&map.owners());
+        map
+    }
+
+    pub fn with_halo(mut self, halo: i64) -> Self {
+        self.halo = halo;
+        self
+    }
+
+    pub fn with_partitioner(mut self, partitioner: Arc<dyn Partitioner>) -> Self {
+        self.partitioner = partitioner;
+        self.index = Arc::default();
+        self.update_index(&self.owners());
+        self
+    }
+
+    pub fn with_topology(mut self, topology: Arc<dyn Topology>) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn owners(&self) -> RwLockReadGuard<'_, MapOwners> {
+        self.owners.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fragment containing `offset` and its owner.
+    pub fn owner_of_offset(&self, offset: u64) -> Option<(Fragment, FragmentOwner)> {
+        self.index()
+            .fragments
+            .range(..=offset)
+            .next_back()
+            .filter(|(_, (fragment, _, _))| fragment.contains(&offset))
+            .map(|(_, (fragment, owner, _))| (fragment.clone(), owner.clone()))
+    }
+
+    /// Owner of the fragment containing cell `pos`.
+    pub fn owner_of(&self, pos: Position) -> Option<FragmentOwner> {
+        self.offset_of(pos.0, pos.1)
+            .and_then(|offset| self.owner_of_offset(offset))
+            .map(|(_, owner)| owner)
+    }
+
+    /// Owner of fragments of the island, to find the host it runs on.
+    pub fn owner_of_island(&self, island_id: Uuid) -> Option<FragmentOwner> {
+        let index = self.index();
+        let start = index.islands.get(&island_id)?;
+        index
+            .fragments
+            .get(start)
+            .map(|(_, owner, _)| owner.clone())
+    }
+
+    /// Fragment owned by the island.
+    pub fn fragment_of(&self, island_id: Uuid) -> Option<Fragment> {
+        let index = self.index();
+        let start = index.islands.get(&island_id)?;
+        index
+            .fragments
+            .get(start)
+            .map(|(fragment, _, _)| fragment.clone())
+    }
+
+    /// Cell next to `pos` in direction `dir`, given by the topology.
+    pub fn neighbour(&self, pos: Position, dir: Direction) -> Option<Position> {
+        let extent = self.extent()?;
+        self.topology.neighbour(pos, dir, &extent)
+    }
+
+    /// Cells within `radius` moves in any direction from `pos`, including
+    /// `pos` itself, closest first. Wraps around the map if the topology does.
+    pub fn cells_within(&self, pos: Position, radius: i64) -> Vec<Position> {
+        if self.offset_of(pos.0, pos.1).is_none() {
+            return vec![];
+        }
+        let mut reached = vec![pos];
+        let mut seen: BTreeSet<Position> = reached.iter().copied().collect();
+        let mut border = 0;
+        for _ in 0..radius {
+            let end = reached.len();
+            for i in border..end {
+                for dir in Direction::ALL.iter() {
+                    match self.neighbour(reached[i], *dir) {
+                        Some(next) if seen.insert(next) => reached.push(next),
+                        _ => (),
+                    }
+                }
+            }
+            border = end;
+        }
+        reached
+    }
+
+    /// Smallest box containing all cells of the map.
+    pub fn extent(&self) -> Option<BoundingBox> {
+        self.index().extent.clone()
+    }
+
+    /// Offset of cell `(x, y)` given by the partitioner.
+    pub fn offset_of(&self, x: i64, y: i64) -> Option<u64> {
+        self.partitioner.offset(x, y)
+    }
+
+    /// Cell at `offset` given by the partitioner.
+    pub fn cell_at(&self, offset: u64) -> Option<(i64, i64)> {
+        self.partitioner.cell(offset)
+    }
+
+    /// Smallest box containing cells of an owned fragment.
+    pub fn bounds(&self, fragment: &Fragment) -> Option<BoundingBox> {
+        self.index()
+            .fragments
+            .get(&fragment.start)
+            .and_then(|(_, _, bounds)| bounds.clone())
+    }
+
+    /// Number of changes of owners, to know when cached owners are stale.
+    pub fn generation(&self) -> u64 {
+        self.index().generation
+    }
+
+    /// Drops fragments owned by islands of the host, which makes their
+    /// cells unreachable. Returns the number of dropped fragments.
+    pub fn remove_host(&self, ip: &str, port: Port) -> usize {
+        let mut owners = self.owners.write().unwrap_or_else(PoisonError::into_inner);
+        let removed = remove_host(&mut owners, ip, port);
+        self.update_index(&owners);
+        removed
+    }
+
+    /// Replaces owners after hosts joined or left the simulation.
+    pub fn set_owners(&self, owners: MapOwners) {
+        let mut current = self.owners.write().unwrap_or_else(PoisonError::into_inner);
+        *current = owners;
+        self.update_index(&current);
+    }
+
+    fn index(&self) -> RwLockReadGuard<'_, FragmentIndex> {
+        self.index.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update_index(&self, owners: &MapOwners) {
+        let mut index = self.index.write().unwrap_or_else(PoisonError::into_inner);
+        index.generation += 1;
+        index.fragments = owners
+            .iter()
+            .map(|(fragment, owner)| {
+                let bounds = self.partitioner.bounds(fragment);
+                (fragment.start, (fragment.clone(), owner.clone(), bounds))
+            })
+            .collect();
+        index.islands = owners
+            .iter()
+            .map(|(fragment, (_, _, island_id))| (*island_id, fragment.start))
+            .collect();
+        let FragmentIndex {
+            fragments, extent, ..
+        } = &mut *index;
+        *extent = fragments
+            .values()
+            .filter_map(|(_, _, bounds)| bounds.clone())
+            .chain(extent.take())
+            .reduce(|extent, bounds| BoundingBox {
+                x: extent.x.start.min(bounds.x.start)..extent.x.end.max(bounds.x.end),
+                y: extent.y.start.min(bounds.y.start)..extent.y.end.max(bounds.y.end),
+            });
+    }
+}
+
+/// Drops fragments owned by islands of the host.
+/// Returns the number of dropped fragments.
+pub fn remove_host(owners: &mut MapOwners, ip: &str, port: Port) -> usize {
+    let before = owners.len();
+    owners.retain(|_, (owner_ip, owner_port, _)| !(owner_ip == ip && *owner_port == port));
+    before - owners.len()
+}
+
+/// Assigns fragments following the last one to islands of a joining host.
+/// Fragments of other islands are kept, because islands hold the state of
+/// their cells. Returns `false` if there is no fragment to take the length from
+/// or the fragments would not fit in `capacity` cells, see [`Partitioner::capacity`].
+pub fn add_host(
+    owners: &mut MapOwners,
+    ip: &str,
+    port: Port,
+    island_ids: &[Uuid],
+    capacity: Option<u64>,
+) -> bool {
+    let fragment_len = match owners.keys().next() {
+        Some(fragment) => fragment.end - fragment.start,
+        None => return false,
+    };
+    let mut start = owners
+        .keys()
+        .map(|fragment| fragment.end)
+        .max()
+        .unwrap_or(0);
+    let end = start + fragment_len * island_ids.len() as u64;
+    if capacity.is_some_and(|capacity| end > capacity) {
+        return false;
+    }
+    for &island_id in island_ids {
+        let fragment = start..start + fragment_len;
+        owners.insert(fragment, (ip.to_string(), port, island_id));
+        start += fragment_len;
+    }
+    true
+}
+
+/// Moves fragments of two islands along with the islands, each one to the
+/// host of the other.
+pub fn exchange_hosts(owners: &mut MapOwners, first: &FragmentOwner, second: &FragmentOwner) {
+    for (ip, port, island_id) in owners.values_mut() {
+        let moved_to = if island_id == &first.2 {
+            second
+        } else if island_id == &second.2 {
+            first
+        } else {
+            continue;
+        };
+        *ip = moved_to.0.clone();
+        *port = moved_to.1;
     }
 }
 
+/// Own cells mirrored by each neighbouring island.
+type HaloPlan = Vec<(FragmentOwner, Vec<u64>)>;
+
+/// Fragment of the map owned by an island, with cells of any type `T`.
+/// Cells are sent to other islands encoded with the codec of the simulation.
 #[derive(Debug, Clone)]
-pub struct MapInstance {
+pub struct MapInstance<T> {
     pub map: Map,
-    pub data: Vec<i32>,
+    pub data: Vec<T>,
     pub fragment_owner: FragmentOwner,
+    next_request: RequestId,
+    values: Vec<CellValue<T>>,
+    /// Cells of neighbouring fragments by offset and the generation of
+    /// owners they were received for.
+    halo: HashMap<u64, T>,
+    halo_generation: u64,
+    /// Offsets of cells mirrored by other islands and the generation
+    /// of owners they were found for.
+    halo_plan: Option<(u64, HaloPlan)>,
+    /// Writes to cells of other islands waiting for [`MapInstance::flush`].
+    writes: BTreeMap<Uuid, (FragmentOwner, BTreeMap<Position, T>)>,
+    /// Agents on the fragment, if enabled.
+    agents: Option<AgentIndex>,
 }
 
-impl MapInstance {
+impl<T: Serialize + DeserializeOwned + Clone> MapInstance<T> {
     pub fn get_instance(island_env: &IslandEnv) -> Self {
         Self {
             data: Vec::with_capacity(
@@ -37,94 +336,596 @@ impl MapInstance {
             ),
             map: island_env.map.clone(),
             fragment_owner: island_env.fragment_owner.clone(),
+            next_request: 0,
+            values: Vec::new(),
+            halo: HashMap::new(),
+            halo_generation: island_env.map.generation(),
+            halo_plan: None,
+            writes: BTreeMap::new(),
+            agents: None,
         }
     }
 
-    pub fn set(&mut self, island_env: &mut IslandEnv, x: i64, y: i64, val: i32) {
-        let offset = self.pos_to_offset(x, y);
-        let range = self
+    /// Keeps an index of agents on the fragment, see [`MapInstance::place_agent`].
+    pub fn with_agent_index(mut self) -> Self {
+        self.agents = Some(AgentIndex::new());
+        self
+    }
+
+    /// Writes cell `(x, y)` wherever it is. Cells of this island are written
+    /// at once, writes to other cells wait for [`MapInstance::flush`] and only
+    /// the last write to a cell is sent.
+    pub fn set(&mut self, x: i64, y: i64, val: T) -> Result<()> {
+        let owner = self
             .map
-            .owners
-            .keys()
-            .find(|&r| r.contains(&offset.try_into().unwrap()))
-            .unwrap();
-
-        let (other_ip, other_port, other_island_id) = self.map.owners.get(range).unwrap();
-        let (my_ip, _my_port, my_island_id) = &self.fragment_owner;
-
-        if other_island_id.to_string() == my_island_id.to_string() {
-            self.data[offset as usize] = val;
-        } else if other_ip == my_ip {
-            // TODO: Sending between islands on host
-            island_env
-                .send_to_local(*other_island_id, Message::MapSet(x, y, val))
-                .expect("Error sending to local in map");
+            .owner_of((x, y))
+            .ok_or(RampError::UnknownCell(x, y))?;
+
+        if owner.2 == self.fragment_owner.2 {
+            self.update_value(val, (x, y));
         } else {
-            // TODO: Sending between hosts
-            island_env.send_to_global((other_ip.clone(), *other_port), Message::MapSet(x, y, val));
+            self.writes
+                .entry(owner.2)
+                .or_insert_with(|| (owner, BTreeMap::new()))
+                .1
+                .insert((x, y), val);
         }
+        Ok(())
     }
 
-    pub fn pos_to_offset(&self, x: i64, y: i64) -> i64 {
-        y * self.map.chunk_len + x
+    /// Sends writes to cells of other islands, one [`Message::MapBatch`]
+    /// per island. Called at the end of a turn, owners apply the writes in
+    /// [`MapInstance::handle_messages`] at the start of their next turn.
+    ///
+    /// With a halo it also sends border cells of this island, as they are at
+    /// the end of the turn, to owners of neighbouring fragments. They mirror
+    /// them in their next turn, so islands with a halo must call it every turn.
+    pub fn flush(&mut self, island_env: &mut IslandEnv) {
+        for (island_id, (owner, cells)) in mem::take(&mut self.writes) {
+            let cells: Vec<(Position, T)> = cells.into_iter().collect();
+            let sent = island_env.codec().encode(&cells).and_then(|cells| {
+                let batch = Message::MapBatch(island_id, cells);
+                self.send_to_owner(island_env, owner, batch)
+            });
+            if let Err(e) = sent {
+                log::warn!("Cannot send writes to island {}: {}", island_id, e);
+            }
+        }
+        self.push_halo(island_env);
     }
 
-    pub fn offset_to_pos(&self, offset: i64) -> (i64, i64) {
-        let x = offset % self.map.chunk_len;
-        let y = offset / self.map.chunk_len;
+    /// Reads cell `(x, y)` wherever it is. Values of cells owned by this
+    /// island are available at once, other cells are answered by their
+    /// owners in a later turn. Answers are collected by
+    /// [`MapInstance::handle_messages`] and returned by [`MapInstance::take_values`].
+    pub fn request_value(
+        &mut self,
+        island_env: &mut IslandEnv,
+        x: i64,
+        y: i64,
+    ) -> Result<RequestId> {
+        let owner = self
+            .map
+            .owner_of((x, y))
+            .ok_or(RampError::UnknownCell(x, y))?;
+        let request_id = self.next_request;
+        self.next_request += 1;
 
-        (x, y)
+        if owner.2 == self.fragment_owner.2 {
+            let value = self.cell_value(x, y).cloned();
+            self.values.push(CellValue {
+                request_id,
+                x,
+                y,
+                value,
+            });
+        } else {
+            let request = Message::MapGet(request_id, self.fragment_owner.clone(), x, y);
+            self.send_to_owner(island_env, owner, request)?;
+        }
+        Ok(request_id)
     }
 
-    pub fn init_with_val(&mut self, val: i32) {
-        for i in 0..self.map.chunk_len * self.map.chunk_len {
-            self.data.insert(i as usize, val);
+    /// Applies writes and answers reads of cells of this island, and
+    /// collects answers to its own reads. Other messages are left in place.
+    ///
+    /// With a halo it also mirrors border cells sent by owners of
+    /// neighbouring fragments in [`MapInstance::flush`] at the end of the
+    /// previous turn. Mirrored cells are dropped whenever owners of the map
+    /// change and filled again by their new owners.
+    pub fn handle_messages(&mut self, island_env: &mut IslandEnv, messages: &mut Vec<Message>) {
+        let codec = island_env.codec();
+        let generation = self.map.generation();
+        if self.halo_generation != generation {
+            self.halo.clear();
+            self.halo_generation = generation;
         }
+        let mut rest = Vec::with_capacity(messages.len());
+        for msg in messages.drain(..) {
+            match msg {
+                Message::MapBatch(_, cells) => match codec.decode::<Vec<(Position, T)>>(&cells) {
+                    Ok(cells) => {
+                        for ((x, y), val) in cells {
+                            match self.cell_value(x, y) {
+                                Some(_) => self.update_value(val, (x, y)),
+                                None => log::warn!(
+                                    "Cell ({}, {}) is no longer owned by this island",
+                                    x,
+                                    y
+                                ),
+                            }
+                        }
+                    }
+                    Err(e) => log::warn!("Cannot decode writes: {}", e),
+                },
+                Message::MapGet(request_id, requester, x, y) => {
+                    let value = match self.cell_value(x, y).map(|val| codec.encode(val)) {
+                        Some(Ok(val)) => Some(val),
+                        Some(Err(e)) => {
+                            log::warn!("Cannot encode cell ({}, {}): {}", x, y, e);
+                            None
+                        }
+                        None => None,
+                    };
+                    let answer = Message::MapValue(request_id, requester.2, x, y, value);
+                    if let Err(e) = self.send_to_owner(island_env, requester, answer) {
+                        log::warn!("Cannot answer read of cell ({}, {}): {}", x, y, e);
+                    }
+                }
+                Message::MapValue(request_id, _, x, y, value) => {
+                    match value.map(|val| codec.decode(&val)).transpose() {
+                        Ok(value) => self.values.push(CellValue {
+                            request_id,
+                            x,
+                            y,
+                            value,
+                        }),
+                        Err(e) => log::warn!("Cannot decode cell ({}, {}): {}", x, y, e),
+                    }
+                }
+                Message::Halo(_, cells) => match codec.decode::<Vec<(u64, T)>>(&cells) {
+                    Ok(cells) => {
+                        let map = &self.map;
+                        let owned = cells
+                            .into_iter()
+                            .filter(|(offset, _)| map.owner_of_offset(*offset).is_some());
+                        self.halo.extend(owned)
+                    }
+                    Err(e) => log::warn!("Cannot decode halo: {}", e),
+                },
+                msg => rest.push(msg),
+            }
+        }
+        *messages = rest;
     }
 
-    pub fn get_my_range(&self) -> Fragment {
-        let (_, _, my_island_id) = &self.fragment_owner;
-        let range = self
-            .map
-            .owners
-            .keys()
-            .find(|&k| self.map.owners.get(k).unwrap().2 == *my_island_id)
-            .unwrap();
-        range.clone()
-    }
-
-    pub fn get_value(&self, pos: (i64, i64)) -> i32 {
-        let offset = self.pos_to_offset(pos.0, pos.1);
-        let range_start = self.get_my_range().start;
-        let scale = self.map.chunk_len.pow(2);
-        let local_index = if range_start == 0 {
-            offset
-        } else {
-            offset - (range_start as i64 / scale) * scale
+    /// Sends border cells to owners of neighbouring fragments.
+    fn push_halo(&mut self, island_env: &mut IslandEnv) {
+        if self.map.halo == 0 || self.data.is_empty() {
+            return;
+        }
+        let generation = self.map.generation();
+        if self.halo_plan.as_ref().map(|(planned, _)| *planned) != Some(generation) {
+            self.halo_plan = Some((generation, self.plan_halo()));
+        }
+        let start = match self.get_my_range() {
+            Some(range) => range.start,
+            None => return,
         };
-        if self.data.get(local_index as usize) == None {
-            -1
+        let plan = self.halo_plan.as_ref().map(|(_, plan)| plan.as_slice());
+        for (owner, offsets) in plan.unwrap_or_default() {
+            let cells: Vec<(u64, &T)> = offsets
+                .iter()
+                .map(|&offset| (offset, &self.data[(offset - start) as usize]))
+                .collect();
+            let sent = island_env.codec().encode(&cells).and_then(|cells| {
+                let halo = Message::Halo(owner.2, cells);
+                self.send_to_owner(island_env, owner.clone(), halo)
+            });
+            if let Err(e) = sent {
+                log::warn!("Cannot send halo: {}", e);
+            }
+        }
+    }
+
+    /// Finds cells of this island within the halo of other fragments.
+    fn plan_halo(&self) -> HaloPlan {
+        let halo = self.map.halo;
+        let mut plan: BTreeMap<Uuid, (FragmentOwner, BTreeSet<u64>)> = BTreeMap::new();
+        for offset in self.get_my_range().into_iter().flatten() {
+            let cell = match self.map.cell_at(offset) {
+                Some(cell) => cell,
+                None => continue,
+            };
+            for pos in self.map.cells_within(cell, halo) {
+                match self.map.owner_of(pos) {
+                    Some(owner) if owner.2 != self.fragment_owner.2 => {
+                        plan.entry(owner.2)
+                            .or_insert_with(|| (owner, BTreeSet::new()))
+                            .1
+                            .insert(offset);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        plan.into_iter()
+            .map(|(_, (owner, offsets))| (owner, offsets.into_iter().collect()))
+            .collect()
+    }
+
+    /// Tells whether cell `pos` belongs to this island, another island
+    /// of this host or a remote host, e.g. to decide where an agent moving
+    /// to the cell has to migrate.
+    pub fn locate(&self, pos: Position) -> Location {
+        match self.map.owner_of(pos) {
+            Some(owner) if owner.2 == self.fragment_owner.2 => Location::Own,
+            Some(owner) if self.is_on_this_host(&owner) => Location::Local(owner),
+            Some(owner) => Location::Remote(owner),
+            None => Location::Unreachable,
+        }
+    }
+
+    /// Puts a new or received agent at `pos`. The agent is indexed only if
+    /// the cell belongs to this island, otherwise the caller has to send it
+    /// where [`Location`] tells.
+    pub fn place_agent(&mut self, id: Uuid, pos: Position) -> Result<Location> {
+        let location = self.locate(pos);
+        let agents = self.agents.as_mut().ok_or(RampError::MissingAgentIndex)?;
+        if location == Location::Own {
+            agents.insert(id, pos);
+        }
+        Ok(location)
+    }
+
+    /// Moves an indexed agent to `pos`. An agent moving to a cell of another
+    /// island leaves the index and has to migrate where [`Location`] tells,
+    /// an agent moving to an unreachable cell stays where it was.
+    pub fn move_agent(&mut self, id: Uuid, pos: Position) -> Result<Location> {
+        let location = self.locate(pos);
+        let agents = self.agents.as_mut().ok_or(RampError::MissingAgentIndex)?;
+        if agents.position_of(id).is_none() {
+            return Err(RampError::UnknownAgent(id));
+        }
+        match location {
+            Location::Own => {
+                agents.insert(id, pos);
+            }
+            Location::Local(_) | Location::Remote(_) => {
+                agents.remove(id);
+            }
+            Location::Unreachable => (),
+        }
+        Ok(location)
+    }
+
+    /// Drops an agent from the index, e.g. when it dies.
+    /// Returns its last position.
+    pub fn remove_agent(&mut self, id: Uuid) -> Option<Position> {
+        self.agents.as_mut()?.remove(id)
+    }
+
+    pub fn position_of(&self, id: Uuid) -> Option<Position> {
+        self.agents.as_ref()?.position_of(id)
+    }
+
+    /// Indexed agents at `pos` in the order they entered the cell.
+    pub fn agents_at(&self, pos: Position) -> &[Uuid] {
+        self.agents
+            .as_ref()
+            .map_or(&[], |agents| agents.agents_at(pos))
+    }
+
+    /// Indexed agents within `radius` moves from `pos`, closest cells first.
+    /// Agents of other islands are not indexed, even in the halo.
+    pub fn agents_within(&self, pos: Position, radius: i64) -> Vec<Uuid> {
+        match self.agents.as_ref() {
+            Some(agents) => self
+                .map
+                .cells_within(pos, radius)
+                .into_iter()
+                .flat_map(|cell| agents.agents_at(cell).iter().copied())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Answers to reads received so far.
+    pub fn take_values(&mut self) -> Vec<CellValue<T>> {
+        mem::take(&mut self.values)
+    }
+
+    fn send_to_owner(
+        &self,
+        island_env: &mut IslandEnv,
+        owner: FragmentOwner,
+        msg: Message,
+    ) -> Result<()> {
+        if self.is_on_this_host(&owner) {
+            island_env
+                .send_to_local(owner.2, msg)
+                .map_err(|_| RampError::IslandUnreachable(owner.2))
         } else {
-            *self.data.get(local_index as usize).unwrap()
+            let (ip, port, _) = owner;
+            island_env.send_to_global((ip, port), msg);
+            Ok(())
         }
     }
 
-    pub fn update_value(&mut self, new_val: i32, pos: (i64, i64)) {
-        let offset = self.pos_to_offset(pos.0, pos.1);
-        let range_start = self.get_my_range().start;
-        let scale = self.map.chunk_len.pow(2);
-        let local_index = if range_start == 0 {
-            offset
+    fn is_on_this_host(&self, (ip, port, _): &FragmentOwner) -> bool {
+        *ip == self.fragment_owner.0 && *port == self.fragment_owner.1
+    }
+
+    /// Value of a cell of this island.
+    fn cell_value(&self, x: i64, y: i64) -> Option<&T> {
+        let offset = self.pos_to_offset(x, y)?;
+        let range = self.get_my_range()?;
+        if range.contains(&offset) {
+            self.data.get((offset - range.start) as usize)
         } else {
-            offset - (range_start as i64 / scale) * scale
+            None
+        }
+    }
+
+    pub fn pos_to_offset(&self, x: i64, y: i64) -> Option<u64> {
+        self.map.offset_of(x, y)
+    }
+
+    pub fn offset_to_pos(&self, offset: u64) -> Option<(i64, i64)> {
+        self.map.cell_at(offset)
+    }
+
+    pub fn init_with_val(&mut self, val: T) -> Result<()> {
+        let range = self
+            .get_my_range()
+            .ok_or(RampError::MissingFragment(self.fragment_owner.2))?;
+        self.data = vec![val; (range.end - range.start) as usize];
+        Ok(())
+    }
+
+    /// Fragment of this island, `None` once it owns none, e.g. after its
+    /// host was dropped from the simulation.
+    pub fn get_my_range(&self) -> Option<Fragment> {
+        self.map.fragment_of(self.fragment_owner.2)
+    }
+
+    /// Value of a cell of this island or its halo.
+    pub fn get_value(&self, pos: (i64, i64)) -> Option<&T> {
+        let (x, y) = pos;
+        self.cell_value(x, y)
+            .or_else(|| self.halo.get(&self.pos_to_offset(x, y)?))
+    }
+
+    /// Sets a cell of this island, other cells are ignored.
+    pub fn update_value(&mut self, new_val: T, pos: (i64, i64)) {
+        let range = self.get_my_range().unwrap_or_default();
+        match self.pos_to_offset(pos.0, pos.1) {
+            Some(offset) if range.contains(&offset) => {
+                self.data[(offset - range.start) as usize] = new_val;
+            }
+            _ => log::warn!("Cell {:?} is not owned by this island", pos),
+        }
+    }
+
+    /// Adjacent cells (including diagonals) whose values are known to
+    /// this island, i.e. cells of its fragment and its halo.
+    pub fn get_neighbourhood(&self, x: i64, y: i64) -> Vec<(i64, i64)> {
+        Direction::ALL
+            .iter()
+            .filter_map(|dir| self.map.neighbour((x, y), *dir))
+            .filter(|cell| self.get_value(*cell).is_some())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_host, remove_host, FragmentOwner, Location, Map, MapInstance, MapOwners};
+    use crate::address_book::{self, AddressBook};
+    use crate::codec::{Codec, CodecKind};
+    use crate::island::IslandEnv;
+    use crate::message::Message;
+    use crate::metrics::MetricHub;
+    use crate::partition::{Blocks, BoundingBox};
+    use crate::topology::{Direction, Torus};
+    use std::sync::{mpsc, Arc};
+    use std::time::Instant;
+    use uuid::Uuid;
+
+    fn island_env(map: Map, fragment_owner: FragmentOwner) -> IslandEnv {
+        let (dispatcher_tx, _) = mpsc::channel();
+        let address_book = AddressBook::new(
+            dispatcher_tx,
+            address_book::local_islands(&[], vec![]),
+            None,
+            0,
+        );
+        let metric_hub = Arc::new(MetricHub::default());
+        let now = Instant::now();
+        IslandEnv::new(
+            address_book,
+            map,
+            fragment_owner,
+            metric_hub,
+            now,
+            0,
+            CodecKind::Bincode,
+        )
+    }
+
+    #[test]
+    fn test_remove_host_is_shared_by_clones() {
+        let mut owners = MapOwners::new();
+        owners.insert(0..10, (String::from("10.0.0.1"), 5000, Uuid::nil()));
+        owners.insert(10..20, (String::from("10.0.0.2"), 5000, Uuid::nil()));
+        let map = Map::new(10, owners);
+        let other = map.clone();
+
+        assert_eq!(other.remove_host("10.0.0.2", 5000), 1);
+        assert!(map.owner_of_offset(15).is_none());
+        assert_eq!(
+            map.owner_of_offset(5).map(|(fragment, _)| fragment),
+            Some(0..10)
+        );
+    }
+
+    #[test]
+    fn test_owner_of_cell() {
+        let mut owners = MapOwners::new();
+        owners.insert(0..4, (String::from("10.0.0.1"), 5000, Uuid::nil()));
+        owners.insert(4..8, (String::from("10.0.0.2"), 5000, Uuid::nil()));
+        let map = Map::new(2, owners);
+
+        assert_eq!(map.owner_of((1, 1)).unwrap().0, "10.0.0.1");
+        assert_eq!(map.owner_of((0, 2)).unwrap().0, "10.0.0.2");
+        assert!(map.owner_of((0, 4)).is_none());
+        assert!(map.owner_of((-1, 0)).is_none());
+    }
+
+    #[test]
+    fn test_owner_of_cell_in_blocks() {
+        let mut owners = MapOwners::new();
+        owners.insert(0..4, (String::from("10.0.0.1"), 5000, Uuid::nil()));
+        owners.insert(4..8, (String::from("10.0.0.2"), 5000, Uuid::nil()));
+        let map = Map::new(2, owners).with_partitioner(Arc::new(Blocks {
+            block_len: 2,
+            columns: 2,
+        }));
+
+        assert_eq!(map.owner_of((1, 1)).unwrap().0, "10.0.0.1");
+        assert_eq!(map.owner_of((2, 0)).unwrap().0, "10.0.0.2");
+        assert!(map.owner_of((0, 2)).is_none());
+        assert_eq!(map.bounds(&(4..8)), Some(BoundingBox { x: 2..4, y: 0..2 }));
+
+        let generation = map.generation();
+        map.remove_host("10.0.0.2", 5000);
+        assert!(map.generation() > generation);
+        assert!(map.bounds(&(4..8)).is_none());
+    }
+
+    #[test]
+    fn test_neighbours_on_torus() {
+        let mut owners = MapOwners::new();
+        owners.insert(0..4, (String::from("10.0.0.1"), 5000, Uuid::nil()));
+        owners.insert(4..8, (String::from("10.0.0.2"), 5000, Uuid::nil()));
+        let map = Map::new(2, owners).with_topology(Arc::new(Torus));
+
+        assert_eq!(map.extent(), Some(BoundingBox { x: 0..2, y: 0..4 }));
+        assert_eq!(map.neighbour((0, 0), Direction::North), Some((0, 3)));
+        assert_eq!(map.neighbour((1, 1), Direction::East), Some((0, 1)));
+
+        // Dropped fragments stay in the map
+        map.remove_host("10.0.0.2", 5000);
+        assert_eq!(map.neighbour((0, 0), Direction::North), Some((0, 3)));
+        assert!(map.owner_of((0, 3)).is_none());
+    }
+
+    #[test]
+    fn test_halo_is_dropped_when_owners_change() {
+        let own = (String::from("10.0.0.1"), 5000, Uuid::new_v4());
+        let other = (String::from("10.0.0.2"), 5000, Uuid::new_v4());
+        let mut owners = MapOwners::new();
+        owners.insert(0..4, own.clone());
+        owners.insert(4..8, other);
+        let map = Map::new(2, owners);
+        let mut env = island_env(map.clone(), own.clone());
+        let mut instance: MapInstance<i32> = MapInstance::get_instance(&env);
+        let halo = |offset: u64| {
+            let cells = CodecKind::Bincode.encode(&vec![(offset, 9)]).unwrap();
+            vec![Message::Halo(own.2, cells)]
         };
-        //log::warn!("value before: {}", &self.data.get(local_index as usize).unwrap());
-        //log::info!("The local index for this position is {}", local_index);
-        *self.data.get_mut(local_index as usize).unwrap() = new_val;
-        //log::warn!("value after: {}", &self.data.get(local_index as usize).unwrap());
+
+        instance.handle_messages(&mut env, &mut halo(5));
+        assert_eq!(instance.get_value((1, 2)), Some(&9));
+
+        map.remove_host("10.0.0.2", 5000);
+        instance.handle_messages(&mut env, &mut vec![]);
+        assert_eq!(instance.get_value((1, 2)), None);
+
+        // Late borders of a fragment without owner are ignored
+        instance.handle_messages(&mut env, &mut halo(5));
+        assert_eq!(instance.get_value((1, 2)), None);
+    }
+
+    #[test]
+    fn test_fragment_of_dropped_island() {
+        let own = (String::from("10.0.0.1"), 5000, Uuid::new_v4());
+        let mut owners = MapOwners::new();
+        owners.insert(4..8, own.clone());
+        let map = Map::new(2, owners);
+        let env = island_env(map.clone(), own);
+        let mut instance: MapInstance<i32> = MapInstance::get_instance(&env);
+
+        assert_eq!(instance.get_my_range(), Some(4..8));
+        instance.init_with_val(1).unwrap();
+        assert_eq!(instance.get_value((0, 2)), Some(&1));
+
+        map.remove_host("10.0.0.1", 5000);
+        assert_eq!(instance.get_my_range(), None);
+        assert_eq!(instance.get_value((0, 2)), None);
+        assert!(instance.init_with_val(1).is_err());
     }
 
-    //    pub fn get_neighbourhood(&self, x: u64, y: u64) -> Vec<(u64, u64)> {
-    //        unimplemented!();
-    //    }
+    #[test]
+    fn test_agent_index_follows_moves() {
+        let own = (String::from("10.0.0.1"), 5000, Uuid::new_v4());
+        let other = (String::from("10.0.0.1"), 5000, Uuid::new_v4());
+        let mut owners = MapOwners::new();
+        owners.insert(0..4, own.clone());
+        owners.insert(4..8, other.clone());
+        let map = Map::new(2, owners);
+        let env = island_env(map, own);
+        let mut map: MapInstance<i32> = MapInstance::get_instance(&env).with_agent_index();
+
+        let (sheep, wolf) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(map.place_agent(sheep, (0, 0)).unwrap(), Location::Own);
+        assert_eq!(map.place_agent(wolf, (1, 1)).unwrap(), Location::Own);
+        assert_eq!(map.agents_within((0, 0), 1), vec![sheep, wolf]);
+
+        assert_eq!(map.move_agent(sheep, (1, 1)).unwrap(), Location::Own);
+        assert_eq!(map.agents_at((1, 1)), &[wolf, sheep]);
+        assert_eq!(
+            map.move_agent(wolf, (1, 2)).unwrap(),
+            Location::Local(other)
+        );
+        assert_eq!(
+            map.move_agent(sheep, (2, 1)).unwrap(),
+            Location::Unreachable
+        );
+        assert_eq!(map.agents_at((1, 1)), &[sheep]);
+        assert!(map.move_agent(wolf, (0, 0)).is_err());
+    }
+
+    #[test]
+    fn test_add_host_keeps_fragments() {
+        let mut owners = MapOwners::new();
+        owners.insert(0..10, (String::from("10.0.0.1"), 5000, Uuid::nil()));
+        owners.insert(10..20, (String::from("10.0.0.2"), 5000, Uuid::nil()));
+        remove_host(&mut owners, "10.0.0.2", 5000);
+
+        let joined = [Uuid::new_v4(), Uuid::new_v4()];
+        assert!(add_host(&mut owners, "10.0.0.3", 5000, &joined, None));
+        assert_eq!(owners[&(0..10)].0, "10.0.0.1");
+        assert_eq!(owners[&(10..20)].2, joined[0]);
+        assert_eq!(owners[&(20..30)].2, joined[1]);
+        assert!(!add_host(
+            &mut MapOwners::new(),
+            "10.0.0.3",
+            5000,
+            &joined,
+            None
+        ));
+    }
+
+    #[test]
+    fn test_add_host_within_capacity() {
+        let mut owners = MapOwners::new();
+        owners.insert(0..10, (String::from("10.0.0.1"), 5000, Uuid::nil()));
+
+        let joined = [Uuid::new_v4(), Uuid::new_v4()];
+        assert!(!add_host(&mut owners, "10.0.0.2", 5000, &joined, Some(20)));
+        assert_eq!(owners.len(), 1);
+        assert!(add_host(&mut owners, "10.0.0.2", 5000, &joined, Some(30)));
+        assert_eq!(owners.len(), 3);
+    }
 }
@@ -1,7 +1,10 @@
 use uuid::Uuid;
 
-use crate::map::MapOwners;
+use crate::balance::IslandLoad;
+use crate::map::{FragmentOwner, MapOwners, RequestId};
+use crate::migration::Migrant;
 use crate::network::{Ip, Port};
+use crate::outcome::IslandResult;
 
 pub type TurnNumber = u32;
 
@@ -9,35 +12,131 @@ pub type TurnNumber = u32;
 pub enum Message {
     Islands(Vec<Uuid>),
     Owners(MapOwners),
-    MapSet(i64, i64, i32),
-    MapGet(i64, i64, i32),
-    Agent(Vec<u8>),
+    MapBatch(Uuid, Vec<u8>),
+    MapGet(RequestId, FragmentOwner, i64, i64),
+    MapValue(RequestId, Uuid, i64, i64, Option<Vec<u8>>),
+    Halo(Uuid, Vec<u8>),
+    Agent(Migrant),
     Hello(Ip, Port),
     IpTable(Vec<(Ip, Port)>),
     StartSim,
     FinSim,
     HostReady,
     NextTurn(TurnNumber),
-    TurnDone,
+    TurnDone(Vec<IslandLoad>),
+    Checkpoint(TurnNumber),
+    Resume(TurnNumber),
+    Heartbeat,
+    HostDown(Ip, Port),
+    Join(Ip, Port, Vec<Uuid>),
+    Leave(Ip, Port),
+    /// The first island moves to the host of the second one and back.
+    Exchange(FragmentOwner, FragmentOwner),
+    /// State of an island moved to another host and messages it has not
+    /// handled yet. The state is missing if the island has no snapshot.
+    IslandState(Uuid, Option<Vec<u8>>, Vec<Message>),
+    /// Message sent by an island in the given turn, see [`crate::pace`].
+    Stamped(TurnNumber, Box<Message>),
+    /// The island has finished the turn.
+    Progress(Uuid, TurnNumber),
+    /// Message sent by an island as its n-th one, see
+    /// [`crate::address_book::AddressBook::with_sequence`].
+    Sequenced(Uuid, u64, Box<Message>),
+    /// Results of islands of a host sent to the coordinator at the end.
+    Results(Ip, Port, Vec<IslandResult>),
     Ok,
     Err,
 }
 
 impl Message {
+    /// The message itself, without the turn or the order it was sent in.
+    pub fn unstamped(&self) -> &Message {
+        match self {
+            Message::Stamped(_, msg) | Message::Sequenced(_, _, msg) => msg.unstamped(),
+            msg => msg,
+        }
+    }
+
+    /// Name of the variant, e.g. `next_turn`. Stamped and sequenced
+    /// messages are of the kind of the message they wrap.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Islands(..) => "islands",
+            Message::Owners(..) => "owners",
+            Message::MapBatch(..) => "map_batch",
+            Message::MapGet(..) => "map_get",
+            Message::MapValue(..) => "map_value",
+            Message::Halo(..) => "halo",
+            Message::Agent(..) => "agent",
+            Message::Hello(..) => "hello",
+            Message::IpTable(..) => "ip_table",
+            Message::StartSim => "start_sim",
+            Message::FinSim => "fin_sim",
+            Message::HostReady => "host_ready",
+            Message::NextTurn(..) => "next_turn",
+            Message::TurnDone(..) => "turn_done",
+            Message::Checkpoint(..) => "checkpoint",
+            Message::Resume(..) => "resume",
+            Message::Heartbeat => "heartbeat",
+            Message::HostDown(..) => "host_down",
+            Message::Join(..) => "join",
+            Message::Leave(..) => "leave",
+            Message::Exchange(..) => "exchange",
+            Message::IslandState(..) => "island_state",
+            Message::Stamped(_, msg) | Message::Sequenced(_, _, msg) => msg.kind(),
+            Message::Progress(..) => "progress",
+            Message::Results(..) => "results",
+            Message::Ok => "ok",
+            Message::Err => "err",
+        }
+    }
+
     pub fn as_string(&self) -> String {
         match self {
             Message::Islands(island_ids) => format!("({:#?})", island_ids),
             Message::Owners(owners) => format!("MAP OWNERS {:#?}", owners),
-            Message::MapSet(x, y, value) => format!("MAP SET ({}, {}) -> {}", x, y, value),
-            Message::MapGet(x, y, value) => format!("MAP GET ({}, {}) -> {}", x, y, value),
-            Message::Agent(agent_vec) => format!("{:#?}", agent_vec),
+            Message::MapBatch(island_id, _) => format!("MAP BATCH TO {}", island_id),
+            Message::MapGet(request_id, (ip, port, island_id), x, y) => format!(
+                "MAP GET #{} ({}, {}) FROM {}:{} ({})",
+                request_id, x, y, ip, port, island_id
+            ),
+            Message::MapValue(request_id, island_id, x, y, _) => {
+                format!("MAP VALUE #{} ({}, {}) TO {}", request_id, x, y, island_id)
+            }
+            Message::Agent(migrant) => format!("AGENT {}", migrant.kind),
+            Message::Halo(island_id, _) => format!("HALO TO {}", island_id),
             Message::Hello(ip, port) => format!("HELLO FROM {}:{}", ip, port),
             Message::IpTable(table) => format!("IP TABLE {:#?}", table),
             Message::StartSim => String::from("START SIM"),
             Message::FinSim => String::from("FIN SIM"),
             Message::HostReady => String::from("HOST READY"),
             Message::NextTurn(turn_number) => format!("NEXT TURN ({})", turn_number),
-            Message::TurnDone => String::from("TURN DONE"),
+            Message::TurnDone(_) => String::from("TURN DONE"),
+            Message::Checkpoint(turn_number) => format!("CHECKPOINT ({})", turn_number),
+            Message::Resume(turn_number) => format!("RESUME ({})", turn_number),
+            Message::Heartbeat => String::from("HEARTBEAT"),
+            Message::HostDown(ip, port) => format!("HOST DOWN {}:{}", ip, port),
+            Message::Join(ip, port, island_ids) => {
+                format!("JOIN {}:{} ({:#?})", ip, port, island_ids)
+            }
+            Message::Leave(ip, port) => format!("LEAVE {}:{}", ip, port),
+            Message::Exchange((ip, port, island_id), (other_ip, other_port, other_id)) => format!(
+                "EXCHANGE {} ({}:{}) WITH {} ({}:{})",
+                island_id, ip, port, other_id, other_ip, other_port
+            ),
+            Message::IslandState(island_id, _, _) => format!("ISLAND STATE {}", island_id),
+            Message::Stamped(turn_number, msg) => {
+                format!("{} (SENT IN TURN {})", msg.as_string(), turn_number)
+            }
+            Message::Progress(island_id, turn_number) => {
+                format!("PROGRESS {} ({})", island_id, turn_number)
+            }
+            Message::Sequenced(island_id, seq, msg) => {
+                format!("{} (#{} OF {})", msg.as_string(), seq, island_id)
+            }
+            Message::Results(ip, port, results) => {
+                format!("RESULTS OF {} ISLANDS FROM {}:{}", results.len(), ip, port)
+            }
             Message::Ok => String::from("OK"),
             Message::Err => String::from("ERROR"),
         }
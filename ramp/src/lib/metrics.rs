@@ -1,22 +1,78 @@
 use hyper::{header::CONTENT_TYPE, rt::Future, service::service_fn_ok, Body, Response, Server};
-use prometheus::{Encoder, GaugeVec, IntGaugeVec, TextEncoder};
+use prometheus::{
+    CounterVec, Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::message::TurnNumber;
+
+/// Buckets of histograms of durations in seconds, see [`prometheus::DEFAULT_BUCKETS`].
+pub use prometheus::DEFAULT_BUCKETS;
 
 lazy_static! {
-    static ref TOTAL_RECV_MESSAGES_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+    static ref RECV_MESSAGES_COUNTER: IntCounterVec = register_int_counter_vec!(
         "messages_recv_total",
-        "total messages received from source by target from last request",
-        &["source", "target", "status"]
+        "messages received from source by target",
+        &["source", "target", "kind"]
+    )
+    .unwrap();
+    static ref RECV_BYTES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "message_bytes_recv_total",
+        "bytes of messages received from source by target",
+        &["source", "target", "kind"]
+    )
+    .unwrap();
+    static ref SENT_MESSAGES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "messages_sent_total",
+        "messages sent by the host to target",
+        &["target", "kind"]
+    )
+    .unwrap();
+    static ref SENT_BYTES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "message_bytes_sent_total",
+        "bytes of messages sent by the host to target",
+        &["target", "kind"]
+    )
+    .unwrap();
+    static ref ISLAND_MESSAGES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "island_messages_sent_total",
+        "messages sent by islands to islands on the same host (local) or other hosts (global)",
+        &["kind", "route"]
+    )
+    .unwrap();
+    static ref QUEUE_DEPTH_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "island_queue_depth",
+        "messages waiting for the island when its last turn started",
+        &["island_id"]
+    )
+    .unwrap();
+    static ref TURN_GAUGE: IntGaugeVec =
+        register_int_gauge_vec!("island_turn", "turn the island is in", &["island_id"]).unwrap();
+    static ref TURN_DURATION_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "turn_duration_seconds",
+        "time of do_turn of an island",
+        &["island_id"]
+    )
+    .unwrap();
+    static ref BARRIER_WAIT_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "barrier_wait_seconds",
+        "time an island waits for other islands before a turn",
+        &["island_id"]
     )
     .unwrap();
 }
 
 pub type MetricName = String;
 
+#[derive(Default)]
 pub struct MetricHub {
     pub int_gauges_vec: HashMap<MetricName, IntGaugeVec>,
     pub gauges_vec: HashMap<MetricName, GaugeVec>,
+    pub int_counters_vec: HashMap<MetricName, IntCounterVec>,
+    pub counters_vec: HashMap<MetricName, CounterVec>,
+    pub histograms_vec: HashMap<MetricName, HistogramVec>,
 }
 
 impl MetricHub {
@@ -81,19 +137,100 @@ impl MetricHub {
             gauge.reset();
         }
     }
-}
 
-impl Default for MetricHub {
-    fn default() -> Self {
-        Self {
-            int_gauges_vec: HashMap::new(),
-            gauges_vec: HashMap::new(),
+    pub fn register_int_counter_vec(&mut self, name: &str, desc: &str, labels: &[&str]) {
+        self.int_counters_vec.insert(
+            name.to_owned(),
+            register_int_counter_vec!(name, desc, labels).unwrap(),
+        );
+    }
+
+    pub fn add_int_counter_vec(&self, name: &str, labels: &[&str], value: u64) {
+        if let Some(counter) = self.int_counters_vec.get(name) {
+            counter.with_label_values(labels).inc_by(value as i64);
+        }
+    }
+
+    pub fn inc_int_counter_vec(&self, name: &str, labels: &[&str]) {
+        if let Some(counter) = self.int_counters_vec.get(name) {
+            counter.with_label_values(labels).inc();
+        }
+    }
+
+    pub fn reset_int_counter_vec(&self, name: &str) {
+        if let Some(counter) = self.int_counters_vec.get(name) {
+            counter.reset();
+        }
+    }
+
+    pub fn register_counter_vec(&mut self, name: &str, desc: &str, labels: &[&str]) {
+        self.counters_vec.insert(
+            name.to_owned(),
+            register_counter_vec!(name, desc, labels).unwrap(),
+        );
+    }
+
+    /// Counters only grow, a negative `value` is ignored.
+    pub fn add_counter_vec(&self, name: &str, labels: &[&str], value: f64) {
+        if let Some(counter) = self.counters_vec.get(name) {
+            if value >= 0.0 {
+                counter.with_label_values(labels).inc_by(value);
+            }
+        }
+    }
+
+    pub fn inc_counter_vec(&self, name: &str, labels: &[&str]) {
+        if let Some(counter) = self.counters_vec.get(name) {
+            counter.with_label_values(labels).inc();
+        }
+    }
+
+    pub fn reset_counter_vec(&self, name: &str) {
+        if let Some(counter) = self.counters_vec.get(name) {
+            counter.reset();
+        }
+    }
+
+    /// `buckets` are upper bounds of the buckets in increasing order,
+    /// e.g. [`DEFAULT_BUCKETS`] or [`prometheus::exponential_buckets`].
+    pub fn register_histogram_vec(
+        &mut self,
+        name: &str,
+        desc: &str,
+        labels: &[&str],
+        buckets: Vec<f64>,
+    ) {
+        self.histograms_vec.insert(
+            name.to_owned(),
+            register_histogram_vec!(name, desc, labels, buckets).unwrap(),
+        );
+    }
+
+    pub fn observe_histogram_vec(&self, name: &str, labels: &[&str], value: f64) {
+        if let Some(histogram) = self.histograms_vec.get(name) {
+            histogram.with_label_values(labels).observe(value);
+        }
+    }
+
+    pub fn reset_histogram_vec(&self, name: &str) {
+        if let Some(histogram) = self.histograms_vec.get(name) {
+            histogram.reset();
         }
     }
 }
 
 pub fn start_server(address: String) {
-    let addr: SocketAddr = address.parse().unwrap();
+    let addr: SocketAddr = match address.parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            log::error!(
+                "Metrics are not exposed, invalid address {}: {}",
+                address,
+                e
+            );
+            return;
+        }
+    };
     let new_service = || {
         let encoder = TextEncoder::new();
         service_fn_ok(move |_request| {
@@ -105,7 +242,6 @@ pub fn start_server(address: String) {
                 .header(CONTENT_TYPE, encoder.format_type())
                 .body(Body::from(buffer))
                 .unwrap();
-            TOTAL_RECV_MESSAGES_GAUGE.reset();
             response
         })
     };
@@ -118,15 +254,70 @@ pub fn start_server(address: String) {
     hyper::rt::run(server);
 }
 
-pub fn inc_received_messages(from: String, target: String, status: String) {
-    TOTAL_RECV_MESSAGES_GAUGE
-        .with_label_values(&[from.as_str(), target.as_str(), status.as_str()])
+/// Counts a received message of the given kind, see
+/// [`crate::message::Message::kind`], and its size in bytes. Both only grow,
+/// so every scraper computes rates on its own.
+pub fn inc_received_messages(from: &str, target: &str, kind: &str, bytes: usize) {
+    RECV_MESSAGES_COUNTER
+        .with_label_values(&[from, target, kind])
+        .inc();
+    RECV_BYTES_COUNTER
+        .with_label_values(&[from, target, kind])
+        .inc_by(bytes as i64);
+}
+
+/// Counts a sent message of the given kind and its size in bytes. Target is
+/// a host, another publishing key such as [`crate::network::BROADCAST_KEY`],
+/// or `server` for requests to the server.
+pub fn inc_sent_messages(target: &str, kind: &str, bytes: usize) {
+    SENT_MESSAGES_COUNTER
+        .with_label_values(&[target, kind])
         .inc();
+    SENT_BYTES_COUNTER
+        .with_label_values(&[target, kind])
+        .inc_by(bytes as i64);
+}
+
+/// Counts a message of the given kind sent by an island, `global` if it
+/// goes to other hosts.
+pub fn inc_island_messages(kind: &str, global: bool) {
+    let route = if global { "global" } else { "local" };
+    ISLAND_MESSAGES_COUNTER
+        .with_label_values(&[kind, route])
+        .inc();
+}
+
+/// Turn the island starts and the number of messages it takes for it.
+pub fn set_turn_start(island_id: &str, turn: TurnNumber, queued: usize) {
+    TURN_GAUGE
+        .with_label_values(&[island_id])
+        .set(i64::from(turn));
+    QUEUE_DEPTH_GAUGE
+        .with_label_values(&[island_id])
+        .set(queued as i64);
+}
+
+/// Time of a turn of the island, measured by ramp itself.
+pub fn observe_turn_duration(island_id: &str, duration: Duration) {
+    TURN_DURATION_HISTOGRAM
+        .with_label_values(&[island_id])
+        .observe(duration.as_secs_f64());
+}
+
+/// Time the island waited for other islands or the server before a turn.
+pub fn observe_barrier_wait(island_id: &str, duration: Duration) {
+    BARRIER_WAIT_HISTOGRAM
+        .with_label_values(&[island_id])
+        .observe(duration.as_secs_f64());
 }
 
 #[cfg(test)]
 mod tests {
-    use super::MetricHub;
+    use super::{
+        MetricHub, QUEUE_DEPTH_GAUGE, RECV_BYTES_COUNTER, RECV_MESSAGES_COUNTER,
+        SENT_BYTES_COUNTER, SENT_MESSAGES_COUNTER, TURN_GAUGE,
+    };
+    use prometheus::core::Metric;
 
     #[test]
     fn test_gauge_vec_api() {
@@ -160,9 +351,7 @@ mod tests {
             panic!("No such metric");
         }
 
-        if let Some(_) = metrics.gauges_vec.get("different_metric_name") {
-            panic!("No such metric");
-        }
+        assert!(!metrics.gauges_vec.contains_key("different_metric_name"));
     }
 
     #[test]
@@ -197,8 +386,74 @@ mod tests {
             panic!("No such metric");
         }
 
-        if let Some(_) = metrics.int_gauges_vec.get("different_metric_name") {
-            panic!("No such metric");
+        assert!(!metrics.int_gauges_vec.contains_key("different_metric_name"));
+    }
+
+    #[test]
+    fn test_counter_vec_api() {
+        let metric_labels = &["l1"];
+        let mut metrics = MetricHub::default();
+
+        metrics.register_int_counter_vec("test_int_counter", "test desc", &["test_label"]);
+        metrics.inc_int_counter_vec("test_int_counter", metric_labels);
+        metrics.add_int_counter_vec("test_int_counter", metric_labels, 2);
+        let counter = &metrics.int_counters_vec["test_int_counter"];
+        assert_eq!(counter.with_label_values(metric_labels).get(), 3);
+
+        metrics.register_counter_vec("test_counter", "test desc", &["test_label"]);
+        metrics.add_counter_vec("test_counter", metric_labels, 1.5);
+        metrics.add_counter_vec("test_counter", metric_labels, -1.0);
+        metrics.inc_counter_vec("test_counter", metric_labels);
+        let counter = &metrics.counters_vec["test_counter"];
+        assert_eq!(counter.with_label_values(metric_labels).get(), 2.5);
+    }
+
+    #[test]
+    fn test_histogram_vec_api() {
+        let metric_name = "test_histogram";
+        let metric_labels = &["l1"];
+        let mut metrics = MetricHub::default();
+
+        let buckets = vec![1.0, 10.0];
+        metrics.register_histogram_vec(metric_name, "test desc", &["test_label"], buckets);
+        for value in &[0.5, 5.0, 50.0] {
+            metrics.observe_histogram_vec(metric_name, metric_labels, *value);
         }
+        let histogram = metrics.histograms_vec[metric_name].with_label_values(metric_labels);
+        let proto = histogram.metric();
+        let histogram = proto.get_histogram();
+        assert_eq!(histogram.get_sample_count(), 3);
+        assert_eq!(histogram.get_sample_sum(), 55.5);
+        let buckets = histogram.get_bucket();
+        let counts: Vec<u64> = buckets.iter().map(|b| b.get_cumulative_count()).collect();
+        assert_eq!(counts, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_received_messages_are_not_reset_by_scrapes() {
+        let labels = &["test_source", "test_target", "next_turn"];
+        super::inc_received_messages("test_source", "test_target", "next_turn", 10);
+        prometheus::gather();
+        super::inc_received_messages("test_source", "test_target", "next_turn", 10);
+
+        let messages = RECV_MESSAGES_COUNTER.with_label_values(labels);
+        assert_eq!(messages.get(), 2);
+        assert_eq!(RECV_BYTES_COUNTER.with_label_values(labels).get(), 20);
+    }
+
+    #[test]
+    fn test_builtin_metrics() {
+        super::inc_sent_messages("test_host", "agent", 100);
+        super::inc_sent_messages("test_host", "agent", 50);
+        let labels = &["test_host", "agent"];
+        assert_eq!(SENT_MESSAGES_COUNTER.with_label_values(labels).get(), 2);
+        assert_eq!(SENT_BYTES_COUNTER.with_label_values(labels).get(), 150);
+
+        super::set_turn_start("test_island", 7, 3);
+        assert_eq!(TURN_GAUGE.with_label_values(&["test_island"]).get(), 7);
+        assert_eq!(
+            QUEUE_DEPTH_GAUGE.with_label_values(&["test_island"]).get(),
+            3
+        );
     }
 }
@@ -0,0 +1,191 @@
+//! Typed migration of agents between islands.
+//!
+//! [`MigrationEnv`] wraps an [`IslandEnv`] and sends agents of a single
+//! type `A` as [`Migrant`]s tagged with the kind of agent given to
+//! [`MigrationEnv::new`]. Migrants of another kind (e.g. sent by a different
+//! binary) are rejected with [`RampError::UnexpectedAgent`] instead of being
+//! decoded into garbage. The kind is part of the wire format, so it is named
+//! explicitly rather than derived from the type, whose name may differ
+//! between builds.
+
+use crate::codec::{Codec, CodecKind};
+use crate::dispatcher::Addr;
+use crate::error::{RampError, Result};
+use crate::island::IslandEnv;
+use crate::message::Message;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use uuid::Uuid;
+
+/// Encoded agent carried by [`Message::Agent`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Migrant {
+    pub kind: String,
+    pub payload: Vec<u8>,
+}
+
+impl Migrant {
+    pub fn encode<A: Serialize>(codec: CodecKind, kind: &str, agent: &A) -> Result<Migrant> {
+        Ok(Migrant {
+            kind: String::from(kind),
+            payload: codec.encode(agent)?,
+        })
+    }
+
+    /// Fails unless the migrant is of the given kind.
+    pub fn decode<A: DeserializeOwned>(&self, codec: CodecKind, kind: &str) -> Result<A> {
+        if self.kind != kind {
+            return Err(RampError::UnexpectedAgent(self.kind.clone()));
+        }
+        codec.decode(&self.payload)
+    }
+}
+
+/// Where a migrating agent is sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationTarget {
+    /// Random island of this host.
+    RandomLocal,
+    /// Island of this host.
+    Local(Uuid),
+    /// Random island of a random other host.
+    RandomGlobal,
+    /// Random island of the given host.
+    Host(Addr),
+}
+
+/// Migrations of an island since it was created.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MigrationStats {
+    pub sent_local: u64,
+    pub sent_global: u64,
+    pub received: u64,
+    pub rejected: u64,
+}
+
+/// Environment of an island migrating agents of type `A`.
+/// Dereferences to the wrapped [`IslandEnv`].
+pub struct MigrationEnv<A> {
+    island_env: IslandEnv,
+    kind: &'static str,
+    stats: MigrationStats,
+    agent: PhantomData<fn() -> A>,
+}
+
+impl<A: Serialize + DeserializeOwned> MigrationEnv<A> {
+    /// Agents are sent and accepted as `kind`, e.g. `"sheep"`.
+    pub fn new(island_env: IslandEnv, kind: &'static str) -> Self {
+        MigrationEnv {
+            island_env,
+            kind,
+            stats: MigrationStats::default(),
+            agent: PhantomData,
+        }
+    }
+
+    /// Sends `agent` to `target`. The agent stays with the caller
+    /// when it cannot be sent, e.g. there are no other local islands.
+    pub fn migrate(&mut self, agent: &A, target: MigrationTarget) -> Result<()> {
+        let msg = Message::Agent(Migrant::encode(self.island_env.codec(), self.kind, agent)?);
+        match target {
+            MigrationTarget::RandomLocal => self
+                .island_env
+                .send_to_rnd_local(msg)
+                .map_err(|_| RampError::ChannelClosed)?,
+            MigrationTarget::Local(island_id) => self
+                .island_env
+                .send_to_local(island_id, msg)
+                .map_err(|_| RampError::IslandUnreachable(island_id))?,
+            MigrationTarget::RandomGlobal => {
+                self.island_env.send_to_rnd_global(msg);
+                self.stats.sent_global += 1;
+                return Ok(());
+            }
+            MigrationTarget::Host(addr) => {
+                self.island_env.send_to_global(addr, msg);
+                self.stats.sent_global += 1;
+                return Ok(());
+            }
+        }
+        self.stats.sent_local += 1;
+        Ok(())
+    }
+
+    /// Takes migrants out of `messages` received in a turn, leaving other
+    /// messages in place, and decodes them in the order they were received.
+    pub fn received_migrants(
+        &mut self,
+        messages: &mut Vec<Message>,
+    ) -> impl Iterator<Item = Result<A>> {
+        let codec = self.island_env.codec();
+        let mut migrants = Vec::new();
+        let mut rest = Vec::with_capacity(messages.len());
+        for msg in messages.drain(..) {
+            match msg {
+                Message::Agent(migrant) => {
+                    let agent = migrant.decode(codec, self.kind);
+                    match agent {
+                        Ok(_) => self.stats.received += 1,
+                        Err(_) => self.stats.rejected += 1,
+                    }
+                    migrants.push(agent);
+                }
+                msg => rest.push(msg),
+            }
+        }
+        *messages = rest;
+        migrants.into_iter()
+    }
+
+    pub fn stats(&self) -> MigrationStats {
+        self.stats
+    }
+
+    pub fn into_inner(self) -> IslandEnv {
+        self.island_env
+    }
+}
+
+impl<A> Deref for MigrationEnv<A> {
+    type Target = IslandEnv;
+
+    fn deref(&self) -> &IslandEnv {
+        &self.island_env
+    }
+}
+
+impl<A> DerefMut for MigrationEnv<A> {
+    fn deref_mut(&mut self) -> &mut IslandEnv {
+        &mut self.island_env
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Migrant;
+    use crate::codec::CodecKind;
+    use crate::error::RampError;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Sheep(u32);
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Wolf(u32);
+
+    #[test]
+    fn test_migrant_round_trip() {
+        for codec in [CodecKind::Bincode, CodecKind::MessagePack, CodecKind::Cbor].iter() {
+            let migrant = Migrant::encode(*codec, "sheep", &Sheep(7)).unwrap();
+            assert_eq!(migrant.decode::<Sheep>(*codec, "sheep").unwrap(), Sheep(7));
+        }
+    }
+
+    #[test]
+    fn test_unexpected_agent() {
+        let migrant = Migrant::encode(CodecKind::Bincode, "sheep", &Sheep(7)).unwrap();
+        let result = migrant.decode::<Wolf>(CodecKind::Bincode, "wolf");
+        assert!(matches!(result, Err(RampError::UnexpectedAgent(_))));
+    }
+}
@@ -1,5 +1,9 @@
-use crate::message::Message;
+use crate::codec::CodecKind;
+use crate::error::{RampError, Result};
+use crate::map::MapOwners;
+use crate::message::{Message, TurnNumber};
 use crate::settings::NetworkSettings;
+use config::ConfigError;
 use zmq::Socket;
 
 type Key = String;
@@ -8,70 +12,135 @@ type From = String;
 pub const COORD_INFO_KEY: &str = "COORD_INFO";
 pub const SERVER_INFO_KEY: &str = "SERVER_INFO";
 pub const BROADCAST_KEY: &str = "BROADCAST";
-
-pub fn connect_sock(sock: &Socket, ip: &str, port: u32) {
-    let address = &format!("tcp://{}:{}", ip.to_string(), port);
-    assert!(sock.connect(address).is_ok());
+/// Key under which hosts publish heartbeats for the server.
+pub const HEARTBEAT_KEY: &str = "HEARTBEAT";
+
+/// How long rendezvous loops wait for an answer before they resend
+/// a handshake message that could have been lost.
+pub const HANDSHAKE_RETRY_MS: i64 = 500;
+
+/// How often hosts and the server tell each other they are alive.
+pub const HEARTBEAT_INTERVAL_MS: u64 = 1000;
+/// How long a peer may stay silent before it is considered dead,
+/// unless configured otherwise.
+pub const DEFAULT_PEER_TIMEOUT_MS: u64 = 10_000;
+
+/// How long published messages, e.g. results of islands, are still sent
+/// once the simulation has finished.
+const PUB_LINGER_MS: i32 = 1000;
+
+pub fn connect_sock(sock: &Socket, ip: &str, port: u32) -> Result<()> {
+    let address = &format!("tcp://{}:{}", ip, port);
+    sock.connect(address)?;
+    Ok(())
 }
 
-pub fn bind_sock(sock: &Socket, ip: String, port: u32) {
-    println!("{}", ip);
+pub fn bind_sock(sock: &Socket, ip: String, port: u32) -> Result<()> {
     let endpoint = &format!("tcp://{}:{}", ip, port);
-    assert!(sock.bind(endpoint).is_ok());
+    sock.bind(endpoint)?;
+    Ok(())
 }
 
-pub fn subscribe_sock(sock: &Socket, key: String) {
-    sock.set_subscribe(key.as_bytes()).unwrap();
+pub fn subscribe_sock(sock: &Socket, key: String) -> Result<()> {
+    sock.set_subscribe(key.as_bytes())?;
+    Ok(())
 }
 
 /// Sends ['Message'] in REQ-REP pattern. First is
-/// sender identity and next is msg. Sender identity should
-/// be its ip address.
-pub fn send_rr(sock: &Socket, from: From, msg: Message) {
+/// sender identity and next is msg encoded by `codec`. Sender
+/// identity should be its ip address.
+pub fn send_rr(sock: &Socket, codec: CodecKind, from: From, msg: Message) -> Result<()> {
+    send_rr_sized(sock, codec, from, msg).map(|_| ())
+}
+
+/// Like ['send_rr'], returns the size of the encoded message in bytes.
+pub fn send_rr_sized(sock: &Socket, codec: CodecKind, from: From, msg: Message) -> Result<usize> {
     let s_from = from.into_bytes();
-    let s_msg = bincode::serialize(&msg).unwrap();
-    sock.send(s_from, zmq::SNDMORE).unwrap();
-    sock.send(s_msg, 0).unwrap();
+    let s_msg = codec.encode_frame(&msg)?;
+    let size = s_msg.len();
+    sock.send(s_from, zmq::SNDMORE)?;
+    sock.send(s_msg, 0)?;
+    Ok(size)
 }
 
 /// Sends ['Message'] in PUB-SUB pattern. First is key, next is
 /// sender identity and the last one is msg. Sender identity should
 /// be its ip address.
-pub fn send_ps(sock: &Socket, key: Key, from: From, msg: Message) {
+pub fn send_ps(sock: &Socket, codec: CodecKind, key: Key, from: From, msg: Message) -> Result<()> {
+    send_ps_sized(sock, codec, key, from, msg).map(|_| ())
+}
+
+/// Like ['send_ps'], returns the size of the encoded message in bytes.
+pub fn send_ps_sized(
+    sock: &Socket,
+    codec: CodecKind,
+    key: Key,
+    from: From,
+    msg: Message,
+) -> Result<usize> {
     let s_key = key.into_bytes();
     let s_from = from.into_bytes();
-    let s_msg = bincode::serialize(&msg).unwrap();
-    sock.send(s_key, zmq::SNDMORE).unwrap();
-    sock.send(s_from, zmq::SNDMORE).unwrap();
-    sock.send(s_msg, 0).unwrap();
+    let s_msg = codec.encode_frame(&msg)?;
+    let size = s_msg.len();
+    sock.send(s_key, zmq::SNDMORE)?;
+    sock.send(s_from, zmq::SNDMORE)?;
+    sock.send(s_msg, 0)?;
+    Ok(size)
 }
 
 /// Receives ['Message'] in REQ-REP pattern. Analogous to ['send_rr'].
-pub fn recv_rr(sock: &Socket) -> (From, Message) {
-    let from = sock.recv_string(0).unwrap().unwrap();
-    let msg = sock.recv_bytes(0).unwrap();
-    let d_msg: Message = bincode::deserialize(&msg).unwrap();
-    (from, d_msg)
+/// The whole multipart message is always consumed, so after an error
+/// the socket is ready for the next message.
+pub fn recv_rr(sock: &Socket, codec: CodecKind) -> Result<(From, Message)> {
+    let mut frames = sock.recv_multipart(0)?.into_iter();
+    match (frames.next(), frames.next(), frames.next()) {
+        (Some(from), Some(msg), None) => Ok((frame_to_string(from)?, codec.decode_frame(&msg)?)),
+        _ => Err(RampError::InvalidFrame(String::from(
+            "expected identity and message",
+        ))),
+    }
 }
 
 /// Receives ['Message'] in PUB-SUB pattern. Analogous to ['send_ps'].
-pub fn recv_ps(sock: &Socket) -> (Key, From, Message) {
-    let key = sock.recv_string(0).unwrap().unwrap();
-    let from = sock.recv_string(0).unwrap().unwrap();
-    let msg = sock.recv_bytes(0).unwrap();
-    let d_msg: Message = bincode::deserialize(&msg).unwrap();
-    (key, from, d_msg)
+pub fn recv_ps(sock: &Socket, codec: CodecKind) -> Result<(Key, From, Message)> {
+    recv_ps_sized(sock, codec).map(|(key, from, msg, _)| (key, from, msg))
+}
+
+/// Like ['recv_ps'], also returns the size of the encoded message in bytes.
+pub fn recv_ps_sized(sock: &Socket, codec: CodecKind) -> Result<(Key, From, Message, usize)> {
+    let mut frames = sock.recv_multipart(0)?.into_iter();
+    match (frames.next(), frames.next(), frames.next(), frames.next()) {
+        (Some(key), Some(from), Some(msg), None) => Ok((
+            frame_to_string(key)?,
+            frame_to_string(from)?,
+            codec.decode_frame(&msg)?,
+            msg.len(),
+        )),
+        _ => Err(RampError::InvalidFrame(String::from(
+            "expected key, identity and message",
+        ))),
+    }
+}
+
+/// Waits at most `timeout_ms` for a readable socket.
+pub fn poll_readable(sock: &Socket, timeout_ms: i64) -> Result<bool> {
+    let mut items = [sock.as_poll_item(zmq::POLLIN)];
+    zmq::poll(&mut items, timeout_ms)?;
+    Ok(items[0].is_readable())
 }
 
-//////////////////////////////////////////////////////////////////////////////
-////                          Network Context                             ////
-//////////////////////////////////////////////////////////////////////////////
+fn frame_to_string(frame: Vec<u8>) -> Result<String> {
+    String::from_utf8(frame).map_err(|_| RampError::InvalidFrame(String::from("non utf-8 header")))
+}
+
+// Network context
 
 pub type Ip = String;
 pub type Port = u32;
 
 pub struct NetworkCtx {
     pub private_key: String,
+    pub codec: CodecKind,
     pub settings: NetworkSettings,
     pub req_sock: Socket,
     pub rep_sock: Socket,
@@ -82,6 +151,7 @@ pub struct NetworkCtx {
 
 pub struct DispatcherNetworkCtx {
     pub nt_sett: NetworkSettings,
+    pub codec: CodecKind,
     pub ip_table: Vec<(Ip, Port)>,
     pub pub_sock: Socket,
     pub s_req_sock: Socket,
@@ -89,224 +159,390 @@ pub struct DispatcherNetworkCtx {
 
 pub struct CollectorNetworkCtx {
     pub nt_sett: NetworkSettings,
+    pub codec: CodecKind,
+    pub ip_table: Vec<(Ip, Port)>,
     pub sub_sock: Socket,
 }
 
 impl NetworkCtx {
-    pub fn new(settings: NetworkSettings) -> Self {
+    pub fn new(settings: NetworkSettings) -> Result<Self> {
         let private_key = Self::create_private_key(&settings);
+        let codec = settings.codec.unwrap_or_default();
         let context = zmq::Context::new();
-        let rep_sock = context.socket(zmq::REP).unwrap();
-        let req_sock = context.socket(zmq::REQ).unwrap();
-        let s_req_sock = context.socket(zmq::REQ).unwrap();
-        let pub_sock = context.socket(zmq::PUB).unwrap();
-        let sub_sock = context.socket(zmq::SUB).unwrap();
-        NetworkCtx {
+        let rep_sock = context.socket(zmq::REP)?;
+        let req_sock = context.socket(zmq::REQ)?;
+        let s_req_sock = context.socket(zmq::REQ)?;
+        let pub_sock = context.socket(zmq::PUB)?;
+        let sub_sock = context.socket(zmq::SUB)?;
+        // Messages queued for peers which are gone would keep the context
+        // from terminating when the simulation finishes
+        pub_sock.set_linger(PUB_LINGER_MS)?;
+        Ok(NetworkCtx {
             private_key,
+            codec,
             settings,
             req_sock,
             rep_sock,
             pub_sock,
             sub_sock,
             s_req_sock,
-        }
+        })
     }
 
-    pub fn init(self) -> (DispatcherNetworkCtx, CollectorNetworkCtx) {
+    pub fn init(self) -> Result<(DispatcherNetworkCtx, CollectorNetworkCtx)> {
         let host_ip = self.settings.host_ip.clone();
         let host_pub_port = self.settings.pub_port;
-        bind_sock(&self.pub_sock, host_ip.clone(), host_pub_port);
-        self.subscribe();
+        bind_sock(&self.pub_sock, host_ip, host_pub_port)?;
+        self.subscribe()?;
 
         let mut ip_table;
-        if self.settings.global_sync.sync {
+        if self.settings.join.unwrap_or(false) {
+            if !self.settings.global_sync.sync {
+                return Err(RampError::Settings(ConfigError::Message(String::from(
+                    "joining a running simulation requires global sync",
+                ))));
+            }
+            // Peers are known once the server admits this host
+            let server_ip = self.settings.global_sync.server_ip.clone();
+            connect_sock(
+                &self.s_req_sock,
+                &server_ip,
+                self.settings.global_sync.server_rep_port,
+            )?;
+            connect_sock(
+                &self.sub_sock,
+                &server_ip,
+                self.settings.global_sync.server_pub_port,
+            )?;
+            subscribe_sock(&self.sub_sock, String::from(SERVER_INFO_KEY))?;
+            ip_table = vec![];
+        } else if self.settings.global_sync.sync {
             let server_ip = self.settings.global_sync.server_ip.clone();
             let server_rep_port = self.settings.global_sync.server_rep_port;
             let server_pub_port = self.settings.global_sync.server_pub_port;
-            connect_sock(&self.s_req_sock, &server_ip, server_rep_port);
-            connect_sock(&self.sub_sock, &server_ip, server_pub_port);
-            subscribe_sock(&self.sub_sock, String::from(SERVER_INFO_KEY));
-            self.send_hello_msg(&self.s_req_sock);
-            ip_table = self.wait_for_ip_table();
-            self.connect(&ip_table);
+            connect_sock(&self.s_req_sock, &server_ip, server_rep_port)?;
+            connect_sock(&self.sub_sock, &server_ip, server_pub_port)?;
+            subscribe_sock(&self.sub_sock, String::from(SERVER_INFO_KEY))?;
+            self.send_hello_msg(&self.s_req_sock)?;
+            ip_table = self.wait_for_ip_table()?;
+            self.connect(&ip_table)?;
         } else if self.settings.is_coordinator {
             let coord_ip = self.settings.coordinator_ip.clone();
             let coord_rep_port = self.settings.coordinator_rep_port;
             let coord_pub_port = self.settings.coordinator_pub_port;
-            bind_sock(&self.rep_sock, coord_ip.clone(), coord_rep_port);
+            bind_sock(&self.rep_sock, coord_ip.clone(), coord_rep_port)?;
             ip_table = wait_for_hosts(
                 &self.rep_sock,
+                self.codec,
                 &self.private_key,
                 self.settings.hosts_num,
                 false,
-            );
-            self.connect(&ip_table);
+            )?;
+            self.connect(&ip_table)?;
             ip_table.push((coord_ip, coord_pub_port));
-            publish_ip_table(&self.pub_sock, &self.private_key, &ip_table);
+            publish_ip_table(&self.pub_sock, self.codec, &self.private_key, &ip_table)?;
             wait_for_confirmations(
                 &self.rep_sock,
+                &self.pub_sock,
+                self.codec,
                 &self.private_key,
                 self.settings.hosts_num,
                 false,
-            );
-            self.publish_start_sim();
+                &ip_table,
+            )?;
+            self.publish_start_sim()?;
         } else {
             let coord_ip = self.settings.coordinator_ip.clone();
             let coord_rep_port = self.settings.coordinator_rep_port;
             let coord_pub_port = self.settings.coordinator_pub_port;
-            connect_sock(&self.req_sock, &coord_ip, coord_rep_port);
-            connect_sock(&self.sub_sock, &coord_ip, coord_pub_port);
-            self.send_hello_msg(&self.req_sock);
-            ip_table = self.wait_for_ip_table();
-            self.connect(&ip_table);
-            self.send_ready_msg(&self.req_sock);
-            self.wait_for_signal();
+            connect_sock(&self.req_sock, &coord_ip, coord_rep_port)?;
+            connect_sock(&self.sub_sock, &coord_ip, coord_pub_port)?;
+            self.send_hello_msg(&self.req_sock)?;
+            ip_table = self.wait_for_ip_table()?;
+            self.connect(&ip_table)?;
+            self.send_ready_msg(&self.req_sock)?;
+            self.wait_for_signal()?;
         }
 
+        let coll_nt_ctx = CollectorNetworkCtx {
+            nt_sett: self.settings.clone(),
+            codec: self.codec,
+            ip_table: ip_table.clone(),
+            sub_sock: self.sub_sock,
+        };
+
         let dis_nt_ctx = DispatcherNetworkCtx {
             nt_sett: self.settings.clone(),
+            codec: self.codec,
             ip_table,
             pub_sock: self.pub_sock,
             s_req_sock: self.s_req_sock,
         };
 
-        let coll_nt_ctx = CollectorNetworkCtx {
-            nt_sett: self.settings.clone(),
-            sub_sock: self.sub_sock,
-        };
-
-        (dis_nt_ctx, coll_nt_ctx)
+        Ok((dis_nt_ctx, coll_nt_ctx))
     }
 
     fn create_private_key(settings: &NetworkSettings) -> String {
-        format!(
-            "{}:{}",
-            settings.host_ip.to_string(),
-            settings.pub_port.to_string()
-        )
+        host_key(&settings.host_ip, settings.pub_port)
     }
 
-    fn connect(&self, ip_table: &[(Ip, Port)]) {
+    fn connect(&self, ip_table: &[(Ip, Port)]) -> Result<()> {
         ip_table
             .iter()
-            .for_each(|(ip, port)| connect_sock(&self.sub_sock, ip, *port));
+            .try_for_each(|(ip, port)| connect_sock(&self.sub_sock, ip, *port))
     }
 
-    fn subscribe(&self) {
-        subscribe_sock(&self.sub_sock, self.private_key.clone());
-        subscribe_sock(&self.sub_sock, String::from(COORD_INFO_KEY));
-        subscribe_sock(&self.sub_sock, String::from(BROADCAST_KEY));
+    fn subscribe(&self) -> Result<()> {
+        subscribe_sock(&self.sub_sock, self.private_key.clone())?;
+        subscribe_sock(&self.sub_sock, String::from(COORD_INFO_KEY))?;
+        subscribe_sock(&self.sub_sock, String::from(BROADCAST_KEY))
     }
 
-    fn publish_start_sim(&self) {
+    fn publish_start_sim(&self) -> Result<()> {
         log::info!("Publishing start sim");
         let key = String::from(COORD_INFO_KEY);
-        let from = self.settings.host_ip.clone();
+        let from = host_key(&self.settings.host_ip, self.settings.pub_port);
         let msg = Message::StartSim;
 
-        send_ps(&self.pub_sock, key, from, msg);
+        send_ps(&self.pub_sock, self.codec, key, from, msg)
     }
 
-    fn send_ready_msg(&self, sock: &Socket) {
+    fn send_ready_msg(&self, sock: &Socket) -> Result<()> {
         log::info!("Sending host ready message");
-        let from = self.settings.host_ip.clone();
+        let from = host_key(&self.settings.host_ip, self.settings.pub_port);
         let msg = Message::HostReady;
 
-        send_rr(sock, from, msg);
-        let (_, msg) = recv_rr(sock);
+        send_rr(sock, self.codec, from, msg)?;
+        let (_, msg) = recv_rr(sock, self.codec)?;
         log::info!("{}", msg.as_string());
+        Ok(())
     }
 
-    fn send_hello_msg(&self, sock: &Socket) {
+    fn send_hello_msg(&self, sock: &Socket) -> Result<()> {
         log::info!("Sending hello message");
-        let from = self.settings.host_ip.clone();
+        let from = host_key(&self.settings.host_ip, self.settings.pub_port);
         let msg = Message::Hello(self.settings.host_ip.clone(), self.settings.pub_port);
 
-        send_rr(sock, from, msg);
-        let (_, msg) = recv_rr(sock);
+        send_rr(sock, self.codec, from, msg)?;
+        let (_, msg) = recv_rr(sock, self.codec)?;
         log::info!("{}", msg.as_string());
+        Ok(())
     }
 
-    fn wait_for_ip_table(&self) -> Vec<(Ip, Port)> {
+    fn wait_for_ip_table(&self) -> Result<Vec<(Ip, Port)>> {
         log::info!("Waiting for ip table");
-        let x;
         loop {
-            let (_, _, msg) = recv_ps(&self.sub_sock);
-            if let Message::IpTable(mut ip_table) = msg {
-                ip_table.retain(|(ip, port)| {
-                    *ip != self.settings.host_ip && *port != self.settings.pub_port
-                });
-                log::info!("Received Ip Table: {:#?}", ip_table);
-                x = ip_table;
-                break;
+            match recv_ps(&self.sub_sock, self.codec) {
+                Ok((_, _, Message::IpTable(ip_table))) => {
+                    let own_addr = (self.settings.host_ip.as_str(), self.settings.pub_port);
+                    let ip_table = peers_of(ip_table, own_addr);
+                    log::info!("Received Ip Table: {:#?}", ip_table);
+                    return Ok(ip_table);
+                }
+                Ok(_) => continue,
+                Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+                Err(e) => log::warn!("Dropping message while waiting for ip table: {}", e),
             }
         }
-        x
     }
 
-    fn wait_for_signal(&self) {
+    fn wait_for_signal(&self) -> Result<()> {
         log::info!("Waiting for signal to start sim");
-        let (_, _, msg) = recv_ps(&self.sub_sock);
-        log::info!("{}", msg.as_string());
+        loop {
+            match recv_ps(&self.sub_sock, self.codec) {
+                Ok((_, _, Message::StartSim)) => {
+                    log::info!("{}", Message::StartSim.as_string());
+                    return Ok(());
+                }
+                Ok(_) => continue,
+                Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+                Err(e) => log::warn!("Dropping message while waiting for signal: {}", e),
+            }
+        }
     }
 }
 
-pub fn publish_ip_table(pub_sock: &Socket, identity: &str, ip_table: &[(Ip, Port)]) {
+pub fn publish_ip_table(
+    pub_sock: &Socket,
+    codec: CodecKind,
+    identity: &str,
+    ip_table: &[(Ip, Port)],
+) -> Result<()> {
     log::info!("Publishing ip table");
     let key = String::from(COORD_INFO_KEY);
     let from = identity.to_string();
     let msg = Message::IpTable(ip_table.to_owned());
 
-    send_ps(pub_sock, key, from, msg);
+    send_ps(pub_sock, codec, key, from, msg)
 }
 
 pub fn wait_for_hosts(
     rep_sock: &Socket,
+    codec: CodecKind,
     identity: &str,
     hosts: u32,
     is_server: bool,
-) -> Vec<(Ip, Port)> {
+) -> Result<Vec<(Ip, Port)>> {
     let mut host_count = hosts;
     if !is_server {
         host_count -= 1;
     }
     let mut ip_table = vec![];
     while ip_table.len() != host_count as usize {
-        let (from, msg) = recv_rr(&rep_sock);
-        log::info!("{} {}", msg.as_string(), from);
-        match msg {
-            Message::Hello(ip, port) => {
+        match recv_rr(rep_sock, codec) {
+            Ok((from, Message::Hello(ip, port))) => {
+                log::info!("{} {}", Message::Hello(ip.clone(), port).as_string(), from);
                 ip_table.push((ip, port));
-                send_rr(&rep_sock, identity.to_string(), Message::Ok);
+                send_rr(rep_sock, codec, identity.to_string(), Message::Ok)?;
+            }
+            Ok((from, msg)) => {
+                log::warn!(
+                    "Unexpected msg while waiting for hosts {} {}",
+                    msg.as_string(),
+                    from
+                );
+                send_rr(rep_sock, codec, identity.to_string(), Message::Err)?;
             }
-            _ => {
-                log::warn!("Unexpected msg while waiting for hosts");
-                send_rr(&rep_sock, identity.to_string(), Message::Err);
+            Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+            Err(e) => {
+                log::warn!("Dropping malformed msg while waiting for hosts: {}", e);
+                send_rr(rep_sock, codec, identity.to_string(), Message::Err)?;
             }
         }
     }
-    ip_table
+    Ok(ip_table)
+}
+
+/// What hosts told while confirming they are ready.
+#[derive(Debug, Default)]
+pub struct Confirmations {
+    /// Turn restored hosts resume from.
+    pub resume_turn: Option<TurnNumber>,
+    /// Map owners, sent to the server by the coordinator.
+    pub owners: Option<MapOwners>,
 }
 
-pub fn wait_for_confirmations(rep_sock: &Socket, identity: &str, hosts: u32, is_server: bool) {
+/// Waits for `HostReady` or `Resume` from every host. Ip table is published
+/// again whenever nothing arrives for a while, because hosts which subscribed
+/// late could have missed it.
+pub fn wait_for_confirmations(
+    rep_sock: &Socket,
+    pub_sock: &Socket,
+    codec: CodecKind,
+    identity: &str,
+    hosts: u32,
+    is_server: bool,
+    ip_table: &[(Ip, Port)],
+) -> Result<Confirmations> {
     log::info!("Waiting for confirmations");
     let mut host_count = hosts;
     if !is_server {
         host_count -= 1;
     }
     let mut count = 0;
+    let mut confirmations = Confirmations::default();
     while count != host_count {
-        let (from, msg) = recv_rr(rep_sock);
-        match msg {
-            Message::HostReady => {
+        if !poll_readable(rep_sock, HANDSHAKE_RETRY_MS)? {
+            publish_ip_table(pub_sock, codec, identity, ip_table)?;
+            continue;
+        }
+        match recv_rr(rep_sock, codec) {
+            Ok((from, Message::HostReady)) => {
+                count += 1;
+                log::info!("{} {}", Message::HostReady.as_string(), from);
+                send_rr(rep_sock, codec, identity.to_string(), Message::Ok)?;
+            }
+            Ok((from, Message::Resume(turn))) => {
                 count += 1;
-                log::info!("{} {}", msg.as_string(), from);
-                send_rr(rep_sock, identity.to_string(), Message::Ok);
+                log::info!("{} {}", Message::Resume(turn).as_string(), from);
+                let resume_turn = &mut confirmations.resume_turn;
+                if resume_turn.is_some_and(|resume_turn| resume_turn != turn) {
+                    log::warn!("Host {} resumes from a different turn {}", from, turn);
+                }
+                *resume_turn = resume_turn.or(Some(turn));
+                send_rr(rep_sock, codec, identity.to_string(), Message::Ok)?;
             }
-            _ => {
+            Ok((_, Message::Owners(owners))) if is_server => {
+                confirmations.owners = Some(owners);
+                send_rr(rep_sock, codec, identity.to_string(), Message::Ok)?;
+            }
+            Ok(_) => {
                 log::warn!("Unexpected msg while waiting for confirmations");
-                send_rr(&rep_sock, identity.to_string(), Message::Err);
+                send_rr(rep_sock, codec, identity.to_string(), Message::Err)?;
+            }
+            Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+            Err(e) => {
+                log::warn!(
+                    "Dropping malformed msg while waiting for confirmations: {}",
+                    e
+                );
+                send_rr(rep_sock, codec, identity.to_string(), Message::Err)?;
             }
         }
     }
+    Ok(confirmations)
+}
+
+/// Connects `sub_sock` to hosts which appeared in `ip_table` and disconnects
+/// it from those which are gone. `peers` becomes `ip_table` without `own_addr`.
+pub fn update_peers(
+    sub_sock: &Socket,
+    peers: &mut Vec<(Ip, Port)>,
+    ip_table: Vec<(Ip, Port)>,
+    own_addr: (&str, Port),
+) -> Result<()> {
+    let ip_table = peers_of(ip_table, own_addr);
+    for (ip, port) in ip_table.iter().filter(|&addr| !peers.contains(addr)) {
+        connect_sock(sub_sock, ip, *port)?;
+    }
+    for (ip, port) in peers.iter().filter(|&addr| !ip_table.contains(addr)) {
+        sub_sock.disconnect(&format!("tcp://{}:{}", ip, port))?;
+    }
+    *peers = ip_table;
+    Ok(())
+}
+
+/// Key of a host, `ip:port` of its pub socket. Unicast messages are
+/// published under it and the host identifies itself with it to the server.
+pub fn host_key(ip: &str, port: Port) -> String {
+    format!("{}:{}", ip, port)
+}
+
+/// Address of a host from its key, see [`host_key`].
+pub fn parse_host_key(key: &str) -> Option<(Ip, Port)> {
+    let (ip, port) = key.rsplit_once(':')?;
+    Some((ip.to_string(), port.parse().ok()?))
+}
+
+/// Hosts of `ip_table` other than this one. Hosts may share an ip.
+fn peers_of(mut ip_table: Vec<(Ip, Port)>, own_addr: (&str, Port)) -> Vec<(Ip, Port)> {
+    ip_table.retain(|(ip, port)| !(ip == own_addr.0 && *port == own_addr.1));
+    ip_table
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_peers_on_the_same_ip_are_kept() {
+        let host = |ip: &str, port| (String::from(ip), port);
+        let ip_table = vec![
+            host("10.0.0.1", 5000),
+            host("10.0.0.1", 5001),
+            host("10.0.0.2", 5000),
+        ];
+        let peers = super::peers_of(ip_table, ("10.0.0.1", 5000));
+
+        assert_eq!(peers, [host("10.0.0.1", 5001), host("10.0.0.2", 5000)]);
+    }
+
+    #[test]
+    fn test_host_key_round_trip() {
+        let key = super::host_key("10.0.0.1", 5001);
+
+        assert_eq!(
+            super::parse_host_key(&key),
+            Some((String::from("10.0.0.1"), 5001))
+        );
+        assert_eq!(super::parse_host_key("10.0.0.1"), None);
+    }
 }
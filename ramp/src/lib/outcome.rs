@@ -0,0 +1,142 @@
+//! Outcome of a simulation reduced from final results of islands.
+//!
+//! Islands may return a result from [`crate::island::Island::result`], e.g.
+//! their best fitness encoded with
+//! [`crate::island::IslandEnv::encode_result`]. When islands are finished
+//! every host sends their results to the coordinator, which waits for the
+//! hosts still taking part in the simulation, decodes the results with the
+//! codec of the simulation and passes them to the [`Reducer`] given to
+//! [`crate::simulation::SimulationBuilder::reducer`]. The outcome is printed
+//! and, with a `[results]` section in settings, written to
+//! `<dir>/outcome.txt`.
+
+use crate::codec::{Codec, CodecKind};
+use crate::error::Result;
+use crate::settings::ResultsSettings;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+const OUTCOME_FILE: &str = "outcome.txt";
+
+/// Value returned by an island from [`crate::island::Island::result`],
+/// encoded until it reaches the [`Reducer`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct IslandResult<T = Vec<u8>> {
+    /// Host the island finished on, as `ip:port`.
+    pub host: String,
+    pub island_id: Uuid,
+    pub value: T,
+}
+
+/// Produces the outcome of the simulation, e.g. the best fitness of all islands.
+pub trait Reducer: Send {
+    /// Type of the results returned by islands.
+    type Value: DeserializeOwned;
+
+    /// Results are sorted by host and island, so the same results are
+    /// always reduced in the same order. Results which cannot be decoded
+    /// are left out.
+    fn reduce(&self, results: &[IslandResult<Self::Value>]) -> String;
+}
+
+/// [`Reducer`] of encoded results, so the simulation can keep reducers of
+/// any value type.
+pub(crate) trait EncodedReducer: Send {
+    fn reduce_encoded(&self, codec: CodecKind, results: Vec<IslandResult>) -> String;
+}
+
+impl<R: Reducer> EncodedReducer for R {
+    fn reduce_encoded(&self, codec: CodecKind, results: Vec<IslandResult>) -> String {
+        let results: Vec<IslandResult<R::Value>> = results
+            .into_iter()
+            .filter_map(|result| match codec.decode(&result.value) {
+                Ok(value) => Some(IslandResult {
+                    host: result.host,
+                    island_id: result.island_id,
+                    value,
+                }),
+                Err(e) => {
+                    log::warn!("Cannot decode result of island {}: {}", result.island_id, e);
+                    None
+                }
+            })
+            .collect();
+        self.reduce(&results)
+    }
+}
+
+/// Reduces the results, prints the outcome and writes it to the results
+/// directory if there is one.
+pub(crate) fn finish(
+    reducer: &dyn EncodedReducer,
+    codec: CodecKind,
+    mut results: Vec<IslandResult>,
+    settings: Option<&ResultsSettings>,
+) -> Result<String> {
+    results.sort_by(|a, b| (&a.host, a.island_id).cmp(&(&b.host, b.island_id)));
+    let outcome = reducer.reduce_encoded(codec, results);
+    println!("Outcome of the simulation: {}", outcome);
+    if let Some(settings) = settings {
+        let dir = Path::new(&settings.dir);
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(OUTCOME_FILE), format!("{}\n", outcome))?;
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IslandResult, Reducer};
+    use crate::codec::{Codec, CodecKind};
+    use crate::settings::ResultsSettings;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    struct Hosts;
+
+    impl Reducer for Hosts {
+        type Value = u32;
+
+        fn reduce(&self, results: &[IslandResult<u32>]) -> String {
+            let hosts: Vec<String> = results
+                .iter()
+                .map(|result| format!("{}={}", result.host, result.value))
+                .collect();
+            hosts.join(",")
+        }
+    }
+
+    fn result(host: &str, value: u32) -> IslandResult {
+        IslandResult {
+            host: host.to_owned(),
+            island_id: Uuid::new_v4(),
+            value: CodecKind::Bincode.encode(&value).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_outcome_is_written_to_results_dir() {
+        let dir = env::temp_dir().join(format!("ramp-outcome-{}", Uuid::new_v4()));
+        let settings = ResultsSettings {
+            dir: dir.to_string_lossy().into_owned(),
+            merged: None,
+        };
+        let mut garbage = result("c:1", 0);
+        garbage.value.clear();
+        let results = vec![
+            result("b:1", 3),
+            result("a:2", 2),
+            result("a:1", 1),
+            garbage,
+        ];
+        let outcome = super::finish(&Hosts, CodecKind::Bincode, results, Some(&settings)).unwrap();
+
+        // Results which cannot be decoded are left out
+        assert_eq!(outcome, "a:1=1,a:2=2,b:1=3");
+        let written = fs::read_to_string(dir.join("outcome.txt")).unwrap();
+        assert_eq!(written, "a:1=1,a:2=2,b:1=3\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
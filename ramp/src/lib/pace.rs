@@ -0,0 +1,165 @@
+//! Asynchronous turns with bounded staleness.
+//!
+//! Without global sync islands run their turns independently. With
+//! [`crate::settings::ClientSettings::staleness`] set to `K` an island starts
+//! turn `t` only once every other island of the simulation has finished turn
+//! `t - 1 - K`, so it is never more than `K` turns ahead of the slowest one.
+//! Islands tell each other about finished turns with [`Message::Progress`].
+//!
+//! Messages are stamped with the turn they were sent in and handed to the
+//! receiving island in its next turn after that one. Messages of an island
+//! which is ahead wait until the receiver catches up.
+
+use crate::address_book::{AddressBook, TurnClock};
+use crate::map::Map;
+use crate::message::{Message, TurnNumber};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use uuid::Uuid;
+
+pub struct Pace {
+    island_id: Uuid,
+    staleness: u32,
+    /// Turn of the island, stamped on messages it sends.
+    clock: TurnClock,
+    /// Islands of the simulation are the owners of map fragments.
+    map: Map,
+    /// Tells other islands about finished turns.
+    address_book: AddressBook,
+    /// Last turn finished by other islands, `first_turn - 1` if unknown.
+    done: HashMap<Uuid, i64>,
+    first_turn: TurnNumber,
+    /// Received messages with the turn they were sent in.
+    held: Vec<(Option<TurnNumber>, Message)>,
+}
+
+impl Pace {
+    pub fn new(
+        island_id: Uuid,
+        staleness: u32,
+        clock: TurnClock,
+        map: Map,
+        address_book: AddressBook,
+        first_turn: TurnNumber,
+    ) -> Self {
+        Pace {
+            island_id,
+            staleness,
+            clock,
+            map,
+            address_book,
+            done: HashMap::new(),
+            first_turn,
+            held: vec![],
+        }
+    }
+
+    /// Blocks until the island may start `turn` and returns messages for it.
+    /// Returns `None` if the simulation is finished in the meantime.
+    pub fn wait_for_turn(
+        &mut self,
+        rx: &Receiver<Message>,
+        turn: TurnNumber,
+    ) -> Option<Vec<Message>> {
+        for msg in rx.try_iter() {
+            if !self.receive(msg) {
+                return None;
+            }
+        }
+        while !self.may_start(turn) {
+            // Disconnected channel means that the collector is gone
+            let received = rx.recv().map(|msg| self.receive(msg));
+            if received != Ok(true) {
+                return None;
+            }
+        }
+        self.clock.store(turn, Ordering::SeqCst);
+
+        let (ready, held) = self
+            .held
+            .drain(..)
+            .partition(|(sent, _)| sent.is_none_or(|sent| sent < turn));
+        self.held = held;
+        Some(ready.into_iter().map(|(_, msg)| msg).collect())
+    }
+
+    /// Tells other islands, on this host and others, the turn is finished.
+    pub fn finish_turn(&mut self, turn: TurnNumber) {
+        let progress = Message::Progress(self.island_id, turn);
+        if self
+            .address_book
+            .send_to_all_local(progress.clone())
+            .is_err()
+        {
+            log::debug!("No other islands on this host");
+        }
+        self.address_book.send_to_all_global(progress);
+    }
+
+    /// Returns `false` when the simulation is finished.
+    fn receive(&mut self, msg: Message) -> bool {
+        match msg {
+            Message::Progress(island_id, turn) => {
+                let done = self.done.entry(island_id).or_insert(i64::from(turn));
+                *done = (*done).max(i64::from(turn));
+            }
+            Message::Stamped(turn, msg) => self.held.push((Some(turn), *msg)),
+            Message::FinSim => return false,
+            msg => self.held.push((None, msg)),
+        }
+        true
+    }
+
+    fn may_start(&self, turn: TurnNumber) -> bool {
+        let needed = i64::from(turn) - 1 - i64::from(self.staleness);
+        let unknown = i64::from(self.first_turn) - 1;
+        self.map
+            .owners()
+            .values()
+            .filter(|(_, _, island_id)| *island_id != self.island_id)
+            .all(|(_, _, island_id)| self.done.get(island_id).copied().unwrap_or(unknown) >= needed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pace;
+    use crate::address_book::{self, AddressBook};
+    use crate::map::{Map, MapOwners};
+    use crate::message::Message;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_island_waits_for_slowest_peer() {
+        let (island_id, peer_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut owners = MapOwners::new();
+        owners.insert(0..4, (String::from("a"), 1, island_id));
+        owners.insert(4..8, (String::from("a"), 1, peer_id));
+        let (dispatcher_tx, _dispatcher_rx) = mpsc::channel();
+        let islands = address_book::local_islands(&[], vec![]);
+        let address_book = AddressBook::new(dispatcher_tx, islands, None, 0);
+        let clock = Arc::default();
+        let map = Map::new(2, owners);
+        let mut pace = Pace::new(island_id, 1, Arc::clone(&clock), map, address_book, 0);
+
+        let (tx, rx) = mpsc::channel();
+        let len = |msgs: Option<Vec<Message>>| msgs.map(|msgs| msgs.len());
+        // Turns 0 and 1 need nothing from the peer
+        assert_eq!(len(pace.wait_for_turn(&rx, 0)), Some(0));
+        assert_eq!(len(pace.wait_for_turn(&rx, 1)), Some(0));
+        tx.send(Message::Stamped(1, Box::new(Message::Ok))).unwrap();
+        tx.send(Message::Stamped(2, Box::new(Message::Ok))).unwrap();
+        tx.send(Message::Progress(peer_id, 0)).unwrap();
+        // The message sent in turn 2 is held until turn 3
+        assert_eq!(len(pace.wait_for_turn(&rx, 2)), Some(1));
+        assert_eq!(clock.load(Ordering::SeqCst), 2);
+
+        // Turn 3 waits for the peer to finish turn 1
+        tx.send(Message::FinSim).unwrap();
+        assert!(pace.wait_for_turn(&rx, 3).is_none());
+    }
+}
@@ -0,0 +1,242 @@
+//! Layouts of map cells.
+//!
+//! Fragments of the map are ranges of offsets. A [`Partitioner`] places
+//! cells along offsets, which decides the shape of fragments:
+//! * [`Strips`] - rows of the map one after another, so fragments are
+//!   horizontal strips,
+//! * [`Blocks`] - square blocks in a grid with a fixed number of columns,
+//! * [`Hilbert`] - a Hilbert curve over a square map, so fragments are
+//!   compact areas with short borders.
+
+use crate::map::Fragment;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+pub trait Partitioner: fmt::Debug + Send + Sync {
+    /// Offset of cell `(x, y)`, `None` for cells outside the map.
+    fn offset(&self, x: i64, y: i64) -> Option<u64>;
+
+    /// Cell at `offset`, `None` for offsets outside the map.
+    fn cell(&self, offset: u64) -> Option<(i64, i64)>;
+
+    /// Number of cells of the map, `None` if it grows with fragments.
+    fn capacity(&self) -> Option<u64> {
+        None
+    }
+
+    /// Smallest box containing all cells of the fragment.
+    fn bounds(&self, fragment: &Fragment) -> Option<BoundingBox> {
+        fragment
+            .clone()
+            .filter_map(|offset| self.cell(offset))
+            .fold(None, |bounds: Option<BoundingBox>, (x, y)| {
+                Some(match bounds {
+                    Some(bounds) => BoundingBox {
+                        x: bounds.x.start.min(x)..bounds.x.end.max(x + 1),
+                        y: bounds.y.start.min(y)..bounds.y.end.max(y + 1),
+                    },
+                    None => BoundingBox {
+                        x: x..x + 1,
+                        y: y..y + 1,
+                    },
+                })
+            })
+    }
+}
+
+/// Cells `x` and `y` of a fragment are within the ranges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundingBox {
+    pub x: Range<i64>,
+    pub y: Range<i64>,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, x: i64, y: i64) -> bool {
+        self.x.contains(&x) && self.y.contains(&y)
+    }
+}
+
+/// Rows of `width` cells.
+#[derive(Debug, Clone, Copy)]
+pub struct Strips {
+    pub width: i64,
+}
+
+impl Partitioner for Strips {
+    fn offset(&self, x: i64, y: i64) -> Option<u64> {
+        if x < 0 || x >= self.width || y < 0 {
+            return None;
+        }
+        Some((y * self.width + x) as u64)
+    }
+
+    fn cell(&self, offset: u64) -> Option<(i64, i64)> {
+        let offset = offset as i64;
+        Some((offset % self.width, offset / self.width))
+    }
+}
+
+/// Square blocks of `block_len` cells, `columns` blocks in a row.
+/// Cells of a block are stored row by row.
+#[derive(Debug, Clone, Copy)]
+pub struct Blocks {
+    pub block_len: i64,
+    pub columns: i64,
+}
+
+impl Partitioner for Blocks {
+    fn offset(&self, x: i64, y: i64) -> Option<u64> {
+        if x < 0 || x >= self.block_len * self.columns || y < 0 {
+            return None;
+        }
+        let block = (y / self.block_len) * self.columns + x / self.block_len;
+        let cell = (y % self.block_len) * self.block_len + x % self.block_len;
+        Some((block * self.block_len * self.block_len + cell) as u64)
+    }
+
+    fn cell(&self, offset: u64) -> Option<(i64, i64)> {
+        let area = self.block_len * self.block_len;
+        let (block, cell) = (offset as i64 / area, offset as i64 % area);
+        let x = (block % self.columns) * self.block_len + cell % self.block_len;
+        let y = (block / self.columns) * self.block_len + cell / self.block_len;
+        Some((x, y))
+    }
+}
+
+/// Hilbert curve over a square map with `side` cells, a power of two.
+#[derive(Debug, Clone, Copy)]
+pub struct Hilbert {
+    pub side: i64,
+}
+
+impl Hilbert {
+    /// Smallest square map with at least `width` cells in a row.
+    pub fn covering(width: i64) -> Self {
+        Hilbert {
+            side: (width.max(1) as u64).next_power_of_two() as i64,
+        }
+    }
+
+    fn rotate(side: i64, x: &mut i64, y: &mut i64, rx: i64, ry: i64) {
+        if ry == 0 {
+            if rx == 1 {
+                *x = side - 1 - *x;
+                *y = side - 1 - *y;
+            }
+            std::mem::swap(x, y);
+        }
+    }
+}
+
+impl Partitioner for Hilbert {
+    fn offset(&self, mut x: i64, mut y: i64) -> Option<u64> {
+        if x < 0 || x >= self.side || y < 0 || y >= self.side {
+            return None;
+        }
+        let mut offset = 0;
+        let mut s = self.side / 2;
+        while s > 0 {
+            let rx = i64::from(x & s > 0);
+            let ry = i64::from(y & s > 0);
+            offset += s * s * ((3 * rx) ^ ry);
+            Self::rotate(self.side, &mut x, &mut y, rx, ry);
+            s /= 2;
+        }
+        Some(offset as u64)
+    }
+
+    fn cell(&self, offset: u64) -> Option<(i64, i64)> {
+        if offset >= self.capacity()? {
+            return None;
+        }
+        let (mut x, mut y) = (0, 0);
+        let mut t = offset as i64;
+        let mut s = 1;
+        while s < self.side {
+            let rx = 1 & (t / 2);
+            let ry = 1 & (t ^ rx);
+            Self::rotate(s, &mut x, &mut y, rx, ry);
+            x += s * rx;
+            y += s * ry;
+            t /= 4;
+            s *= 2;
+        }
+        Some((x, y))
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        Some((self.side * self.side) as u64)
+    }
+}
+
+/// Partitioner selected in settings, strips when missing.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionerKind {
+    #[default]
+    Strips,
+    Blocks,
+    Hilbert,
+}
+
+impl PartitionerKind {
+    /// Fragments hold `chunk_len * chunk_len` cells. Blocks and the Hilbert
+    /// curve place `columns` fragments in a row, strips are `chunk_len` wide.
+    pub fn build(self, chunk_len: i64, columns: i64) -> Arc<dyn Partitioner> {
+        match self {
+            PartitionerKind::Strips => Arc::new(Strips { width: chunk_len }),
+            PartitionerKind::Blocks => Arc::new(Blocks {
+                block_len: chunk_len,
+                columns,
+            }),
+            PartitionerKind::Hilbert => Arc::new(Hilbert::covering(chunk_len * columns)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BoundingBox, PartitionerKind};
+
+    #[test]
+    fn test_offsets_round_trip() {
+        let kinds = [
+            PartitionerKind::Strips,
+            PartitionerKind::Blocks,
+            PartitionerKind::Hilbert,
+        ];
+        for kind in kinds.iter() {
+            let partitioner = kind.build(4, 2);
+            for offset in 0..64 {
+                let (x, y) = partitioner.cell(offset).unwrap();
+                assert_eq!(partitioner.offset(x, y), Some(offset), "{:?}", kind);
+            }
+            assert_eq!(partitioner.offset(-1, 0), None);
+        }
+    }
+
+    #[test]
+    fn test_fragment_bounds() {
+        let strips = PartitionerKind::Strips.build(4, 2);
+        let blocks = PartitionerKind::Blocks.build(4, 2);
+        let hilbert = PartitionerKind::Hilbert.build(4, 2);
+
+        let bounds = |x, y| Some(BoundingBox { x, y });
+        assert_eq!(strips.bounds(&(16..32)), bounds(0..4, 4..8));
+        assert_eq!(blocks.bounds(&(16..32)), bounds(4..8, 0..4));
+        // Aligned segments of the Hilbert curve are squares
+        for fragment in 0..4 {
+            let bounds = hilbert
+                .bounds(&(fragment * 16..(fragment + 1) * 16))
+                .unwrap();
+            assert_eq!(
+                (bounds.x.end - bounds.x.start, bounds.y.end - bounds.y.start),
+                (4, 4)
+            );
+        }
+        assert_eq!(hilbert.cell(64), None);
+        assert_eq!(hilbert.capacity(), Some(64));
+    }
+}
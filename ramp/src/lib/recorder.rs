@@ -0,0 +1,211 @@
+//! Results of islands recorded per turn.
+//!
+//! With a `[results]` section in settings every island gets a [`Recorder`]
+//! in its [`crate::island::IslandEnv`] and records named values, e.g. the
+//! best fitness or the number of sheep, in its turns. Rows are written as
+//! `host,island_id,turn,name,value` to `<dir>/<host>/<island_id>.csv`, or to
+//! `<dir>/<host>.csv` for all islands of the host if results are `merged`.
+//! Files of islands coming back to a host or resumed from checkpoints are
+//! appended to.
+
+use crate::error::Result;
+use crate::message::TurnNumber;
+use crate::settings::ResultsSettings;
+use csv::{Writer, WriterBuilder};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use uuid::Uuid;
+
+const RESULTS_EXT: &str = "csv";
+
+#[derive(Debug, Serialize)]
+struct Row<'a> {
+    host: &'a str,
+    island_id: Uuid,
+    turn: TurnNumber,
+    name: &'a str,
+    value: f64,
+}
+
+enum Sink {
+    Island(Box<Writer<File>>),
+    Host(Arc<Mutex<Writer<File>>>),
+}
+
+/// Records values of an island. Values are dropped if results are off.
+pub struct Recorder {
+    host: String,
+    island_id: Uuid,
+    sink: Option<Sink>,
+}
+
+impl Recorder {
+    pub fn disabled() -> Self {
+        Recorder {
+            host: String::new(),
+            island_id: Uuid::nil(),
+            sink: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// A value which cannot be written is logged and dropped, it is
+    /// not a reason to stop the simulation.
+    pub fn record(&mut self, turn: TurnNumber, name: &str, value: f64) {
+        let row = Row {
+            host: &self.host,
+            island_id: self.island_id,
+            turn,
+            name,
+            value,
+        };
+        let result = match self.sink.as_mut() {
+            Some(Sink::Island(writer)) => writer.serialize(row),
+            Some(Sink::Host(writer)) => writer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .serialize(row),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            log::warn!("Cannot record {} of island {}: {}", name, self.island_id, e);
+        }
+    }
+
+    /// Writes buffered rows, which otherwise happens when the island is dropped.
+    pub fn flush(&mut self) {
+        let result = match self.sink.as_mut() {
+            Some(Sink::Island(writer)) => writer.flush(),
+            Some(Sink::Host(writer)) => writer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .flush(),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            log::warn!("Cannot write results of island {}: {}", self.island_id, e);
+        }
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
+/// Creates recorders of islands of a host.
+#[derive(Clone)]
+pub struct Results {
+    dir: PathBuf,
+    host: String,
+    /// File shared by all islands of the host if results are merged.
+    merged: Option<Arc<Mutex<Writer<File>>>>,
+}
+
+impl Results {
+    pub fn new(settings: &ResultsSettings, host: String) -> Result<Self> {
+        let dir = PathBuf::from(&settings.dir);
+        let file_name = host.replace(':', "_");
+        let merged = if settings.merged.unwrap_or(false) {
+            let file = dir.join(format!("{}.{}", file_name, RESULTS_EXT));
+            Some(Arc::new(Mutex::new(open(&file)?)))
+        } else {
+            None
+        };
+        Ok(Results {
+            dir: dir.join(file_name),
+            host,
+            merged,
+        })
+    }
+
+    pub fn recorder(&self, island_id: Uuid) -> Result<Recorder> {
+        let sink = match &self.merged {
+            Some(writer) => Sink::Host(Arc::clone(writer)),
+            None => {
+                let file = self.dir.join(format!("{}.{}", island_id, RESULTS_EXT));
+                Sink::Island(Box::new(open(&file)?))
+            }
+        };
+        Ok(Recorder {
+            host: self.host.clone(),
+            island_id,
+            sink: Some(sink),
+        })
+    }
+}
+
+/// Opens the file for appending, the header is written only to new files.
+fn open(path: &Path) -> Result<Writer<File>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let is_new = !path.exists();
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(WriterBuilder::new().has_headers(is_new).from_writer(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Results;
+    use crate::settings::ResultsSettings;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_island_results_are_appended() {
+        let dir = env::temp_dir().join(format!("ramp-results-{}", Uuid::new_v4()));
+        let settings = ResultsSettings {
+            dir: dir.to_string_lossy().into_owned(),
+            merged: None,
+        };
+        let results = Results::new(&settings, String::from("10.0.0.1:5000")).unwrap();
+        let island_id = Uuid::new_v4();
+        let mut recorder = results.recorder(island_id).unwrap();
+        recorder.record(1, "sheep", 10.0);
+        drop(recorder);
+        let mut recorder = results.recorder(island_id).unwrap();
+        recorder.record(2, "sheep", 12.5);
+        recorder.flush();
+
+        let file = dir.join("10.0.0.1_5000").join(format!("{}.csv", island_id));
+        let expected = format!(
+            "host,island_id,turn,name,value\n\
+             10.0.0.1:5000,{id},1,sheep,10.0\n\
+             10.0.0.1:5000,{id},2,sheep,12.5\n",
+            id = island_id
+        );
+        assert_eq!(fs::read_to_string(file).unwrap(), expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_merged_results_share_host_file() {
+        let dir = env::temp_dir().join(format!("ramp-merged-{}", Uuid::new_v4()));
+        let settings = ResultsSettings {
+            dir: dir.to_string_lossy().into_owned(),
+            merged: Some(true),
+        };
+        let results = Results::new(&settings, String::from("10.0.0.1:5000")).unwrap();
+        let islands: Vec<Uuid> = (0..2).map(|_| Uuid::new_v4()).collect();
+        for island_id in &islands {
+            let mut recorder = results.recorder(*island_id).unwrap();
+            recorder.record(0, "fitness", 1.0);
+            recorder.flush();
+        }
+
+        let content = fs::read_to_string(dir.join("10.0.0.1_5000.csv")).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "host,island_id,turn,name,value");
+        for (line, island_id) in lines[1..].iter().zip(&islands) {
+            assert!(line.contains(&island_id.to_string()));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+//! Seeded random number generators.
+//!
+//! All randomness of a simulation comes from the master seed in
+//! [`crate::settings::ClientSettings`]. Every host derives its own seed from
+//! the master seed and its address, and every island derives independent
+//! streams from the host seed and its number. With islands synchronized,
+//! a single-host run with the same seed is replayed exactly.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use uuid::{Builder, Uuid, Variant, Version};
+
+pub type IslandRng = StdRng;
+
+/// Stream of ids given to islands of a host.
+pub const ISLAND_IDS_STREAM: u64 = u64::MAX;
+/// Stream used by address books of the collector and the local dispatcher.
+pub const ROUTING_STREAM: u64 = u64::MAX - 1;
+
+const ISLAND_RNG_STREAM: u64 = 0;
+const ISLAND_IDS_SUBSTREAM: u64 = 1;
+const ISLAND_ROUTING_STREAM: u64 = 2;
+
+/// Derives a seed of an independent stream (splitmix64 of both values).
+pub fn derive_seed(seed: u64, stream: u64) -> u64 {
+    let mut z = seed ^ stream.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Seed of a host, so hosts sharing the master seed do not repeat
+/// each other's streams and island ids.
+pub fn host_seed(seed: u64, host: &str) -> u64 {
+    // FNV-1a, stable between Rust releases unlike `DefaultHasher`
+    let hash = host.bytes().fold(0xCBF2_9CE4_8422_2325, |hash: u64, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3)
+    });
+    derive_seed(seed, hash)
+}
+
+pub fn island_rng(island_seed: u64) -> IslandRng {
+    StdRng::seed_from_u64(derive_seed(island_seed, ISLAND_RNG_STREAM))
+}
+
+pub fn island_id_generator(island_seed: u64) -> IdGenerator {
+    IdGenerator::new(derive_seed(island_seed, ISLAND_IDS_SUBSTREAM))
+}
+
+pub fn island_routing_seed(island_seed: u64) -> u64 {
+    derive_seed(island_seed, ISLAND_ROUTING_STREAM)
+}
+
+/// Generates random (version 4) uuids from a seeded stream.
+#[derive(Debug, Clone)]
+pub struct IdGenerator {
+    rng: StdRng,
+}
+
+impl IdGenerator {
+    pub fn new(seed: u64) -> Self {
+        IdGenerator {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn next_id(&mut self) -> Uuid {
+        Builder::from_bytes(self.rng.gen())
+            .set_variant(Variant::RFC4122)
+            .set_version(Version::Random)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive_seed, host_seed, island_rng, IdGenerator};
+    use rand::distributions::Standard;
+    use rand::Rng;
+    use uuid::Version;
+
+    #[test]
+    fn test_streams_are_reproducible() {
+        let seed = host_seed(42, "127.0.0.1:5000");
+        assert_eq!(seed, host_seed(42, "127.0.0.1:5000"));
+        assert_ne!(seed, host_seed(42, "127.0.0.1:5001"));
+
+        let draws = |stream| -> Vec<u32> {
+            island_rng(derive_seed(seed, stream))
+                .sample_iter(Standard)
+                .take(5)
+                .collect()
+        };
+        let first = draws(0);
+        let again = draws(0);
+        let other = draws(1);
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn test_id_generator() {
+        let mut ids = IdGenerator::new(7);
+        let mut same_ids = IdGenerator::new(7);
+        let id = ids.next_id();
+
+        assert_eq!(id, same_ids.next_id());
+        assert_ne!(id, ids.next_id());
+        assert_eq!(id.get_version(), Some(Version::Random));
+    }
+}
@@ -0,0 +1,527 @@
+//! Server starting turns of all hosts with global sync.
+//!
+//! The server waits until every host has finished a turn before it starts
+//! the next one. It also keeps track of hosts joining, leaving or failing
+//! and balances load between them. It runs either as the `server` binary or
+//! in the coordinator host, see [`crate::settings::SyncLeader`], and in both
+//! cases is configured by the settings of the coordinator.
+
+use crate::balance::LoadTracker;
+use crate::codec::CodecKind;
+use crate::dispatcher::Addr;
+use crate::error::{RampError, Result};
+use crate::map::{self, MapOwners};
+use crate::message::Message;
+use crate::network::{self, recv_rr, Ip, Port};
+use crate::settings::{ClientSettings, HostFailurePolicy, NetworkSettings};
+use config::ConfigError;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use zmq::Socket;
+
+const DEFAULT_BALANCE_TOLERANCE: f64 = 0.2;
+
+/// Network settings of a simulation with global sync.
+pub fn sync_settings(settings: &ClientSettings) -> Result<&NetworkSettings> {
+    match settings.network.as_ref() {
+        Some(network) if network.global_sync.sync => Ok(network),
+        _ => Err(RampError::Settings(ConfigError::Message(String::from(
+            "the server needs settings of a host with global sync",
+        )))),
+    }
+}
+
+/// Runs the simulation until the last turn or until it is aborted.
+pub fn run(settings: &ClientSettings) -> Result<()> {
+    bind(settings)?.run(settings)
+}
+
+/// Binds sockets of the server, so a server which cannot bind fails
+/// before any host waits for it.
+pub fn bind(settings: &ClientSettings) -> Result<BoundServer> {
+    let sync = &sync_settings(settings)?.global_sync;
+    let context = zmq::Context::new();
+    let rep_sock = context.socket(zmq::REP)?;
+    let pub_sock = context.socket(zmq::PUB)?;
+    let heartbeat_sock = context.socket(zmq::SUB)?;
+
+    network::bind_sock(&rep_sock, sync.server_ip.clone(), sync.server_rep_port)?;
+    network::bind_sock(&pub_sock, sync.server_ip.clone(), sync.server_pub_port)?;
+    Ok(BoundServer {
+        rep_sock,
+        pub_sock,
+        heartbeat_sock,
+    })
+}
+
+/// Server with its sockets bound, waiting to be run.
+pub struct BoundServer {
+    rep_sock: Socket,
+    pub_sock: Socket,
+    heartbeat_sock: Socket,
+}
+
+impl BoundServer {
+    /// Runs the simulation until the last turn or until it is aborted.
+    pub fn run(self, settings: &ClientSettings) -> Result<()> {
+        let network = sync_settings(settings)?;
+        let sync = &network.global_sync;
+        let codec = network.codec.unwrap_or_default();
+        let BoundServer {
+            rep_sock,
+            pub_sock,
+            heartbeat_sock,
+        } = self;
+
+        let from = sync.server_ip.clone();
+        let hosts = network.hosts_num;
+        let ip_table = network::wait_for_hosts(&rep_sock, codec, &from, hosts, true)?;
+        for (ip, port) in &ip_table {
+            network::connect_sock(&heartbeat_sock, ip, *port)?;
+        }
+        network::subscribe_sock(&heartbeat_sock, String::from(network::HEARTBEAT_KEY))?;
+        network::publish_ip_table(&pub_sock, codec, &from, &ip_table)?;
+        let confirmations = network::wait_for_confirmations(
+            &rep_sock, &pub_sock, codec, &from, hosts, true, &ip_table,
+        )?;
+
+        // Restored islands have not processed the checkpointed turn yet
+        let first_turn = confirmations
+            .resume_turn
+            .map_or(0, |turn| turn.saturating_sub(1));
+        let mut server = Server {
+            rep_sock,
+            pub_sock,
+            heartbeat_sock,
+            codec,
+            identity: from,
+            hosts: Hosts::new(&ip_table),
+            last_heartbeat: Instant::now(),
+            owners: confirmations.owners,
+            capacity: settings.map.partitioner().capacity(),
+            pending_joins: vec![],
+            joining: HashSet::new(),
+            hosts_changed: false,
+            loads: LoadTracker::new(sync.balance_by.unwrap_or_default()),
+        };
+        server.run(settings, first_turn)
+    }
+}
+
+/// Hosts still taking part in the simulation, by their address, with the
+/// time they were last heard from. Hosts identify themselves with their
+/// key, see [`network::host_key`], so hosts sharing an ip are told apart.
+struct Hosts {
+    alive: HashMap<Addr, Instant>,
+}
+
+impl Hosts {
+    fn new(ip_table: &[(Ip, Port)]) -> Self {
+        let now = Instant::now();
+        let alive = ip_table.iter().map(|addr| (addr.clone(), now)).collect();
+        Hosts { alive }
+    }
+
+    fn add(&mut self, addr: Addr) {
+        self.alive.insert(addr, Instant::now());
+    }
+
+    fn ip_table(&self) -> Vec<(Ip, Port)> {
+        let mut ip_table: Vec<(Ip, Port)> = self.alive.keys().cloned().collect();
+        ip_table.sort();
+        ip_table
+    }
+
+    /// Address of a known host from the identity it sent.
+    fn find(&self, identity: &str) -> Option<Addr> {
+        network::parse_host_key(identity).filter(|addr| self.alive.contains_key(addr))
+    }
+
+    fn seen(&mut self, addr: &Addr) {
+        if let Some(last_seen) = self.alive.get_mut(addr) {
+            *last_seen = Instant::now();
+        }
+    }
+
+    /// Hosts which have not confirmed the turn and either stayed silent
+    /// for too long or did not finish the turn in time.
+    fn failed(
+        &self,
+        confirmed: &HashSet<Addr>,
+        host_timeout: Duration,
+        turn_overdue: bool,
+    ) -> Vec<Addr> {
+        self.alive
+            .iter()
+            .filter(|(addr, _)| !confirmed.contains(*addr))
+            .filter(|(_, last_seen)| turn_overdue || last_seen.elapsed() > host_timeout)
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+}
+
+/// Addresses of hosts as `ip:port, ip:port`, for logs and errors.
+fn addr_list(hosts: &[Addr]) -> String {
+    let hosts: Vec<String> = hosts
+        .iter()
+        .map(|(ip, port)| network::host_key(ip, *port))
+        .collect();
+    hosts.join(", ")
+}
+
+struct Server {
+    rep_sock: Socket,
+    pub_sock: Socket,
+    heartbeat_sock: Socket,
+    codec: CodecKind,
+    identity: String,
+    hosts: Hosts,
+    last_heartbeat: Instant,
+    /// Unknown if the coordinator did not send them, then hosts cannot join.
+    owners: Option<MapOwners>,
+    /// Cells of the map, fragments of joining hosts have to fit in them.
+    capacity: Option<u64>,
+    /// Hosts which asked to join, admitted at the next turn boundary.
+    pending_joins: Vec<(Ip, Port, Vec<Uuid>)>,
+    /// Admitted hosts which have not told they are ready yet.
+    joining: HashSet<Addr>,
+    /// Hosts left since hosts were last published.
+    hosts_changed: bool,
+    /// Loads of islands since they were last balanced.
+    loads: LoadTracker,
+}
+
+impl Server {
+    fn run(&mut self, settings: &ClientSettings, first_turn: u32) -> Result<()> {
+        log::info!("START SIM");
+        let sync = &sync_settings(settings)?.global_sync;
+        let checkpoint_interval = settings
+            .checkpoint
+            .as_ref()
+            .map_or(0, |checkpoint| checkpoint.interval);
+        let host_timeout = Duration::from_millis(
+            sync.host_timeout_ms
+                .unwrap_or(network::DEFAULT_PEER_TIMEOUT_MS),
+        );
+        let turn_timeout = sync.turn_timeout_ms.map(Duration::from_millis);
+        let policy = sync.on_host_failure.unwrap_or(HostFailurePolicy::Abort);
+        let balance_interval = sync.balance_interval.unwrap_or(0);
+        let balance_tolerance = sync.balance_tolerance.unwrap_or(DEFAULT_BALANCE_TOLERANCE);
+        let mut turn = first_turn;
+        let mut result = Ok(());
+        while turn < settings.turns {
+            if let Err(e) = self.update_hosts(host_timeout) {
+                log::error!("{}", e);
+                break;
+            }
+            log::info!("TURN {}", turn + 1);
+            if checkpoint_interval != 0 && (turn + 1).is_multiple_of(checkpoint_interval) {
+                self.publish(Message::Checkpoint(turn + 1))?;
+            }
+            self.publish(Message::NextTurn(turn + 1))?;
+            turn += 1;
+
+            let turn_start = Instant::now();
+            let mut confirmed = HashSet::new();
+            loop {
+                let overdue = turn_timeout.is_some_and(|timeout| turn_start.elapsed() > timeout);
+                let failed = self.hosts.failed(&confirmed, host_timeout, overdue);
+                if !failed.is_empty() {
+                    if let Err(e) = self.handle_failure(failed, policy, turn) {
+                        result = Err(e);
+                        break;
+                    }
+                    continue;
+                }
+                if self.all_confirmed(&confirmed) {
+                    break;
+                }
+                if let Err(e) = self.wait_for_confirmations(&mut confirmed) {
+                    log::error!("{}", e);
+                    break;
+                }
+            }
+            if result.is_err() || !self.all_confirmed(&confirmed) {
+                break;
+            }
+            if balance_interval != 0
+                && turn.is_multiple_of(balance_interval)
+                && turn < settings.turns
+            {
+                self.balance(balance_tolerance)?;
+            }
+        }
+        log::info!("FIN SIM");
+        self.publish(Message::FinSim)?;
+        result
+    }
+
+    fn all_confirmed(&self, confirmed: &HashSet<Addr>) -> bool {
+        self.hosts.alive.keys().all(|addr| confirmed.contains(addr))
+    }
+
+    /// Admits hosts which asked to join and publishes hosts and map owners
+    /// if they changed. Joined hosts have to be ready before the next turn,
+    /// the ones which are not ready in time are dropped.
+    fn update_hosts(&mut self, host_timeout: Duration) -> Result<()> {
+        if self.pending_joins.is_empty() && !self.hosts_changed {
+            return Ok(());
+        }
+        for (ip, port, island_ids) in mem::take(&mut self.pending_joins) {
+            let owners = match self.owners.as_mut() {
+                Some(owners) => owners,
+                None => {
+                    log::warn!("Host {}:{} cannot join, map owners are unknown", ip, port);
+                    continue;
+                }
+            };
+            if !map::add_host(owners, &ip, port, &island_ids, self.capacity) {
+                log::warn!("Host {}:{} cannot join, no map fragments left", ip, port);
+                continue;
+            }
+            log::info!(
+                "Host {}:{} joins with {} islands",
+                ip,
+                port,
+                island_ids.len()
+            );
+            network::connect_sock(&self.heartbeat_sock, &ip, port)?;
+            self.joining.insert((ip.clone(), port));
+            self.hosts.add((ip, port));
+        }
+        self.publish(Message::IpTable(self.hosts.ip_table()))?;
+        if let Some(owners) = self.owners.clone() {
+            self.publish(Message::Owners(owners))?;
+        }
+        self.hosts_changed = false;
+
+        let start = Instant::now();
+        let mut confirmed = HashSet::new();
+        while !self.joining.is_empty() {
+            if start.elapsed() > host_timeout {
+                let not_ready: Vec<Addr> = self.joining.drain().collect();
+                log::error!("Hosts {} not ready in time", addr_list(&not_ready));
+                return self.remove_hosts(not_ready);
+            }
+            self.wait_for_confirmations(&mut confirmed)?;
+        }
+        Ok(())
+    }
+
+    fn wait_for_confirmations(&mut self, confirmed: &mut HashSet<Addr>) -> Result<()> {
+        if self.last_heartbeat.elapsed() >= Duration::from_millis(network::HEARTBEAT_INTERVAL_MS) {
+            self.publish(Message::Heartbeat)?;
+            self.last_heartbeat = Instant::now();
+        }
+        let mut items = [
+            self.rep_sock.as_poll_item(zmq::POLLIN),
+            self.heartbeat_sock.as_poll_item(zmq::POLLIN),
+        ];
+        zmq::poll(&mut items, network::HEARTBEAT_INTERVAL_MS as i64)?;
+        let (requests, heartbeats) = (items[0].is_readable(), items[1].is_readable());
+
+        if heartbeats {
+            while network::poll_readable(&self.heartbeat_sock, 0)? {
+                match network::recv_ps(&self.heartbeat_sock, self.codec) {
+                    Ok((_, from, Message::Heartbeat)) => {
+                        if let Some(addr) = self.hosts.find(&from) {
+                            self.hosts.seen(&addr);
+                        }
+                    }
+                    Ok((_, from, msg)) => {
+                        log::warn!("Unexpected message {} from {}", msg.as_string(), from)
+                    }
+                    Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+                    Err(e) => log::warn!("Dropping malformed heartbeat: {}", e),
+                }
+            }
+        }
+        if !requests {
+            return Ok(());
+        }
+        match recv_rr(&self.rep_sock, self.codec) {
+            Ok((from, Message::TurnDone(loads))) => match self.hosts.find(&from) {
+                Some(addr) => {
+                    self.hosts.seen(&addr);
+                    self.loads.record(&loads);
+                    confirmed.insert(addr);
+                    self.reply(Message::Ok)?;
+                }
+                None => {
+                    log::warn!("Turn confirmed by host {} which is considered dead", from);
+                    self.reply(Message::Err)?;
+                }
+            },
+            Ok((from, Message::HostReady)) if self.ready(&from) => {
+                log::info!("{} {}", Message::HostReady.as_string(), from);
+                self.reply(Message::Ok)?;
+            }
+            Ok((_, Message::Join(ip, port, island_ids))) => {
+                let known = self.hosts.alive.contains_key(&(ip.clone(), port))
+                    || self
+                        .pending_joins
+                        .iter()
+                        .any(|(other_ip, other_port, _)| *other_ip == ip && *other_port == port);
+                if known || !self.fits(&island_ids) {
+                    log::warn!("Host {}:{} cannot join", ip, port);
+                    self.reply(Message::Err)?;
+                } else {
+                    self.pending_joins.push((ip, port, island_ids));
+                    self.reply(Message::Ok)?;
+                }
+            }
+            Ok((_, Message::Leave(ip, port))) => {
+                log::info!("Host {}:{} leaves", ip, port);
+                let addr = (ip.clone(), port);
+                self.hosts.alive.remove(&addr);
+                self.joining.remove(&addr);
+                if let Some(owners) = self.owners.as_mut() {
+                    map::remove_host(owners, &ip, port);
+                }
+                self.hosts_changed = true;
+                self.reply(Message::Ok)?;
+            }
+            Ok((from, Message::FinSim)) => {
+                self.reply(Message::Ok)?;
+                return Err(RampError::Aborted(format!("host {} finished early", from)));
+            }
+            Ok((_from, msg)) => {
+                log::warn!("Unexpected message {:#?}", msg);
+                self.reply(Message::Err)?;
+            }
+            Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+            Err(e) => {
+                log::warn!("Dropping malformed message: {}", e);
+                self.reply(Message::Err)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks a joining host as ready, `false` if it is not joining.
+    fn ready(&mut self, identity: &str) -> bool {
+        match network::parse_host_key(identity) {
+            Some(addr) if self.joining.remove(&addr) => {
+                self.hosts.seen(&addr);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether fragments for the islands fit in the map along with the
+    /// ones of hosts which already asked to join.
+    fn fits(&self, island_ids: &[Uuid]) -> bool {
+        let mut owners = match self.owners.clone() {
+            Some(owners) => owners,
+            None => return false,
+        };
+        let joins = self.pending_joins.iter().map(|(_, _, ids)| ids.as_slice());
+        joins
+            .chain(Some(island_ids))
+            .all(|ids| map::add_host(&mut owners, "", 0, ids, self.capacity))
+    }
+
+    /// Lets an island of the most loaded host trade places with one of the
+    /// least loaded host. Hosts move the islands before the next turn.
+    fn balance(&mut self, tolerance: f64) -> Result<()> {
+        let owners = match self.owners.as_mut() {
+            Some(owners) => owners,
+            None => return Ok(()),
+        };
+        if let Some((leaving, arriving)) = self.loads.plan(owners, tolerance) {
+            log::info!(
+                "Island {} moves from {}:{} to {}:{}, island {} the other way",
+                leaving.2,
+                leaving.0,
+                leaving.1,
+                arriving.0,
+                arriving.1,
+                arriving.2
+            );
+            map::exchange_hosts(owners, &leaving, &arriving);
+            let owners = owners.clone();
+            self.publish(Message::Exchange(leaving, arriving))?;
+            self.publish(Message::Owners(owners))?;
+        }
+        self.loads.clear();
+        Ok(())
+    }
+
+    /// Either gives up the simulation with a report of failed hosts
+    /// or tells the remaining hosts to stop talking to them.
+    fn handle_failure(
+        &mut self,
+        failed: Vec<Addr>,
+        policy: HostFailurePolicy,
+        turn: u32,
+    ) -> Result<()> {
+        for addr in &failed {
+            log::error!(
+                "Host {}:{} failed in turn {}, last heard from {:.1}s ago",
+                addr.0,
+                addr.1,
+                turn,
+                self.hosts.alive[addr].elapsed().as_secs_f64()
+            );
+        }
+        if policy == HostFailurePolicy::Abort {
+            return Err(RampError::Aborted(format!(
+                "hosts {} failed in turn {}",
+                addr_list(&failed),
+                turn
+            )));
+        }
+        self.remove_hosts(failed)
+    }
+
+    /// Tells the remaining hosts to stop talking to the given ones.
+    fn remove_hosts(&mut self, hosts: Vec<Addr>) -> Result<()> {
+        for (ip, port) in hosts {
+            if self.hosts.alive.remove(&(ip.clone(), port)).is_some() {
+                if let Some(owners) = self.owners.as_mut() {
+                    map::remove_host(owners, &ip, port);
+                }
+                self.publish(Message::HostDown(ip, port))?;
+            }
+        }
+        if self.hosts.alive.is_empty() {
+            return Err(RampError::Aborted(String::from("no hosts left")));
+        }
+        log::warn!("Continuing with {} hosts", self.hosts.alive.len());
+        Ok(())
+    }
+
+    fn publish(&self, msg: Message) -> Result<()> {
+        let key = String::from(network::SERVER_INFO_KEY);
+        network::send_ps(&self.pub_sock, self.codec, key, self.identity.clone(), msg)
+    }
+
+    fn reply(&self, msg: Message) -> Result<()> {
+        network::send_rr(&self.rep_sock, self.codec, self.identity.clone(), msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hosts;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[test]
+    fn test_hosts_on_the_same_ip_are_told_apart() {
+        let first = (String::from("10.0.0.1"), 5000);
+        let second = (String::from("10.0.0.1"), 5001);
+        let hosts = Hosts::new(&[first.clone(), second.clone()]);
+
+        assert_eq!(hosts.find("10.0.0.1:5001"), Some(second.clone()));
+        assert_eq!(hosts.find("10.0.0.1:5002"), None);
+        let confirmed: HashSet<_> = vec![first].into_iter().collect();
+        assert_eq!(
+            hosts.failed(&confirmed, Duration::from_secs(60), true),
+            [second]
+        );
+    }
+}
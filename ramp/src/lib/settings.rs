@@ -1,11 +1,27 @@
+use crate::balance::LoadMetric;
+use crate::codec::CodecKind;
+use crate::partition::{Partitioner, PartitionerKind};
+use crate::topology::TopologyKind;
 use config::{Config, ConfigError, File};
+use std::sync::Arc;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ClientSettings {
     pub turns: u32,
     pub islands: u32,
-    pub network: NetworkSettings,
     pub islands_sync: bool,
+    pub map: MapSettings,
+    /// Missing `[network]` section runs the whole simulation in one process.
+    pub network: Option<NetworkSettings>,
+    pub checkpoint: Option<CheckpointSettings>,
+    /// Values recorded by islands are not written when missing.
+    pub results: Option<ResultsSettings>,
+    /// Islands which are not synchronized run at most this many turns ahead
+    /// of the slowest island of the simulation. Not limited when missing.
+    pub staleness: Option<u32>,
+    /// Master seed of all random number generators. A random one
+    /// is chosen and logged if it is missing.
+    pub seed: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -19,42 +35,97 @@ pub struct NetworkSettings {
     pub pub_port: u32,
     pub metrics_port: u32,
     pub global_sync: GlobalSyncSettings,
-    pub map: MapSettings,
+    /// Joins a simulation which is already running instead of taking part
+    /// in the startup handshake. Requires global sync.
+    pub join: Option<bool>,
+    /// The host leaves the simulation after finishing this turn.
+    /// Requires global sync.
+    pub leave_after_turn: Option<u32>,
+    /// Wire format of messages, the same on all hosts and the server.
+    pub codec: Option<CodecKind>,
 }
 
+/// Turns of all hosts are started by a server, either the separate `server`
+/// binary or the coordinator host, see [`SyncLeader`]. The server is
+/// configured by the settings of the coordinator.
 #[derive(Debug, Deserialize, Clone)]
 pub struct GlobalSyncSettings {
     pub sync: bool,
+    /// Who runs the server, the `server` binary when missing.
+    pub leader: Option<SyncLeader>,
+    /// Address the server binds to, on the coordinator host if it leads.
     pub server_ip: String,
     pub server_rep_port: u32,
     pub server_pub_port: u32,
+    /// The host aborts when the server stays silent for longer.
+    pub server_timeout_ms: Option<u64>,
+    /// A host is considered dead when no heartbeat comes from it for longer.
+    pub host_timeout_ms: Option<u64>,
+    /// A host is considered dead when it does not finish a turn in time.
+    /// Turns are not limited when missing.
+    pub turn_timeout_ms: Option<u64>,
+    /// What to do after a host failure, `abort` when missing.
+    pub on_host_failure: Option<HostFailurePolicy>,
+    /// Islands are moved between hosts to even out their load every
+    /// this many turns. Islands stay where they are when missing.
+    pub balance_interval: Option<u32>,
+    /// Hosts are balanced when one has more than `1 + tolerance` times
+    /// the load of another, `0.2` when missing.
+    pub balance_tolerance: Option<f64>,
+    /// What the load of islands is measured with, turn time when missing.
+    pub balance_by: Option<LoadMetric>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncLeader {
+    /// The separate `server` binary, started with the settings file of
+    /// the coordinator.
+    #[default]
+    Server,
+    /// The coordinator host runs the server in a thread of its own.
+    Coordinator,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct MapSettings {
     pub chunk_len: i64,
+    /// Width of the border of neighbouring fragments mirrored by every
+    /// island, at most `chunk_len`. No cells are mirrored when missing.
+    pub halo: Option<i64>,
+    /// Shape of fragments, strips when missing.
+    pub partitioner: Option<PartitionerKind>,
+    /// Fragments in a row of blocks or of the Hilbert curve, 1 when missing.
+    pub columns: Option<i64>,
+    /// Bounded when missing.
+    pub topology: Option<TopologyKind>,
 }
 
-impl ClientSettings {
-    pub fn new(file_name: String) -> Result<Self, ConfigError> {
-        let mut settings = Config::new();
-
-        settings.merge(File::with_name(&file_name))?;
-        settings.try_into()
+impl MapSettings {
+    pub fn partitioner(&self) -> Arc<dyn Partitioner> {
+        self.partitioner
+            .unwrap_or_default()
+            .build(self.chunk_len, self.columns.unwrap_or(1))
     }
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ServerSettings {
-    pub hosts: u32,
-    pub turns: u32,
-    pub ip: String,
-    pub rep_port: u32,
-    pub pub_port: u32,
-    pub metrics_port: u32,
+/// With global sync the server checkpoints islands of all hosts
+/// with the interval from the settings of the coordinator.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CheckpointSettings {
+    pub interval: u32,
+    pub dir: String,
+}
+
+/// See [`crate::recorder`].
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResultsSettings {
+    pub dir: String,
+    /// Islands of a host write to one file instead of a file each.
+    pub merged: Option<bool>,
 }
 
-impl ServerSettings {
+impl ClientSettings {
     pub fn new(file_name: String) -> Result<Self, ConfigError> {
         let mut settings = Config::new();
 
@@ -62,3 +133,12 @@ impl ServerSettings {
         settings.try_into()
     }
 }
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HostFailurePolicy {
+    /// Finish the simulation on all hosts.
+    Abort,
+    /// Drop islands of the failed host and go on with the remaining ones.
+    Continue,
+}
@@ -1,252 +1,986 @@
 use crate::collector::Collector;
-use crate::dispatcher::{Dispatcher, DispatcherMessage};
+use crate::dispatcher::{Addr, Dispatcher, DispatcherMessage, LocalDispatcher, LocalTurns};
 use crate::island::Island;
 use crate::network::CollectorNetworkCtx;
 use crate::network::DispatcherNetworkCtx;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Mutex, PoisonError};
 use std::thread;
 
+use config::ConfigError;
 use uuid::Uuid;
 
-use crate::address_book::AddressBook;
+use crate::address_book::{self, AddressBook, LocalIslands, TurnClock};
+use crate::balance::IslandLoad;
+use crate::checkpoint::{self, Checkpointer, IslandCheckpoint};
+use crate::codec::CodecKind;
+use crate::error::{RampError, Result};
 use crate::island::{IslandEnv, IslandFactory};
 use crate::map::{Fragment, FragmentOwner, Map, MapOwners};
-use crate::message::Message;
+use crate::message::{Message, TurnNumber};
 use crate::metrics::MetricHub;
 use crate::network::NetworkCtx;
-use crate::settings::ClientSettings;
-use crate::{metrics, network, utils};
+use crate::outcome::{self, EncodedReducer, IslandResult, Reducer};
+use crate::pace::Pace;
+use crate::recorder::Results;
+use crate::rng::{self, IdGenerator};
+use crate::settings::{ClientSettings, NetworkSettings, SyncLeader};
+use crate::topology::Topology;
+use crate::{metrics, network, server, utils};
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::time::Instant;
-use zmq::Socket;
+use std::mem;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-const LOGGER_LEVEL: &str = "info";
-const EXPECTED_ARGS_NUM: usize = 3;
+const LOCAL_HOST_IP: &str = "127.0.0.1";
+const LOCAL_HOST_PORT: network::Port = 0;
 
-pub struct Simulation;
+/// Builds a [`Simulation`] from settings, an island factory and metrics.
+///
+/// ```no_run
+/// # use ramp::island::IslandFactory;
+/// # use ramp::simulation::SimulationBuilder;
+/// # fn factory() -> Box<dyn IslandFactory> { unimplemented!() }
+/// let result = SimulationBuilder::new()
+///     .settings_file("LocalSettings.toml")
+///     .factory(factory())
+///     .logger_level("info")
+///     .build()
+///     .and_then(|simulation| simulation.run());
+/// ```
+pub struct SimulationBuilder {
+    settings: Option<ClientSettings>,
+    settings_file: Option<String>,
+    factory: Option<Box<dyn IslandFactory>>,
+    metrics: MetricHub,
+    logger_level: Option<String>,
+    restore_from: Option<String>,
+    topology: Option<Arc<dyn Topology>>,
+    reducer: Option<Box<dyn EncodedReducer>>,
+}
+
+impl SimulationBuilder {
+    pub fn new() -> Self {
+        SimulationBuilder {
+            settings: None,
+            settings_file: None,
+            factory: None,
+            metrics: MetricHub::default(),
+            logger_level: None,
+            restore_from: None,
+            topology: None,
+            reducer: None,
+        }
+    }
+
+    pub fn settings(mut self, settings: ClientSettings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Settings are read from the file when the simulation is built.
+    /// Ignored if settings were given with [`SimulationBuilder::settings`].
+    pub fn settings_file(mut self, file_name: &str) -> Self {
+        self.settings_file = Some(file_name.to_owned());
+        self
+    }
+
+    pub fn factory(mut self, factory: Box<dyn IslandFactory>) -> Self {
+        self.factory = Some(factory);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: MetricHub) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Initializes the global logger with given level. Leave it unset
+    /// if the application configures logging on its own.
+    pub fn logger_level(mut self, level: &str) -> Self {
+        self.logger_level = Some(level.to_owned());
+        self
+    }
+
+    /// Restores islands from checkpoints found under `path` instead of
+    /// starting a new simulation. See [`crate::checkpoint`].
+    pub fn restore_from(mut self, path: &str) -> Self {
+        self.restore_from = Some(path.to_owned());
+        self
+    }
+
+    /// Topology of the map, e.g. a [`crate::topology::Graph`].
+    /// Overrides the topology given in settings.
+    pub fn topology(mut self, topology: Arc<dyn Topology>) -> Self {
+        self.topology = Some(topology);
+        self
+    }
+
+    /// Reduces results of islands to the outcome of the simulation on the
+    /// coordinator, see [`crate::outcome`]. Ignored by other hosts.
+    pub fn reducer<R: Reducer + 'static>(mut self, reducer: R) -> Self {
+        self.reducer = Some(Box::new(reducer));
+        self
+    }
+
+    pub fn build(self) -> Result<Simulation> {
+        let settings = match (self.settings, self.settings_file) {
+            (Some(settings), _) => settings,
+            (None, Some(file_name)) => ClientSettings::new(file_name)?,
+            (None, None) => return Err(RampError::MissingSettings),
+        };
+        let factory = self.factory.ok_or(RampError::MissingFactory)?;
+        check_map_settings(&settings)?;
+        check_turn_settings(&settings)?;
+        if let Some(level) = self.logger_level {
+            utils::init_logger(&level)?;
+        }
+        let checkpoints = match self.restore_from {
+            Some(path) => load_checkpoints(&path, settings.islands)?,
+            None => vec![],
+        };
+
+        let topology = self
+            .topology
+            .unwrap_or_else(|| settings.map.topology.unwrap_or_default().build());
+
+        Ok(Simulation {
+            settings,
+            factory,
+            metrics: self.metrics,
+            checkpoints,
+            topology,
+            reducer: self.reducer,
+        })
+    }
+}
+
+impl Default for SimulationBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Simulation {
+    settings: ClientSettings,
+    factory: Box<dyn IslandFactory>,
+    metrics: MetricHub,
+    checkpoints: Vec<IslandCheckpoint>,
+    topology: Arc<dyn Topology>,
+    reducer: Option<Box<dyn EncodedReducer>>,
+}
 
 impl Simulation {
-    pub fn start_simulation(factory: Box<dyn IslandFactory>, metrics: MetricHub) {
-        utils::init_logger(LOGGER_LEVEL);
-        let args: Vec<String> = utils::parse_input_args(EXPECTED_ARGS_NUM);
-        let settings_file_name = args[1].clone();
-        let settings = load_settings(settings_file_name.clone());
+    /// Starts the simulation in a background thread.
+    pub fn start(self) -> Result<SimulationHandle> {
+        let thread = thread::spawn(move || self.execute());
+        Ok(SimulationHandle { thread })
+    }
 
-        log::info!("Initializing simulation");
-        let nt_settings = settings.network.clone();
-        let nt_ctx = NetworkCtx::new(nt_settings.clone());
-        let (dis_nt_ctx, coll_nt_ctx) = nt_ctx.init();
-
-        let metrics_port = settings.network.metrics_port;
-        let metrics_addr = format!("{}:{}", settings.network.host_ip.clone(), metrics_port);
-        thread::spawn(move || metrics::start_server(metrics_addr));
-        start(
+    /// Starts the simulation and blocks until it finishes.
+    pub fn run(self) -> Result<()> {
+        self.start()?.join()
+    }
+
+    fn execute(self) -> Result<()> {
+        let Simulation {
             settings,
-            dis_nt_ctx,
-            coll_nt_ctx,
             factory,
-            Arc::new(metrics),
-        );
+            metrics,
+            checkpoints,
+            topology,
+            reducer,
+        } = self;
+
+        log::info!("Initializing simulation");
+        let seed = settings.seed.unwrap_or_else(rand::random);
+        log::info!("Simulation seed: {}", seed);
+        match settings.network.clone() {
+            Some(nt_settings) => {
+                let server = if leads_sync(&nt_settings) {
+                    let server = server::bind(&settings)?;
+                    let server_settings = settings.clone();
+                    Some(thread::spawn(move || server.run(&server_settings)))
+                } else {
+                    None
+                };
+                let nt_ctx = NetworkCtx::new(nt_settings.clone())?;
+                let (dis_nt_ctx, coll_nt_ctx) = nt_ctx.init()?;
+
+                let metrics_addr = format!("{}:{}", nt_settings.host_ip, nt_settings.metrics_port);
+                thread::spawn(move || metrics::start_server(metrics_addr));
+                let result = start(
+                    settings,
+                    nt_settings,
+                    dis_nt_ctx,
+                    coll_nt_ctx,
+                    factory,
+                    Arc::new(metrics),
+                    checkpoints,
+                    topology,
+                    reducer,
+                    seed,
+                );
+                match server {
+                    // A failed server explains why the host failed as well
+                    Some(server) if result.is_ok() || server.is_finished() => {
+                        server.join().map_err(|_| RampError::Panicked)?.and(result)
+                    }
+                    _ => result,
+                }
+            }
+            None => start_local(
+                settings,
+                factory,
+                Arc::new(metrics),
+                checkpoints,
+                topology,
+                reducer,
+                seed,
+            ),
+        }
     }
 }
 
-fn load_settings(file_name: String) -> ClientSettings {
-    ClientSettings::new(file_name).unwrap()
+/// Whether this host runs the global sync server, see [`SyncLeader`].
+fn leads_sync(nt_settings: &NetworkSettings) -> bool {
+    let global_sync = &nt_settings.global_sync;
+    nt_settings.is_coordinator
+        && global_sync.sync
+        && global_sync.leader.unwrap_or_default() == SyncLeader::Coordinator
+}
+
+pub struct SimulationHandle {
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+impl SimulationHandle {
+    /// Waits for the simulation to finish.
+    pub fn join(self) -> Result<()> {
+        self.thread.join().map_err(|_| RampError::Panicked)?
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
 fn start(
     settings: ClientSettings,
+    nt_settings: NetworkSettings,
     dis_nt_ctx: DispatcherNetworkCtx,
-    coll_nt_ctx: CollectorNetworkCtx,
+    mut coll_nt_ctx: CollectorNetworkCtx,
     factory: Box<dyn IslandFactory>,
     metrics: Arc<MetricHub>,
-) {
-    let (island_txes, mut island_rxes) = create_channels(settings.islands);
-    let island_ids = create_island_ids(settings.islands);
-    let mut threads = Vec::<thread::JoinHandle<_>>::new();
+    checkpoints: Vec<IslandCheckpoint>,
+    topology: Arc<dyn Topology>,
+    reducer: Option<Box<dyn EncodedReducer>>,
+    seed: u64,
+) -> Result<()> {
+    let islands = settings.islands;
+    let is_coordinator = nt_settings.is_coordinator;
+    let host_ip = nt_settings.host_ip.clone();
+    let host_port = nt_settings.pub_port;
+    let coord_ip = nt_settings.coordinator_ip.clone();
+    let coord_port = nt_settings.coordinator_pub_port;
+    let mut ip_table = dis_nt_ctx.ip_table.clone();
+
+    let host_seed = rng::host_seed(seed, &format!("{}:{}", host_ip, host_port));
+    let (island_txes, island_rxes) = create_channels(settings.islands);
+    let island_ids = create_island_ids(settings.islands, &checkpoints, host_seed);
 
     let (collector_tx, collector_rx) = mpsc::channel();
     let (dispatcher_tx, dispatcher_rx) = mpsc::channel();
 
-    let islands_sync = if settings.islands_sync {
-        Some(Arc::new(Barrier::new(settings.islands as usize)))
-    } else {
-        None
-    };
-
-    let islands = settings.islands;
-    let is_coordinator = dis_nt_ctx.nt_sett.is_coordinator;
-    let host_ip = dis_nt_ctx.nt_sett.host_ip.clone();
-    let host_port = dis_nt_ctx.nt_sett.pub_port;
-    let coord_ip = dis_nt_ctx.nt_sett.coordinator_ip.clone();
-    let coord_port = dis_nt_ctx.nt_sett.coordinator_pub_port;
-    let mut ip_table = dis_nt_ctx.ip_table.clone();
-
-    let coll_address_book = AddressBook {
-        dispatcher_tx: mpsc::Sender::clone(&dispatcher_tx),
-        addresses: island_txes.clone(),
-        islands: island_ids.clone(),
-    };
+    let local_islands = address_book::local_islands(&island_ids, island_txes);
+    let coll_address_book = AddressBook::new(
+        mpsc::Sender::clone(&dispatcher_tx),
+        Arc::clone(&local_islands),
+        None,
+        rng::derive_seed(host_seed, rng::ROUTING_STREAM),
+    );
 
     let (sim_tx, sim_rx) = mpsc::channel();
-    thread::spawn(move || Dispatcher::new(dispatcher_rx, dis_nt_ctx, islands, sim_tx).start());
-    let mut wait = true;
-    while wait {
-        let incoming = sim_rx.try_iter();
-        for msg in incoming {
-            match msg {
-                Message::Ok => {
-                    wait = false;
-                    log::info!("Dispatcher ready");
-                }
-                _ => println!("dispatcher not ready yet"),
+    let dis_collector_tx = mpsc::Sender::clone(&collector_tx);
+    let dispatcher = thread::spawn(move || {
+        let mut dispatcher =
+            Dispatcher::new(dispatcher_rx, dis_nt_ctx, islands, sim_tx, dis_collector_tx);
+        dispatcher.start();
+        dispatcher
+    });
+    loop {
+        match sim_rx.recv() {
+            Ok(Message::Ok) => {
+                log::info!("Dispatcher ready");
+                break;
             }
+            Ok(_) => println!("dispatcher not ready yet"),
+            Err(_) => return Err(RampError::ChannelClosed),
         }
     }
 
-    let mut map_owners: MapOwners = HashMap::new();
-    if !is_coordinator {
-        dispatcher_tx
-            .send(DispatcherMessage::Unicast(
-                Message::Islands(island_ids.clone()),
-                (coord_ip, coord_port),
-            ))
-            .unwrap();
-        let (_, _, msg) = network::recv_ps(&coll_nt_ctx.sub_sock);
-        if let Message::Owners(owners) = msg {
-            map_owners = owners;
-        }
+    let map_owners = if nt_settings.join.unwrap_or(false) {
+        join_simulation(
+            &mut coll_nt_ctx,
+            &dispatcher_tx,
+            &sim_rx,
+            island_ids.clone(),
+            (host_ip.clone(), host_port),
+        )?
+    } else if !is_coordinator {
+        receive_map_owners(
+            &coll_nt_ctx,
+            &dispatcher_tx,
+            island_ids.clone(),
+            (coord_ip, coord_port),
+        )?
     } else {
-        ip_table.push((host_ip, host_port));
-        map_owners = create_map_owners(
-            &coll_nt_ctx.sub_sock,
+        ip_table.push((host_ip.clone(), host_port));
+        let map_owners = create_map_owners(
+            &coll_nt_ctx,
             island_ids.clone(),
-            settings.network.hosts_num,
+            nt_settings.hosts_num,
             ip_table,
-            settings.network.map.chunk_len,
-        );
-        dispatcher_tx
-            .send(DispatcherMessage::Broadcast(Message::Owners(
+            settings.map.chunk_len,
+        )?;
+        dispatcher_tx.send(DispatcherMessage::Broadcast(Message::Owners(
+            map_owners.clone(),
+        )))?;
+        if nt_settings.global_sync.sync {
+            // The server keeps owners up to date when hosts join or leave
+            dispatcher_tx.send(DispatcherMessage::Server(Message::Owners(
                 map_owners.clone(),
-            )))
-            .unwrap();
-    }
+            )))?;
+        }
+        map_owners
+    };
+
+    let map = create_map(&settings, map_owners, topology);
 
     //Only now can we tell the server we are ready
     log::info!("Sending ready message");
-    dispatcher_tx
-        .send(DispatcherMessage::Info(Message::HostReady))
-        .unwrap();
+    let ready = match checkpoints.first() {
+        Some(checkpoint) => Message::Resume(checkpoint.turn),
+        None => Message::HostReady,
+    };
+    dispatcher_tx.send(DispatcherMessage::Info(ready))?;
 
     // ============================== Spawning and starting islands ==========================================================
-    thread::spawn(move || Collector::new(collector_rx, coll_nt_ctx, coll_address_book).start());
+    let coll_map = map.clone();
+    let collector = thread::spawn(move || {
+        let mut collector = Collector::new(collector_rx, coll_nt_ctx, coll_address_book, coll_map);
+        collector.start();
+        collector
+    });
 
-    let islands = settings.islands;
-    for island_no in 0..islands {
-        let turns = settings.turns;
-        let island_id = island_ids[island_no as usize];
-        let island_sync = islands_sync.clone();
+    let global_sync = nt_settings.global_sync.sync;
+    let host = format!("{}:{}", host_ip, host_port);
+    let threads = spawn_islands(
+        &settings,
+        (host_ip, host_port),
+        island_ids,
+        local_islands,
+        island_rxes,
+        map,
+        &dispatcher_tx,
+        factory,
+        metrics,
+        checkpoints,
+        host_seed,
+        global_sync,
+    )?;
+    let results = join_islands(threads, &host);
 
-        let address_book =
-            create_address_book(&island_txes, &island_ids, island_no as i32, &dispatcher_tx);
+    if !global_sync {
+        // Dispatcher and collector could have finished already after an abort
+        let _ = dispatcher_tx.send(DispatcherMessage::Info(Message::FinSim));
+        let _ = collector_tx.send(Message::FinSim);
+    }
 
-        let island_rx = island_rxes.remove(0);
+    match sim_rx.try_recv() {
+        Ok(Message::Err) => {
+            return Err(RampError::Aborted(String::from(
+                "lost connection with the server",
+            )))
+        }
+        Ok(Message::HostDown(_, _)) => {
+            return Err(RampError::Aborted(String::from(
+                "host declared dead by the server",
+            )))
+        }
+        _ => {}
+    }
+    let mut results = results?;
 
-        let map = Map::new(settings.network.map.chunk_len, map_owners.clone());
-        let fragment_owner: FragmentOwner = (
-            settings.network.host_ip.clone(),
-            settings.network.pub_port,
-            island_id,
-        );
+    // Dispatcher and collector are finished, they only hand over their sockets
+    if !is_coordinator {
+        // Results are flushed when the sockets of both are closed
+        let _collector = collector.join().map_err(|_| RampError::Panicked)?;
+        let dispatcher = dispatcher.join().map_err(|_| RampError::Panicked)?;
+        if let Err(e) = dispatcher.send_results(results) {
+            log::error!("Cannot send results to the coordinator: {}", e);
+        }
+    } else if let Some(reducer) = reducer {
+        let hosts = dispatcher.join().map_err(|_| RampError::Panicked)?.hosts();
+        let mut collector = collector.join().map_err(|_| RampError::Panicked)?;
+        let timeout_ms = nt_settings
+            .global_sync
+            .host_timeout_ms
+            .unwrap_or(network::DEFAULT_PEER_TIMEOUT_MS);
+        results.append(&mut collector.gather_results(&hosts, Duration::from_millis(timeout_ms)));
+        let codec = nt_settings.codec.unwrap_or_default();
+        outcome::finish(reducer.as_ref(), codec, results, settings.results.as_ref())?;
+    }
+    Ok(())
+}
 
-        let island_env = IslandEnv::new(
-            address_book,
-            map,
-            fragment_owner,
-            Arc::clone(&metrics),
-            Instant::now(),
-        );
-        let island = factory.create(island_id, island_env);
-        let dispatcher_tx_cp = mpsc::Sender::clone(&dispatcher_tx);
-        let th_handler = if settings.network.global_sync.sync {
+/// Runs all islands in this process. Messages addressed to other hosts are
+/// looped back to the local islands by [`LocalDispatcher`], which also starts
+/// turns when islands are synchronized.
+fn start_local(
+    settings: ClientSettings,
+    factory: Box<dyn IslandFactory>,
+    metrics: Arc<MetricHub>,
+    checkpoints: Vec<IslandCheckpoint>,
+    topology: Arc<dyn Topology>,
+    reducer: Option<Box<dyn EncodedReducer>>,
+    seed: u64,
+) -> Result<()> {
+    let host_addr = (String::from(LOCAL_HOST_IP), LOCAL_HOST_PORT);
+    let host_seed = rng::host_seed(seed, &format!("{}:{}", host_addr.0, host_addr.1));
+    let (island_txes, island_rxes) = create_channels(settings.islands);
+    let island_ids = create_island_ids(settings.islands, &checkpoints, host_seed);
+    let (dispatcher_tx, dispatcher_rx) = mpsc::channel();
+
+    let local_islands = address_book::local_islands(&island_ids, island_txes);
+    let address_book = AddressBook::new(
+        mpsc::Sender::clone(&dispatcher_tx),
+        Arc::clone(&local_islands),
+        None,
+        rng::derive_seed(host_seed, rng::ROUTING_STREAM),
+    );
+    let turns = if settings.islands_sync {
+        Some(LocalTurns {
+            first_turn: checkpoints.first().map_or(1, |checkpoint| checkpoint.turn),
+            last_turn: settings.turns,
+            checkpoint_interval: settings
+                .checkpoint
+                .as_ref()
+                .map_or(0, |checkpoint| checkpoint.interval),
+        })
+    } else {
+        None
+    };
+    let dispatcher_addr = host_addr.clone();
+    thread::spawn(move || {
+        LocalDispatcher::new(dispatcher_rx, address_book, dispatcher_addr, turns).start()
+    });
+
+    let map_owners = assign_fragments(
+        vec![(host_addr.clone(), island_ids.clone())],
+        settings.map.chunk_len,
+    );
+    let map = create_map(&settings, map_owners, topology);
+
+    let host = format!("{}:{}", host_addr.0, host_addr.1);
+    let threads = spawn_islands(
+        &settings,
+        host_addr,
+        island_ids,
+        local_islands,
+        island_rxes,
+        map,
+        &dispatcher_tx,
+        factory,
+        metrics,
+        checkpoints,
+        host_seed,
+        settings.islands_sync,
+    )?;
+    let results = join_islands(threads, &host);
+
+    dispatcher_tx.send(DispatcherMessage::Info(Message::FinSim))?;
+    let results = results?;
+    if let Some(reducer) = reducer {
+        let codec = CodecKind::default();
+        outcome::finish(reducer.as_ref(), codec, results, settings.results.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Waits for islands and returns their results, see [`crate::outcome`].
+fn join_islands(
+    threads: Vec<thread::JoinHandle<Finished>>,
+    host: &str,
+) -> Result<Vec<IslandResult>> {
+    let mut results = vec![];
+    let mut panicked = false;
+    for thread in threads {
+        match thread.join() {
+            Ok(Some((island_id, value))) => results.push(IslandResult {
+                host: host.to_owned(),
+                island_id,
+                value,
+            }),
+            Ok(None) => {}
+            Err(_) => {
+                log::error!("Island thread panicked");
+                panicked = true;
+            }
+        }
+    }
+    if panicked {
+        return Err(RampError::Panicked);
+    }
+    Ok(results)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_islands(
+    settings: &ClientSettings,
+    host_addr: Addr,
+    island_ids: Vec<Uuid>,
+    local_islands: LocalIslands,
+    mut island_rxes: Vec<Receiver<Message>>,
+    map: Map,
+    dispatcher_tx: &Sender<DispatcherMessage>,
+    factory: Box<dyn IslandFactory>,
+    metrics: Arc<MetricHub>,
+    checkpoints: Vec<IslandCheckpoint>,
+    host_seed: u64,
+    global_sync: bool,
+) -> Result<Vec<thread::JoinHandle<Finished>>> {
+    let islands_sync = if settings.islands_sync {
+        Some(Arc::new(Barrier::new(settings.islands as usize)))
+    } else {
+        None
+    };
+    let checkpointer = settings.checkpoint.as_ref().map(|checkpoint| {
+        let host = format!("{}_{}", host_addr.0, host_addr.1);
+        Checkpointer::new(&checkpoint.dir, host, checkpoint.interval)
+    });
+    let codec = settings
+        .network
+        .as_ref()
+        .and_then(|network| network.codec)
+        .unwrap_or_default();
+    let results = match settings.results.as_ref() {
+        Some(results) => {
+            let host = format!("{}:{}", host_addr.0, host_addr.1);
+            Some(Results::new(results, host)?)
+        }
+        None => None,
+    };
+    let mut checkpoints: HashMap<Uuid, IslandCheckpoint> = checkpoints
+        .into_iter()
+        .map(|checkpoint| (checkpoint.island_id, checkpoint))
+        .collect();
+    let spawner = IslandSpawner {
+        host_addr,
+        local_islands,
+        map,
+        dispatcher_tx: mpsc::Sender::clone(dispatcher_tx),
+        factory: Arc::new(Mutex::new(factory)),
+        metrics,
+        codec,
+        host_seed,
+        results,
+        // Only a single synchronized host replays runs exactly
+        sequenced: settings.network.is_none() && settings.islands_sync,
+        loopback: settings.network.is_none(),
+    };
+
+    // All islands are restored before any of them starts, so a broken
+    // checkpoint does not leave the others waiting at the barrier.
+    let mut islands = vec![];
+    for (island_no, &island_id) in island_ids.iter().enumerate() {
+        let island_seed = rng::derive_seed(host_seed, island_no as u64);
+        let clock = settings.staleness.map(|_| TurnClock::default());
+        let mut island = spawner.create(island_id, island_seed, clock.clone());
+        let resume = match checkpoints.remove(&island_id) {
+            Some(checkpoint) => {
+                island.restore(&checkpoint.state)?;
+                log::info!("Island {} restored at turn {}", island_id, checkpoint.turn);
+                Some((checkpoint.turn, checkpoint.messages))
+            }
+            None => None,
+        };
+        islands.push((island_id, island, resume, clock));
+    }
+
+    let mut threads = Vec::<thread::JoinHandle<_>>::new();
+    for (island_id, island, resume, clock) in islands {
+        let turns = settings.turns;
+        let island_sync = islands_sync.clone();
+        let island_rx = island_rxes.remove(0);
+        let checkpointer = checkpointer.clone();
+        let spawner = spawner.clone();
+        let th_handler = if global_sync {
             thread::spawn(move || {
-                run_with_global_sync(island, island_rx, island_sync, dispatcher_tx_cp)
+                run_with_global_sync(
+                    island,
+                    island_id,
+                    island_rx,
+                    resume,
+                    checkpointer,
+                    island_sync,
+                    spawner,
+                )
             })
         } else {
-            thread::spawn(move || run(island, island_rx, turns, island_sync))
+            let first_turn = resume.as_ref().map_or(0, |(turn, _)| *turn);
+            let pace = settings
+                .staleness
+                .zip(clock)
+                .map(|(staleness, clock)| spawner.pace(island_id, staleness, clock, first_turn));
+            thread::spawn(move || {
+                run(
+                    island,
+                    island_id,
+                    island_rx,
+                    turns,
+                    resume,
+                    checkpointer,
+                    island_sync,
+                    pace,
+                )
+            })
         };
         threads.push(th_handler);
     }
+    Ok(threads)
+}
 
-    for thread in threads {
-        thread.join().unwrap();
+/// Turn and messages of a restored island.
+type Resume = Option<(TurnNumber, Vec<Message>)>;
+
+/// Island and the result it returned when it finished.
+type Finished = Option<(Uuid, Vec<u8>)>;
+
+/// Creates islands of this host, also the ones which move here from
+/// other hosts while the simulation runs.
+#[derive(Clone)]
+struct IslandSpawner {
+    host_addr: Addr,
+    local_islands: LocalIslands,
+    map: Map,
+    dispatcher_tx: Sender<DispatcherMessage>,
+    factory: Arc<Mutex<Box<dyn IslandFactory>>>,
+    metrics: Arc<MetricHub>,
+    codec: CodecKind,
+    host_seed: u64,
+    results: Option<Results>,
+    /// Messages of islands are tagged to be handed over in send order.
+    sequenced: bool,
+    /// Messages for other hosts are looped back to this one.
+    loopback: bool,
+}
+
+impl IslandSpawner {
+    /// Messages sent by the island are stamped with its `clock` if given.
+    fn create(
+        &self,
+        island_id: Uuid,
+        island_seed: u64,
+        clock: Option<TurnClock>,
+    ) -> Box<dyn Island> {
+        let mut address_book = AddressBook::new(
+            mpsc::Sender::clone(&self.dispatcher_tx),
+            Arc::clone(&self.local_islands),
+            Some(island_id),
+            rng::island_routing_seed(island_seed),
+        );
+        if let Some(clock) = clock {
+            address_book = address_book.with_clock(clock);
+        }
+        if self.sequenced {
+            address_book = address_book.with_sequence();
+        }
+        if self.loopback {
+            address_book = address_book.with_loopback();
+        }
+        let fragment_owner: FragmentOwner = (self.host_addr.0.clone(), self.host_addr.1, island_id);
+
+        let mut island_env = IslandEnv::new(
+            address_book,
+            self.map.clone(),
+            fragment_owner,
+            Arc::clone(&self.metrics),
+            Instant::now(),
+            island_seed,
+            self.codec,
+        );
+        if let Some(results) = self.results.as_ref() {
+            match results.recorder(island_id) {
+                Ok(recorder) => island_env = island_env.with_recorder(recorder),
+                Err(e) => log::error!("Results of island {} are not recorded: {}", island_id, e),
+            }
+        }
+        let factory = self.factory.lock().unwrap_or_else(PoisonError::into_inner);
+        factory.create(island_id, island_env)
     }
 
-    if !settings.network.global_sync.sync {
-        dispatcher_tx
-            .send(DispatcherMessage::Info(Message::FinSim))
-            .unwrap();
-        collector_tx.send(Message::FinSim).unwrap();
+    fn pace(
+        &self,
+        island_id: Uuid,
+        staleness: u32,
+        clock: TurnClock,
+        first_turn: TurnNumber,
+    ) -> Pace {
+        // Progress goes to all islands, so no random stream is drawn from
+        let mut address_book = AddressBook::new(
+            mpsc::Sender::clone(&self.dispatcher_tx),
+            Arc::clone(&self.local_islands),
+            Some(island_id),
+            self.host_seed,
+        );
+        if self.loopback {
+            address_book = address_book.with_loopback();
+        }
+        Pace::new(
+            island_id,
+            staleness,
+            clock,
+            self.map.clone(),
+            address_book,
+            first_turn,
+        )
     }
 }
 
 fn run_with_global_sync(
     mut island: Box<dyn Island>,
+    mut island_id: Uuid,
     island_rx: Receiver<Message>,
+    resume: Resume,
+    checkpointer: Option<Checkpointer>,
     island_sync: Option<Arc<Barrier>>,
-    dispatcher_tx: Sender<DispatcherMessage>,
-) {
-    island.on_start();
-    while let (true, turn, messages) = receive_messages_with_global_sync(&island_rx) {
-        island.do_turn(turn, messages);
+    spawner: IslandSpawner,
+) -> Finished {
+    let (resume_turn, mut pending) = start_island(island.as_mut(), resume);
+    // Only islands which can be recreated from a snapshot are moved by the server
+    let mut movable = island.snapshot().is_some();
+    let mut wait_start = Instant::now();
+    while let Some(start) = receive_messages_with_global_sync(&island_rx) {
+        let TurnStart {
+            turn,
+            save,
+            exchange,
+            mut messages,
+        } = start;
+        if !pending.is_empty() {
+            pending.append(&mut messages);
+            messages = mem::take(&mut pending);
+        }
+        if let Some((leaving, arriving)) = exchange {
+            island_id = arriving.2;
+            match exchange_island(island, leaving, arriving, messages, &island_rx, &spawner) {
+                Some((arrived, arrived_messages)) => {
+                    island = arrived;
+                    messages = arrived_messages;
+                    movable = island.snapshot().is_some();
+                }
+                None => return None,
+            }
+        }
+        // Nobody sends messages of this turn until every island has taken
+        // `NextTurn`, otherwise they could be received a turn too early
         island_sync.as_ref().map(|barrier| barrier.wait());
-        dispatcher_tx
-            .send(DispatcherMessage::Info(Message::TurnDone))
-            .unwrap();
+        let label = utils::short_id(&island_id);
+        metrics::observe_barrier_wait(&label, wait_start.elapsed());
+        metrics::set_turn_start(&label, turn, messages.len());
+        // Arrival order depends on thread scheduling, so it is
+        // made canonical to keep synchronized runs reproducible
+        if spawner.sequenced {
+            messages = address_book::in_send_order(messages);
+        }
+        if let Some(checkpointer) = checkpointer.as_ref() {
+            if save && resume_turn != Some(turn) {
+                save_checkpoint(checkpointer, island_id, turn, island.as_ref(), &messages);
+            }
+        }
+        let turn_start = Instant::now();
+        island.do_turn(turn, messages);
+        let turn_time = turn_start.elapsed();
+        metrics::observe_turn_duration(&label, turn_time);
+        let load = IslandLoad {
+            island_id,
+            turn_us: turn_time.as_micros() as u64,
+            agents: island.agent_count(),
+            movable,
+        };
+        if spawner
+            .dispatcher_tx
+            .send(DispatcherMessage::Info(Message::TurnDone(vec![load])))
+            .is_err()
+        {
+            log::warn!("Dispatcher already finished, waiting for FinSim");
+        }
+        wait_start = Instant::now();
     }
     island.on_finish();
+    island.result().map(|value| (island_id, value))
+}
+
+/// Sends the island with its messages to the host of `arriving` and takes
+/// `arriving` in its place. Returns `None` if the simulation finishes first.
+fn exchange_island(
+    island: Box<dyn Island>,
+    leaving: FragmentOwner,
+    arriving: FragmentOwner,
+    messages: Vec<Message>,
+    island_rx: &Receiver<Message>,
+    spawner: &IslandSpawner,
+) -> Option<(Box<dyn Island>, Vec<Message>)> {
+    // The other island may have arrived before the turn was started
+    let (arrived, messages): (Vec<Message>, Vec<Message>) =
+        messages.into_iter().partition(|msg| match msg {
+            Message::IslandState(island_id, _, _) => *island_id == arriving.2,
+            _ => false,
+        });
+    let state = island.snapshot();
+    if state.is_none() {
+        log::warn!("Island {} has no snapshot, it starts anew", leaving.2);
+    }
+    let state = Message::IslandState(leaving.2, state, messages);
+    let addr = (arriving.0.clone(), arriving.1);
+    if spawner
+        .dispatcher_tx
+        .send(DispatcherMessage::Unicast(state, addr))
+        .is_err()
+    {
+        log::warn!("Dispatcher already finished, island {} is lost", leaving.2);
+    }
+
+    let mut pending = vec![];
+    for msg in arrived.into_iter().chain(island_rx.iter()) {
+        match msg {
+            Message::IslandState(island_id, state, mut messages) if island_id == arriving.2 => {
+                let id_bits = island_id.as_bytes()[..8].try_into().unwrap_or_default();
+                let island_seed = rng::derive_seed(spawner.host_seed, u64::from_le_bytes(id_bits));
+                let mut island = spawner.create(island_id, island_seed, None);
+                let restored = state.map(|state| island.restore(&state));
+                match restored {
+                    Some(Ok(())) => log::info!("Island {} moved to this host", island_id),
+                    Some(Err(e)) => {
+                        log::error!("Cannot restore moved island {}: {}", island_id, e);
+                        island.on_start();
+                    }
+                    None => island.on_start(),
+                }
+                messages.append(&mut pending);
+                return Some((island, messages));
+            }
+            Message::FinSim => break,
+            _ => pending.push(msg),
+        }
+    }
+    None
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run(
     mut island: Box<dyn Island>,
+    island_id: Uuid,
     island_rx: Receiver<Message>,
     turns: u32,
+    resume: Resume,
+    checkpointer: Option<Checkpointer>,
     island_sync: Option<Arc<Barrier>>,
-) {
-    island.on_start();
-    for turn in 0..turns {
-        let messages = island_rx.try_iter().collect();
-        island.do_turn(turn, messages);
+    mut pace: Option<Pace>,
+) -> Finished {
+    let (resume_turn, mut messages) = start_island(island.as_mut(), resume);
+    let first_turn = resume_turn.unwrap_or(0);
+    let label = utils::short_id(&island_id);
+    // Islands neither synchronized nor paced do not wait at all
+    let waits = island_sync.is_some() || pace.is_some();
+    let mut wait_start = Instant::now();
+    for turn in first_turn..turns {
+        match pace.as_mut() {
+            Some(pace) => match pace.wait_for_turn(&island_rx, turn) {
+                Some(mut received) => messages.append(&mut received),
+                None => break,
+            },
+            None => messages.extend(island_rx.try_iter()),
+        }
+        if waits {
+            metrics::observe_barrier_wait(&label, wait_start.elapsed());
+        }
+        metrics::set_turn_start(&label, turn, messages.len());
+        if let Some(checkpointer) = checkpointer.as_ref() {
+            if checkpointer.is_due(turn) && turn != first_turn {
+                save_checkpoint(checkpointer, island_id, turn, island.as_ref(), &messages);
+            }
+        }
+        let turn_start = Instant::now();
+        island.do_turn(turn, mem::take(&mut messages));
+        metrics::observe_turn_duration(&label, turn_start.elapsed());
+        if let Some(pace) = pace.as_mut() {
+            pace.finish_turn(turn);
+        }
+        wait_start = Instant::now();
         island_sync.as_ref().map(|barrier| barrier.wait());
     }
     island.on_finish();
+    island.result().map(|value| (island_id, value))
 }
 
-type NextTurn = bool;
-type Turn = u32;
-fn receive_messages_with_global_sync(rx: &Receiver<Message>) -> (NextTurn, Turn, Vec<Message>) {
+/// Calls [`Island::on_start`] unless the island was restored, in which case
+/// returns the turn it resumes from and messages saved for that turn.
+fn start_island(island: &mut dyn Island, resume: Resume) -> (Option<TurnNumber>, Vec<Message>) {
+    match resume {
+        Some((turn, messages)) => (Some(turn), messages),
+        None => {
+            island.on_start();
+            (None, vec![])
+        }
+    }
+}
+
+/// A failed checkpoint is not a reason to stop the simulation.
+fn save_checkpoint(
+    checkpointer: &Checkpointer,
+    island_id: Uuid,
+    turn: TurnNumber,
+    island: &dyn Island,
+    messages: &[Message],
+) {
+    if let Err(e) = checkpointer.save(island_id, turn, island, messages) {
+        log::error!("Cannot checkpoint island {}: {}", island_id, e);
+    }
+}
+
+/// Start of a turn received from the server.
+struct TurnStart {
+    turn: TurnNumber,
+    save: bool,
+    /// The island moves to another host before the turn.
+    exchange: Option<(FragmentOwner, FragmentOwner)>,
+    messages: Vec<Message>,
+}
+
+fn receive_messages_with_global_sync(rx: &Receiver<Message>) -> Option<TurnStart> {
     let mut msg_queue = vec![];
-    let mut next_turn = false;
-    let mut fin_sim = false;
-    let mut current_turn = 0;
-    while !next_turn && !fin_sim {
-        let messages = rx.try_iter();
-        for msg in messages {
-            match msg {
-                Message::NextTurn(turn_number) => {
-                    current_turn = turn_number;
-                    next_turn = true
-                }
-                Message::FinSim => fin_sim = true,
-                _ => msg_queue.push(msg),
+    let mut save = false;
+    let mut exchange = None;
+    // Blocks until the turn is started or finished. Disconnected channel
+    // means that the collector is gone so there will be no more turns.
+    for msg in rx.iter() {
+        match msg {
+            Message::NextTurn(turn) => {
+                return Some(TurnStart {
+                    turn,
+                    save,
+                    exchange,
+                    messages: msg_queue,
+                })
             }
+            Message::Checkpoint(_) => save = true,
+            Message::Exchange(leaving, arriving) => exchange = Some((leaving, arriving)),
+            Message::FinSim => break,
+            _ => msg_queue.push(msg),
         }
     }
-    (!fin_sim, current_turn as u32, msg_queue)
+    None
 }
 
 fn create_channels(islands_number: u32) -> (Vec<Sender<Message>>, Vec<Receiver<Message>>) {
@@ -260,66 +994,224 @@ fn create_channels(islands_number: u32) -> (Vec<Sender<Message>>, Vec<Receiver<M
     (txes, rxes)
 }
 
-fn create_island_ids(islands_number: u32) -> Vec<Uuid> {
+/// Restored islands keep their ids, so messages and map fragments
+/// addressed to them still reach them.
+fn create_island_ids(
+    islands_number: u32,
+    checkpoints: &[IslandCheckpoint],
+    host_seed: u64,
+) -> Vec<Uuid> {
+    if !checkpoints.is_empty() {
+        return checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.island_id)
+            .collect();
+    }
+    let mut id_generator = IdGenerator::new(rng::derive_seed(host_seed, rng::ISLAND_IDS_STREAM));
     let mut island_ids = Vec::<Uuid>::new();
     for _ in 0..islands_number {
-        island_ids.push(Uuid::new_v4());
+        island_ids.push(id_generator.next_id());
     }
     island_ids
 }
 
-fn create_address_book(
-    txes: &[Sender<Message>],
-    island_ids: &[Uuid],
-    island_no: i32,
+/// Sends ids of local islands to the coordinator until it answers with
+/// map owners. The first message can be lost if the coordinator has not
+/// subscribed to us yet.
+fn receive_map_owners(
+    coll_nt_ctx: &CollectorNetworkCtx,
     dispatcher_tx: &Sender<DispatcherMessage>,
-) -> AddressBook {
-    let mut txes_cp = txes.to_owned();
-    txes_cp.remove(island_no as usize);
-    let mut island_ids_cp = island_ids.to_owned();
-    island_ids_cp.remove(island_no as usize);
+    island_ids: Vec<Uuid>,
+    coord_addr: Addr,
+) -> Result<MapOwners> {
+    loop {
+        dispatcher_tx.send(DispatcherMessage::Unicast(
+            Message::Islands(island_ids.clone()),
+            coord_addr.clone(),
+        ))?;
+        while network::poll_readable(&coll_nt_ctx.sub_sock, network::HANDSHAKE_RETRY_MS)? {
+            match network::recv_ps(&coll_nt_ctx.sub_sock, coll_nt_ctx.codec) {
+                Ok((_, _, Message::Owners(owners))) => return Ok(owners),
+                Ok(_) => continue,
+                Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+                Err(e) => log::warn!("Dropping message while waiting for map owners: {}", e),
+            }
+        }
+    }
+}
 
-    AddressBook {
-        dispatcher_tx: mpsc::Sender::clone(dispatcher_tx),
-        addresses: txes_cp,
-        islands: island_ids_cp,
+/// Asks the server to admit this host to the running simulation and waits
+/// until it publishes hosts and map owners including islands of this host.
+/// The server admits new hosts between turns.
+fn join_simulation(
+    coll_nt_ctx: &mut CollectorNetworkCtx,
+    dispatcher_tx: &Sender<DispatcherMessage>,
+    sim_rx: &Receiver<Message>,
+    island_ids: Vec<Uuid>,
+    host_addr: Addr,
+) -> Result<MapOwners> {
+    let (host_ip, host_port) = host_addr;
+    log::info!("Joining running simulation");
+    dispatcher_tx.send(DispatcherMessage::Server(Message::Join(
+        host_ip.clone(),
+        host_port,
+        island_ids.clone(),
+    )))?;
+    loop {
+        if let Ok(Message::Err) = sim_rx.try_recv() {
+            return Err(RampError::Aborted(String::from(
+                "server did not admit this host",
+            )));
+        }
+        if !network::poll_readable(&coll_nt_ctx.sub_sock, network::HANDSHAKE_RETRY_MS)? {
+            continue;
+        }
+        match network::recv_ps(&coll_nt_ctx.sub_sock, coll_nt_ctx.codec) {
+            Ok((_, _, Message::IpTable(ip_table))) => {
+                network::update_peers(
+                    &coll_nt_ctx.sub_sock,
+                    &mut coll_nt_ctx.ip_table,
+                    ip_table,
+                    (&host_ip, host_port),
+                )?;
+                let ip_table = coll_nt_ctx.ip_table.clone();
+                dispatcher_tx.send(DispatcherMessage::Info(Message::IpTable(ip_table)))?;
+            }
+            Ok((_, _, Message::Owners(owners)))
+                if owners.values().any(|(_, _, id)| island_ids.contains(id)) =>
+            {
+                return Ok(owners)
+            }
+            Ok((_, _, Message::FinSim)) => {
+                return Err(RampError::Aborted(String::from(
+                    "simulation finished before this host joined",
+                )))
+            }
+            Ok(_) => continue,
+            Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+            Err(e) => log::warn!("Dropping message while joining: {}", e),
+        }
     }
 }
 
 fn create_map_owners(
-    coll_sub_sock: &Socket,
+    coll_nt_ctx: &CollectorNetworkCtx,
     island_ids: Vec<Uuid>,
     hosts_num: u32,
     ip_table: Vec<(String, network::Port)>,
     map_size: i64,
-) -> MapOwners {
-    let mut owners: MapOwners = HashMap::new();
-    let mut ip_islands: HashMap<String, Vec<Uuid>> = HashMap::new();
-    for _ in 0..hosts_num - 1 {
-        if hosts_num == 1 {
-            break;
-        }
-        let (_, from, msg) = network::recv_ps(coll_sub_sock);
-        if let Message::Islands(island_ids) = msg {
-            ip_islands.insert(from, island_ids);
+) -> Result<MapOwners> {
+    let mut host_islands: HashMap<String, Vec<Uuid>> = HashMap::new();
+    // Hosts resend their islands until they get owners, so duplicates are expected
+    while host_islands.len() < hosts_num as usize - 1 {
+        match network::recv_ps(&coll_nt_ctx.sub_sock, coll_nt_ctx.codec) {
+            Ok((_, from, Message::Islands(island_ids))) => {
+                host_islands.insert(from, island_ids);
+            }
+            Ok(_) => continue,
+            Err(RampError::Network(e)) => return Err(RampError::Network(e)),
+            Err(e) => log::warn!("Dropping message while waiting for islands: {}", e),
         }
     }
+
+    let hosts = ip_table
+        .into_iter()
+        .map(|(addr, port)| {
+            let islands = host_islands
+                .get(&network::host_key(&addr, port))
+                .unwrap_or(&island_ids)
+                .clone();
+            ((addr, port), islands)
+        })
+        .collect();
+    Ok(assign_fragments(hosts, map_size))
+}
+
+/// Assigns consecutive map fragments to islands in order of their ids,
+/// so an island restored on another host keeps its fragment.
+fn assign_fragments(hosts: Vec<(Addr, Vec<Uuid>)>, map_size: i64) -> MapOwners {
+    let mut islands: Vec<(Addr, Uuid)> = hosts
+        .into_iter()
+        .flat_map(|(addr, islands)| {
+            islands
+                .into_iter()
+                .map(move |island| (addr.clone(), island))
+        })
+        .collect();
+    islands.sort_by_key(|(_, island)| *island);
+
+    let mut owners: MapOwners = HashMap::new();
     let mut start = 0;
     let mut end = map_size * map_size;
 
-    for (addr, port) in ip_table {
-        let islands = ip_islands.get(&addr).unwrap_or(&island_ids);
-        for island in islands {
-            let fragment = Fragment {
-                start,
-                end: end.try_into().unwrap(),
-            };
-            let owner = (addr.clone(), port, *island);
+    for ((addr, port), island) in islands {
+        let fragment = Fragment {
+            start,
+            end: end.try_into().unwrap(),
+        };
+        let owner = (addr, port, island);
 
-            owners.insert(fragment, owner);
-            start = end.try_into().unwrap();
-            end += map_size * map_size;
-        }
+        owners.insert(fragment, owner);
+        start = end.try_into().unwrap();
+        end += map_size * map_size;
     }
     owners
 }
+
+fn create_map(settings: &ClientSettings, owners: MapOwners, topology: Arc<dyn Topology>) -> Map {
+    Map::new(settings.map.chunk_len, owners)
+        .with_halo(settings.map.halo.unwrap_or(0))
+        .with_partitioner(settings.map.partitioner())
+        .with_topology(topology)
+}
+
+fn check_map_settings(settings: &ClientSettings) -> Result<()> {
+    let invalid = |reason: String| Err(RampError::Settings(ConfigError::Message(reason)));
+    let map = &settings.map;
+    let halo = map.halo.unwrap_or(0);
+    if halo < 0 || halo > map.chunk_len {
+        return invalid(format!(
+            "map halo must be between 0 and chunk_len, got {}",
+            halo
+        ));
+    }
+    if map.columns.unwrap_or(1) < 1 {
+        return invalid(String::from("map needs at least one column"));
+    }
+    let hosts = settings.network.as_ref().map_or(1, |nt| nt.hosts_num);
+    let cells = u64::from(settings.islands * hosts) * (map.chunk_len * map.chunk_len) as u64;
+    match map.partitioner().capacity() {
+        Some(capacity) if capacity < cells => invalid(format!(
+            "map holds {} cells, {} needed for all islands",
+            capacity, cells
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_turn_settings(settings: &ClientSettings) -> Result<()> {
+    let global_sync = settings
+        .network
+        .as_ref()
+        .is_some_and(|network| network.global_sync.sync);
+    if settings.staleness.is_some() && (settings.islands_sync || global_sync) {
+        return Err(RampError::Settings(ConfigError::Message(String::from(
+            "staleness needs islands_sync and global sync turned off",
+        ))));
+    }
+    Ok(())
+}
+
+/// Loads checkpoints of islands which should run on this host.
+fn load_checkpoints(path: &str, islands: u32) -> Result<Vec<IslandCheckpoint>> {
+    let checkpoints = checkpoint::load(Path::new(path))?;
+    if checkpoints.len() != islands as usize {
+        return Err(RampError::Checkpoint(format!(
+            "found {} islands in {}, expected {}",
+            checkpoints.len(),
+            path,
+            islands
+        )));
+    }
+    Ok(checkpoints)
+}
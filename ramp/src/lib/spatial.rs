@@ -0,0 +1,89 @@
+//! Index of agents by the cell they are in.
+//!
+//! [`crate::map::MapInstance`] keeps an [`AgentIndex`] of agents on its
+//! fragment when created with [`crate::map::MapInstance::with_agent_index`],
+//! so agents at a cell are found without scanning all of them.
+
+use crate::topology::Position;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Default)]
+pub struct AgentIndex {
+    positions: HashMap<Uuid, Position>,
+    /// Agents of a cell in the order they entered it.
+    cells: HashMap<Position, Vec<Uuid>>,
+}
+
+impl AgentIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Puts the agent at `pos`. Returns its previous position.
+    pub fn insert(&mut self, id: Uuid, pos: Position) -> Option<Position> {
+        let previous = self.remove(id);
+        self.positions.insert(id, pos);
+        self.cells.entry(pos).or_default().push(id);
+        previous
+    }
+
+    /// Drops the agent from the index. Returns its last position.
+    pub fn remove(&mut self, id: Uuid) -> Option<Position> {
+        let pos = self.positions.remove(&id)?;
+        if let Some(agents) = self.cells.get_mut(&pos) {
+            agents.retain(|agent| *agent != id);
+            if agents.is_empty() {
+                self.cells.remove(&pos);
+            }
+        }
+        Some(pos)
+    }
+
+    pub fn position_of(&self, id: Uuid) -> Option<Position> {
+        self.positions.get(&id).copied()
+    }
+
+    pub fn agents_at(&self, pos: Position) -> &[Uuid] {
+        self.cells.get(&pos).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AgentIndex;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_agents_keep_order_of_arrival() {
+        let mut index = AgentIndex::new();
+        let agents: Vec<_> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for agent in agents.iter() {
+            index.insert(*agent, (1, 1));
+        }
+        assert_eq!(index.agents_at((1, 1)), agents.as_slice());
+        assert!(index.agents_at((0, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_moved_agent_leaves_cell() {
+        let mut index = AgentIndex::new();
+        let agent = Uuid::new_v4();
+        assert_eq!(index.insert(agent, (0, 0)), None);
+        assert_eq!(index.insert(agent, (0, 1)), Some((0, 0)));
+        assert!(index.agents_at((0, 0)).is_empty());
+        assert_eq!(index.agents_at((0, 1)), &[agent]);
+
+        assert_eq!(index.remove(agent), Some((0, 1)));
+        assert_eq!(index.remove(agent), None);
+        assert!(index.is_empty());
+    }
+}
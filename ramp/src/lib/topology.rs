@@ -0,0 +1,206 @@
+//! Neighbours of map cells.
+//!
+//! A [`Topology`] decides which cell is next to another one:
+//! * [`Bounded`] - cells at the edges of the map have no neighbours
+//!   outside of it,
+//! * [`Torus`] - the map wraps around, so cells at opposite edges are
+//!   neighbours,
+//! * [`Graph`] - explicit links between cells, e.g. walls or portals,
+//!   on top of another topology.
+
+use crate::partition::BoundingBox;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Cell `(x, y)` of the map.
+pub type Position = (i64, i64);
+
+/// Direction of a move to a neighbouring cell. `y` grows towards the south.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+
+impl Direction {
+    /// Directions sharing an edge with the cell.
+    pub const CARDINAL: [Direction; 4] = [
+        Direction::North,
+        Direction::East,
+        Direction::South,
+        Direction::West,
+    ];
+
+    /// All directions, including diagonals.
+    pub const ALL: [Direction; 8] = [
+        Direction::North,
+        Direction::NorthEast,
+        Direction::East,
+        Direction::SouthEast,
+        Direction::South,
+        Direction::SouthWest,
+        Direction::West,
+        Direction::NorthWest,
+    ];
+
+    /// Change of `x` and `y` after a move in this direction.
+    pub fn delta(self) -> (i64, i64) {
+        match self {
+            Direction::North => (0, -1),
+            Direction::NorthEast => (1, -1),
+            Direction::East => (1, 0),
+            Direction::SouthEast => (1, 1),
+            Direction::South => (0, 1),
+            Direction::SouthWest => (-1, 1),
+            Direction::West => (-1, 0),
+            Direction::NorthWest => (-1, -1),
+        }
+    }
+}
+
+pub trait Topology: fmt::Debug + Send + Sync {
+    /// Cell next to `pos` in direction `dir` on a map covering `extent`,
+    /// `None` if there is no such cell.
+    fn neighbour(&self, pos: Position, dir: Direction, extent: &BoundingBox) -> Option<Position>;
+}
+
+/// Map with edges, cells outside of it are never neighbours.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bounded;
+
+impl Topology for Bounded {
+    fn neighbour(&self, pos: Position, dir: Direction, extent: &BoundingBox) -> Option<Position> {
+        let (dx, dy) = dir.delta();
+        let next = (pos.0 + dx, pos.1 + dy);
+        if extent.contains(next.0, next.1) {
+            Some(next)
+        } else {
+            None
+        }
+    }
+}
+
+/// Map wrapping around at its edges.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Torus;
+
+impl Topology for Torus {
+    fn neighbour(&self, pos: Position, dir: Direction, extent: &BoundingBox) -> Option<Position> {
+        if !extent.contains(pos.0, pos.1) {
+            return None;
+        }
+        let wrap = |value: i64, range: &std::ops::Range<i64>| {
+            range.start + (value - range.start).rem_euclid(range.end - range.start)
+        };
+        let (dx, dy) = dir.delta();
+        Some((wrap(pos.0 + dx, &extent.x), wrap(pos.1 + dy, &extent.y)))
+    }
+}
+
+/// Links between cells overriding neighbours given by another topology.
+#[derive(Debug, Clone)]
+pub struct Graph {
+    base: Arc<dyn Topology>,
+    links: HashMap<(Position, Direction), Option<Position>>,
+}
+
+impl Graph {
+    pub fn new(base: Arc<dyn Topology>) -> Self {
+        Graph {
+            base,
+            links: HashMap::new(),
+        }
+    }
+
+    /// Makes `to` the neighbour of `from` in direction `dir`.
+    pub fn link(mut self, from: Position, dir: Direction, to: Position) -> Self {
+        self.links.insert((from, dir), Some(to));
+        self
+    }
+
+    /// Leaves `from` without a neighbour in direction `dir`.
+    pub fn block(mut self, from: Position, dir: Direction) -> Self {
+        self.links.insert((from, dir), None);
+        self
+    }
+}
+
+impl Topology for Graph {
+    fn neighbour(&self, pos: Position, dir: Direction, extent: &BoundingBox) -> Option<Position> {
+        match self.links.get(&(pos, dir)) {
+            Some(link) => *link,
+            None => self.base.neighbour(pos, dir, extent),
+        }
+    }
+}
+
+/// Topology selected in settings, bounded when missing. A [`Graph`] is
+/// given to [`crate::simulation::SimulationBuilder::topology`] instead.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TopologyKind {
+    #[default]
+    Bounded,
+    Torus,
+}
+
+impl TopologyKind {
+    pub fn build(self) -> Arc<dyn Topology> {
+        match self {
+            TopologyKind::Bounded => Arc::new(Bounded),
+            TopologyKind::Torus => Arc::new(Torus),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bounded, Direction, Graph, Topology, Torus};
+    use crate::partition::BoundingBox;
+    use std::sync::Arc;
+
+    fn extent() -> BoundingBox {
+        BoundingBox { x: 0..4, y: 0..8 }
+    }
+
+    #[test]
+    fn test_edges() {
+        assert_eq!(Bounded.neighbour((3, 0), Direction::East, &extent()), None);
+        assert_eq!(
+            Bounded.neighbour((3, 0), Direction::South, &extent()),
+            Some((3, 1))
+        );
+        assert_eq!(
+            Torus.neighbour((3, 0), Direction::East, &extent()),
+            Some((0, 0))
+        );
+        assert_eq!(
+            Torus.neighbour((0, 0), Direction::NorthWest, &extent()),
+            Some((3, 7))
+        );
+        assert_eq!(Torus.neighbour((4, 0), Direction::West, &extent()), None);
+    }
+
+    #[test]
+    fn test_graph_overrides_base() {
+        let graph = Graph::new(Arc::new(Bounded))
+            .link((3, 0), Direction::East, (0, 7))
+            .block((1, 1), Direction::South);
+        assert_eq!(
+            graph.neighbour((3, 0), Direction::East, &extent()),
+            Some((0, 7))
+        );
+        assert_eq!(graph.neighbour((1, 1), Direction::South, &extent()), None);
+        assert_eq!(
+            graph.neighbour((1, 1), Direction::North, &extent()),
+            Some((1, 0))
+        );
+    }
+}
@@ -1,12 +1,12 @@
-use flexi_logger::Logger;
+use flexi_logger::{FlexiLoggerError, Logger};
 use std::env;
 use uuid::Uuid;
 
-pub fn init_logger(logger_lever: &str) {
-    Logger::with_str(logger_lever)
+pub fn init_logger(logger_level: &str) -> Result<(), FlexiLoggerError> {
+    Logger::with_str(logger_level)
         .format_for_stderr(flexi_logger::colored_default_format)
         .start()
-        .unwrap();
+        .map(|_| ())
 }
 
 pub fn parse_input_args(expected_args_num: usize) -> Vec<String> {
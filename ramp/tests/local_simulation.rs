@@ -0,0 +1,624 @@
+use ramp::error::RampError;
+use ramp::island::{Island, IslandEnv, IslandFactory};
+use ramp::map::MapInstance;
+use ramp::message::Message;
+use ramp::migration::{MigrationEnv, MigrationTarget};
+use ramp::outcome::{IslandResult, Reducer};
+use ramp::partition::PartitionerKind;
+use ramp::settings::{CheckpointSettings, ClientSettings, MapSettings, ResultsSettings};
+use ramp::simulation::SimulationBuilder;
+use ramp::topology::{Direction, TopologyKind};
+use rand::Rng;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+const TURNS: u32 = 20;
+const ISLANDS: u32 = 3;
+const STALENESS: u32 = 1;
+
+struct CountingIsland {
+    island_env: MigrationEnv<()>,
+    turns: Arc<AtomicU32>,
+    received: Arc<AtomicU32>,
+    restored: Arc<AtomicU32>,
+}
+
+impl Island for CountingIsland {
+    fn on_start(&mut self) {}
+
+    fn do_turn(&mut self, turn_number: u32, messages: Vec<Message>) {
+        self.turns.fetch_add(1, Ordering::SeqCst);
+        self.received
+            .fetch_add(messages.len() as u32, Ordering::SeqCst);
+        self.island_env
+            .recorder()
+            .record(turn_number, "received", messages.len() as f64);
+        self.island_env
+            .migrate(&(), MigrationTarget::RandomGlobal)
+            .unwrap();
+    }
+
+    fn on_finish(&mut self) {}
+
+    fn result(&self) -> Option<Vec<u8>> {
+        self.island_env
+            .encode_result(&self.turns.load(Ordering::SeqCst))
+            .ok()
+    }
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(vec![])
+    }
+
+    fn restore(&mut self, _snapshot: &[u8]) -> Result<(), RampError> {
+        self.restored.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct CountingIslandFactory {
+    turns: Arc<AtomicU32>,
+    received: Arc<AtomicU32>,
+    restored: Arc<AtomicU32>,
+}
+
+impl IslandFactory for CountingIslandFactory {
+    fn create(&self, _island_id: Uuid, island_env: IslandEnv) -> Box<dyn Island> {
+        Box::new(CountingIsland {
+            island_env: MigrationEnv::new(island_env, "counter"),
+            turns: Arc::clone(&self.turns),
+            received: Arc::clone(&self.received),
+            restored: Arc::clone(&self.restored),
+        })
+    }
+}
+
+/// Hosts of the islands which returned results.
+#[derive(Default)]
+struct HostsReducer {
+    hosts: Arc<Mutex<Vec<String>>>,
+}
+
+impl Reducer for HostsReducer {
+    type Value = u32;
+
+    fn reduce(&self, results: &[IslandResult<u32>]) -> String {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.extend(results.iter().map(|result| result.host.clone()));
+        format!("{} islands", results.len())
+    }
+}
+
+type Trace = Arc<Mutex<Vec<(Uuid, u32, u64, Vec<Uuid>)>>>;
+
+/// Records everything which should be the same in runs with the same seed.
+struct TracingIsland {
+    island_id: Uuid,
+    island_env: MigrationEnv<Uuid>,
+    trace: Trace,
+}
+
+impl Island for TracingIsland {
+    fn on_start(&mut self) {}
+
+    fn do_turn(&mut self, turn_number: u32, mut messages: Vec<Message>) {
+        let draw = self.island_env.rng().gen();
+        let agent_id = self.island_env.new_id();
+        let agents = self
+            .island_env
+            .received_migrants(&mut messages)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert!(messages.is_empty());
+        self.trace
+            .lock()
+            .unwrap()
+            .push((self.island_id, turn_number, draw, agents));
+        let _ = self
+            .island_env
+            .migrate(&agent_id, MigrationTarget::RandomLocal);
+        self.island_env
+            .migrate(&agent_id, MigrationTarget::RandomGlobal)
+            .unwrap();
+    }
+
+    fn on_finish(&mut self) {}
+}
+
+/// Creates islands with a closure.
+struct FnFactory<F>(F);
+
+impl<F> IslandFactory for FnFactory<F>
+where
+    F: Fn(Uuid, IslandEnv) -> Box<dyn Island> + Send,
+{
+    fn create(&self, island_id: Uuid, island_env: IslandEnv) -> Box<dyn Island> {
+        (self.0)(island_id, island_env)
+    }
+}
+
+type MapTurn = Arc<dyn Fn(&mut MapInstance<i32>, &mut IslandEnv, u32) + Send + Sync>;
+
+/// Fills its fragment with the number of the fragment and plays turns
+/// of the test once messages of the map are handled.
+struct MapIsland {
+    island_env: IslandEnv,
+    map: Option<MapInstance<i32>>,
+    turn: MapTurn,
+}
+
+impl Island for MapIsland {
+    fn on_start(&mut self) {
+        let mut map = MapInstance::get_instance(&self.island_env);
+        let start = map.get_my_range().unwrap().start;
+        map.init_with_val(fragment_number(start)).unwrap();
+        self.map = Some(map);
+    }
+
+    fn do_turn(&mut self, turn_number: u32, mut messages: Vec<Message>) {
+        let map = self.map.as_mut().unwrap();
+        map.handle_messages(&mut self.island_env, &mut messages);
+        assert!(messages.is_empty());
+        (self.turn)(map, &mut self.island_env, turn_number);
+        map.flush(&mut self.island_env);
+    }
+
+    fn on_finish(&mut self) {}
+}
+
+fn fragment_number(offset: u64) -> i32 {
+    (offset / 4) as i32
+}
+
+fn map_islands<F>(turn: F) -> Box<dyn IslandFactory>
+where
+    F: Fn(&mut MapInstance<i32>, &mut IslandEnv, u32) + Send + Sync + 'static,
+{
+    let turn: MapTurn = Arc::new(turn);
+    Box::new(FnFactory(move |_, island_env| {
+        Box::new(MapIsland {
+            island_env,
+            map: None,
+            turn: Arc::clone(&turn),
+        }) as Box<dyn Island>
+    }))
+}
+
+type Started = Arc<Mutex<HashMap<Uuid, u32>>>;
+
+/// Checks that no island gets too far ahead of the others and
+/// that migrants never arrive in the turn they were sent in.
+struct PacedIsland {
+    island_id: Uuid,
+    island_env: MigrationEnv<u32>,
+    started: Started,
+    violations: Arc<AtomicU32>,
+}
+
+impl Island for PacedIsland {
+    fn on_start(&mut self) {}
+
+    fn do_turn(&mut self, turn_number: u32, mut messages: Vec<Message>) {
+        let mut started = self.started.lock().unwrap();
+        // An island starts a turn once the others have finished (and so
+        // started) the turn `STALENESS + 1` turns before it
+        let others: Vec<i64> = started
+            .iter()
+            .filter(|(id, _)| **id != self.island_id)
+            .map(|(_, turn)| i64::from(*turn))
+            .collect();
+        // Islands which have not started yet are at turn -1
+        let slowest = if others.len() < ISLANDS as usize - 1 {
+            -1
+        } else {
+            others.into_iter().min().unwrap_or(-1)
+        };
+        if slowest + i64::from(STALENESS) + 1 < i64::from(turn_number) {
+            self.violations.fetch_add(1, Ordering::SeqCst);
+        }
+        started.insert(self.island_id, turn_number);
+        drop(started);
+
+        for sent in self.island_env.received_migrants(&mut messages) {
+            if sent.unwrap() >= turn_number {
+                self.violations.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let _ = self
+            .island_env
+            .migrate(&turn_number, MigrationTarget::RandomLocal);
+    }
+
+    fn on_finish(&mut self) {}
+}
+
+fn paced_islands(started: &Started, violations: &Arc<AtomicU32>) -> Box<dyn IslandFactory> {
+    let (started, violations) = (Arc::clone(started), Arc::clone(violations));
+    Box::new(FnFactory(move |island_id, island_env| {
+        Box::new(PacedIsland {
+            island_id,
+            island_env: MigrationEnv::new(island_env, "value"),
+            started: Arc::clone(&started),
+            violations: Arc::clone(&violations),
+        }) as Box<dyn Island>
+    }))
+}
+
+fn local_settings() -> ClientSettings {
+    ClientSettings {
+        turns: TURNS,
+        islands: ISLANDS,
+        islands_sync: true,
+        map: MapSettings {
+            chunk_len: 2,
+            halo: None,
+            partitioner: None,
+            columns: None,
+            topology: None,
+        },
+        network: None,
+        checkpoint: None,
+        results: None,
+        staleness: None,
+        seed: None,
+    }
+}
+
+#[test]
+fn test_local_simulation_runs_all_turns() {
+    let factory = CountingIslandFactory::default();
+    let turns = Arc::clone(&factory.turns);
+    let received = Arc::clone(&factory.received);
+
+    SimulationBuilder::new()
+        .settings(local_settings())
+        .factory(Box::new(factory))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    assert_eq!(turns.load(Ordering::SeqCst), TURNS * ISLANDS);
+    assert!(received.load(Ordering::SeqCst) > 0);
+}
+
+#[test]
+fn test_build_without_factory() {
+    let result = SimulationBuilder::new().settings(local_settings()).build();
+    match result {
+        Err(RampError::MissingFactory) => (),
+        _ => panic!("Expected missing factory error"),
+    }
+}
+
+#[test]
+fn test_restore_from_checkpoint() {
+    let dir = env::temp_dir().join(format!("ramp-restore-{}", Uuid::new_v4()));
+    let mut settings = local_settings();
+    settings.checkpoint = Some(CheckpointSettings {
+        interval: 5,
+        dir: dir.to_str().unwrap().to_owned(),
+    });
+    SimulationBuilder::new()
+        .settings(settings)
+        .factory(Box::new(CountingIslandFactory::default()))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    let factory = CountingIslandFactory::default();
+    let turns = Arc::clone(&factory.turns);
+    let restored = Arc::clone(&factory.restored);
+    let result = SimulationBuilder::new()
+        .settings(local_settings())
+        .factory(Box::new(factory))
+        .restore_from(dir.join("turn-000010").to_str().unwrap())
+        .build()
+        .and_then(|simulation| simulation.run());
+    fs::remove_dir_all(&dir).unwrap();
+
+    result.unwrap();
+    assert_eq!(restored.load(Ordering::SeqCst), ISLANDS);
+    // The checkpointed turn is played again
+    assert_eq!(turns.load(Ordering::SeqCst), (TURNS - 10 + 1) * ISLANDS);
+}
+
+#[test]
+fn test_results_are_recorded_every_turn() {
+    let dir = env::temp_dir().join(format!("ramp-results-{}", Uuid::new_v4()));
+    let mut settings = local_settings();
+    settings.results = Some(ResultsSettings {
+        dir: dir.to_str().unwrap().to_owned(),
+        merged: Some(true),
+    });
+    let result = SimulationBuilder::new()
+        .settings(settings)
+        .factory(Box::new(CountingIslandFactory::default()))
+        .build()
+        .and_then(|simulation| simulation.run());
+    let content = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+        .collect::<Vec<_>>();
+    fs::remove_dir_all(&dir).unwrap();
+
+    result.unwrap();
+    assert_eq!(content.len(), 1);
+    let lines: Vec<&str> = content[0].lines().collect();
+    assert_eq!(lines[0], "host,island_id,turn,name,value");
+    assert_eq!(lines.len() as u32, 1 + TURNS * ISLANDS);
+    assert!(lines[1..].iter().all(|line| line.contains(",received,")));
+}
+
+#[test]
+fn test_results_of_islands_are_reduced() {
+    let reducer = HostsReducer::default();
+    let hosts = Arc::clone(&reducer.hosts);
+    SimulationBuilder::new()
+        .settings(local_settings())
+        .factory(Box::new(CountingIslandFactory::default()))
+        .reducer(reducer)
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    let hosts = hosts.lock().unwrap();
+    assert_eq!(hosts.len() as u32, ISLANDS);
+    assert!(hosts.iter().all(|host| host == "127.0.0.1:0"));
+}
+
+fn traced_run(seed: u64) -> Vec<(Uuid, u32, u64, Vec<Uuid>)> {
+    let trace = Trace::default();
+    let recorded = Arc::clone(&trace);
+    let mut settings = local_settings();
+    settings.seed = Some(seed);
+    SimulationBuilder::new()
+        .settings(settings)
+        .factory(Box::new(FnFactory(move |island_id, island_env| {
+            Box::new(TracingIsland {
+                island_id,
+                island_env: MigrationEnv::new(island_env, "tracer"),
+                trace: Arc::clone(&recorded),
+            }) as Box<dyn Island>
+        })))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    let mut trace: Vec<_> = trace.lock().unwrap().drain(..).collect();
+    trace.sort();
+    trace
+}
+
+#[test]
+fn test_seeded_runs_are_reproducible() {
+    let trace = traced_run(42);
+    assert_eq!(trace.len(), (TURNS * ISLANDS) as usize);
+    assert_eq!(trace, traced_run(42));
+    assert_ne!(trace, traced_run(43));
+}
+
+/// Every island reads a cell of the next fragment, owned by another island.
+fn reads_with(partitioner: PartitionerKind) {
+    let reads = Arc::new(Mutex::new(vec![]));
+    let recorded = Arc::clone(&reads);
+    let mut settings = local_settings();
+    settings.map.partitioner = Some(partitioner);
+    settings.map.columns = Some(2);
+    SimulationBuilder::new()
+        .settings(settings)
+        .factory(map_islands(move |map, island_env, turn_number| {
+            let next = map.get_my_range().unwrap().end % u64::from(ISLANDS * 4);
+            if turn_number == 1 {
+                let (x, y) = map.offset_to_pos(next).unwrap();
+                map.request_value(island_env, x, y).unwrap();
+            }
+            for cell in map.take_values() {
+                let expected = fragment_number(next);
+                recorded.lock().unwrap().push((expected, cell.value));
+            }
+        }))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    let reads = reads.lock().unwrap();
+    assert_eq!(reads.len(), ISLANDS as usize, "{:?}", partitioner);
+    for (expected, value) in reads.iter() {
+        assert_eq!(Some(*expected), *value, "{:?}", partitioner);
+    }
+}
+
+#[test]
+fn test_reads_of_other_fragments() {
+    reads_with(PartitionerKind::Strips);
+    reads_with(PartitionerKind::Blocks);
+    reads_with(PartitionerKind::Hilbert);
+}
+
+/// Every island writes the number of its fragment into the first cell of
+/// the next fragment and records what was written into its own first cell.
+#[test]
+fn test_batched_writes_to_other_fragments() {
+    let writes = Arc::new(Mutex::new(vec![]));
+    let recorded = Arc::clone(&writes);
+    SimulationBuilder::new()
+        .settings(local_settings())
+        .factory(map_islands(move |map, _, turn_number| {
+            let range = map.get_my_range().unwrap();
+            let fragment = fragment_number(range.start);
+            if turn_number == 1 {
+                let next = range.end % u64::from(ISLANDS * 4);
+                let (x, y) = map.offset_to_pos(next).unwrap();
+                // Only the last write to a cell is sent
+                map.set(x, y, fragment + 100).unwrap();
+                map.set(x, y, fragment).unwrap();
+            } else if turn_number == TURNS {
+                let first = map.offset_to_pos(range.start).unwrap();
+                let previous = (fragment + ISLANDS as i32 - 1) % ISLANDS as i32;
+                let written = map.get_value(first).copied();
+                recorded.lock().unwrap().push((previous, written));
+            }
+        }))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    let writes = writes.lock().unwrap();
+    assert_eq!(writes.len(), ISLANDS as usize);
+    for (expected, value) in writes.iter() {
+        assert_eq!(Some(*expected), *value);
+    }
+}
+
+#[test]
+fn test_map_larger_than_hilbert_curve() {
+    let mut settings = local_settings();
+    settings.map.partitioner = Some(PartitionerKind::Hilbert);
+    let result = SimulationBuilder::new()
+        .settings(settings)
+        .factory(Box::new(CountingIslandFactory::default()))
+        .build();
+    assert!(matches!(result, Err(RampError::Settings(_))));
+}
+
+/// Values of the first row of every fragment and of the rows just above
+/// and below it.
+fn halos_with(topology: TopologyKind) -> Vec<(Option<i32>, Option<i32>, Option<i32>)> {
+    let halos = Arc::new(Mutex::new(vec![]));
+    let recorded = Arc::clone(&halos);
+    let mut settings = local_settings();
+    settings.map.halo = Some(1);
+    settings.map.topology = Some(topology);
+    SimulationBuilder::new()
+        .settings(settings)
+        .factory(map_islands(move |map, _, turn_number| {
+            if turn_number != TURNS {
+                return;
+            }
+            let range = map.get_my_range().unwrap();
+            let (_, first_row) = map.offset_to_pos(range.start).unwrap();
+            let (_, last_row) = map.offset_to_pos(range.end - 1).unwrap();
+            let above = map.map.neighbour((0, first_row), Direction::North);
+            let below = map.map.neighbour((1, last_row), Direction::South);
+            recorded.lock().unwrap().push((
+                map.get_value((1, first_row)).copied(),
+                above.and_then(|pos| map.get_value(pos)).copied(),
+                below.and_then(|pos| map.get_value(pos)).copied(),
+            ));
+        }))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    let mut halos = halos.lock().unwrap().clone();
+    halos.sort();
+    halos
+}
+
+#[test]
+fn test_halo_mirrors_neighbouring_rows() {
+    let halos = halos_with(TopologyKind::Bounded);
+    let last = ISLANDS as i32 - 1;
+    let expected: Vec<_> = (0..=last)
+        .map(|fragment| {
+            let above = Some(fragment - 1).filter(|_| fragment > 0);
+            let below = Some(fragment + 1).filter(|_| fragment < last);
+            (Some(fragment), above, below)
+        })
+        .collect();
+    assert_eq!(halos, expected);
+}
+
+#[test]
+fn test_halo_wraps_around_torus() {
+    let halos = halos_with(TopologyKind::Torus);
+    let last = ISLANDS as i32 - 1;
+    let expected: Vec<_> = (0..=last)
+        .map(|fragment| {
+            let above = (fragment + last) % ISLANDS as i32;
+            let below = (fragment + 1) % ISLANDS as i32;
+            (Some(fragment), Some(above), Some(below))
+        })
+        .collect();
+    assert_eq!(halos, expected);
+}
+
+/// Every island writes the turn number into its cells, the ones mirrored
+/// by neighbours are from the end of the previous turn.
+#[test]
+fn test_halo_is_sent_at_the_end_of_the_turn() {
+    let seen = Arc::new(Mutex::new(vec![]));
+    let recorded = Arc::clone(&seen);
+    let mut settings = local_settings();
+    settings.map.halo = Some(1);
+    SimulationBuilder::new()
+        .settings(settings)
+        .factory(map_islands(move |map, _, turn_number| {
+            let range = map.get_my_range().unwrap();
+            for offset in range.clone() {
+                let (x, y) = map.offset_to_pos(offset).unwrap();
+                map.set(x, y, turn_number as i32).unwrap();
+            }
+            if turn_number == TURNS {
+                let (_, last_row) = map.offset_to_pos(range.end - 1).unwrap();
+                let below = map.map.neighbour((0, last_row), Direction::South);
+                recorded
+                    .lock()
+                    .unwrap()
+                    .extend(below.and_then(|pos| map.get_value(pos)).copied());
+            }
+        }))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(*seen, vec![TURNS as i32 - 1; ISLANDS as usize - 1]);
+}
+
+#[test]
+fn test_halo_wider_than_fragment() {
+    let mut settings = local_settings();
+    settings.map.halo = Some(3);
+    let result = SimulationBuilder::new()
+        .settings(settings)
+        .factory(Box::new(CountingIslandFactory::default()))
+        .build();
+    assert!(matches!(result, Err(RampError::Settings(_))));
+}
+
+#[test]
+fn test_islands_stay_within_staleness() {
+    let started = Started::default();
+    let violations = Arc::new(AtomicU32::new(0));
+    let mut settings = local_settings();
+    settings.islands_sync = false;
+    settings.staleness = Some(STALENESS);
+
+    SimulationBuilder::new()
+        .settings(settings)
+        .factory(paced_islands(&started, &violations))
+        .build()
+        .and_then(|simulation| simulation.run())
+        .unwrap();
+
+    assert_eq!(violations.load(Ordering::SeqCst), 0);
+    let started = started.lock().unwrap();
+    assert!(started.values().all(|turn| *turn == TURNS - 1));
+}
+
+#[test]
+fn test_staleness_needs_unsynchronized_islands() {
+    let mut settings = local_settings();
+    settings.staleness = Some(STALENESS);
+    let result = SimulationBuilder::new()
+        .settings(settings)
+        .factory(Box::new(CountingIslandFactory::default()))
+        .build();
+    assert!(matches!(result, Err(RampError::Settings(_))));
+}
@@ -0,0 +1,244 @@
+use ramp::balance::LoadMetric;
+use ramp::error::RampError;
+use ramp::island::{Island, IslandEnv, IslandFactory};
+use ramp::message::Message;
+use ramp::settings::{
+    ClientSettings, GlobalSyncSettings, MapSettings, NetworkSettings, SyncLeader,
+};
+use ramp::simulation::SimulationBuilder;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+const TURNS: u32 = 12;
+const ISLANDS: u32 = 2;
+const COORDINATOR_IP: &str = "127.0.0.21";
+const HOST_IP: &str = "127.0.0.22";
+const SHARED_IP: &str = "127.0.0.23";
+
+/// A turn played by an island with the number of turns it has counted.
+#[derive(Debug)]
+struct Turn {
+    host: &'static str,
+    turn: u32,
+    counted: u32,
+    agents: u64,
+}
+
+type Played = Arc<Mutex<Vec<Turn>>>;
+
+/// Counts its turns and, with a snapshot, takes the count along
+/// when it moves to another host.
+struct MovingIsland {
+    host: &'static str,
+    agents: u64,
+    movable: bool,
+    turns: u32,
+    played: Played,
+}
+
+impl Island for MovingIsland {
+    fn on_start(&mut self) {}
+
+    fn do_turn(&mut self, turn_number: u32, _messages: Vec<Message>) {
+        self.turns += 1;
+        self.played.lock().unwrap().push(Turn {
+            host: self.host,
+            turn: turn_number,
+            counted: self.turns,
+            agents: self.agents,
+        });
+    }
+
+    fn on_finish(&mut self) {}
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        if !self.movable {
+            return None;
+        }
+        let mut state = self.turns.to_le_bytes().to_vec();
+        state.extend_from_slice(&self.agents.to_le_bytes());
+        Some(state)
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), RampError> {
+        self.turns = u32::from_le_bytes(snapshot[..4].try_into().unwrap());
+        self.agents = u64::from_le_bytes(snapshot[4..].try_into().unwrap());
+        self.movable = true;
+        Ok(())
+    }
+
+    fn agent_count(&self) -> Option<u64> {
+        Some(self.agents)
+    }
+}
+
+/// Islands of a host get the given numbers of agents, the first one without
+/// a snapshot if `fixed` is set. Islands moving to the host are restored.
+struct MovingIslandFactory {
+    host: &'static str,
+    agents: Vec<u64>,
+    fixed: bool,
+    created: AtomicU32,
+    played: Played,
+}
+
+impl IslandFactory for MovingIslandFactory {
+    fn create(&self, _island_id: Uuid, _island_env: IslandEnv) -> Box<dyn Island> {
+        let created = self.created.fetch_add(1, Ordering::SeqCst) as usize;
+        Box::new(MovingIsland {
+            host: self.host,
+            agents: self.agents.get(created).copied().unwrap_or(0),
+            movable: !(self.fixed && created == 0),
+            turns: 0,
+            played: Arc::clone(&self.played),
+        })
+    }
+}
+
+/// Ports of a simulation, so that simulations of different tests
+/// do not clash.
+struct Ports {
+    coordinator_rep: u32,
+    coordinator_pub: u32,
+    host_pub: u32,
+    server_rep: u32,
+    server_pub: u32,
+    metrics: u32,
+}
+
+impl Ports {
+    fn from(base: u32) -> Ports {
+        Ports {
+            coordinator_rep: base + 1,
+            coordinator_pub: base + 2,
+            host_pub: base + 3,
+            server_rep: base + 4,
+            server_pub: base + 5,
+            metrics: base + 6,
+        }
+    }
+}
+
+fn host_settings(
+    coordinator_ip: &str,
+    host_ip: &str,
+    ports: &Ports,
+    is_coordinator: bool,
+) -> ClientSettings {
+    let (pub_port, metrics_port) = if is_coordinator {
+        (ports.coordinator_pub, ports.metrics)
+    } else {
+        (ports.host_pub, ports.metrics + 1)
+    };
+    ClientSettings {
+        turns: TURNS,
+        islands: ISLANDS,
+        islands_sync: true,
+        map: MapSettings {
+            chunk_len: 2,
+            halo: None,
+            partitioner: None,
+            columns: None,
+            topology: None,
+        },
+        network: Some(NetworkSettings {
+            is_coordinator,
+            hosts_num: 2,
+            coordinator_ip: String::from(coordinator_ip),
+            coordinator_rep_port: ports.coordinator_rep,
+            coordinator_pub_port: ports.coordinator_pub,
+            host_ip: String::from(host_ip),
+            pub_port,
+            metrics_port,
+            global_sync: GlobalSyncSettings {
+                sync: true,
+                leader: Some(SyncLeader::Coordinator),
+                server_ip: String::from(coordinator_ip),
+                server_rep_port: ports.server_rep,
+                server_pub_port: ports.server_pub,
+                server_timeout_ms: None,
+                host_timeout_ms: None,
+                turn_timeout_ms: None,
+                on_host_failure: None,
+                balance_interval: Some(4),
+                balance_tolerance: None,
+                balance_by: Some(LoadMetric::Agents),
+            },
+            join: None,
+            leave_after_turn: None,
+            codec: None,
+        }),
+        checkpoint: None,
+        results: None,
+        staleness: None,
+        seed: None,
+    }
+}
+
+fn run_host(settings: ClientSettings, factory: MovingIslandFactory) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        SimulationBuilder::new()
+            .settings(settings)
+            .factory(Box::new(factory))
+            .build()
+            .and_then(|simulation| simulation.run())
+            .unwrap()
+    })
+}
+
+/// Runs the coordinator with a heavy island and a host with light ones,
+/// then checks an island with a snapshot has moved to the host.
+fn check_islands_move(coordinator_ip: &str, host_ip: &str, ports: Ports) {
+    let played = Played::default();
+    let factory = |host, agents: &[u64], fixed| MovingIslandFactory {
+        host,
+        agents: agents.to_vec(),
+        fixed,
+        created: AtomicU32::new(0),
+        played: Arc::clone(&played),
+    };
+    // The heaviest island has no snapshot, so its neighbour has to move
+    let coordinator = run_host(
+        host_settings(coordinator_ip, coordinator_ip, &ports, true),
+        factory("coordinator", &[600, 50], true),
+    );
+    thread::sleep(Duration::from_millis(200));
+    let host = run_host(
+        host_settings(coordinator_ip, host_ip, &ports, false),
+        factory("host", &[10, 20], false),
+    );
+    coordinator.join().unwrap();
+    host.join().unwrap();
+
+    let played = played.lock().unwrap();
+    assert_eq!(played.len() as u32, 2 * ISLANDS * TURNS);
+    // Moved islands play every turn once and keep counting where they left off
+    assert!(played.iter().all(|turn| turn.counted == turn.turn));
+
+    let hosts_of = |agents: u64| {
+        let mut hosts: Vec<&str> = played
+            .iter()
+            .filter(|turn| turn.agents == agents)
+            .map(|turn| turn.host)
+            .collect();
+        hosts.dedup();
+        hosts
+    };
+    // The heaviest island has no snapshot and stays
+    assert_eq!(hosts_of(600), ["coordinator"]);
+    assert_eq!(hosts_of(50), ["coordinator", "host"]);
+}
+
+#[test]
+fn test_islands_with_snapshots_move_to_less_loaded_host() {
+    check_islands_move(COORDINATOR_IP, HOST_IP, Ports::from(47100));
+}
+
+#[test]
+fn test_islands_move_between_hosts_on_the_same_ip() {
+    check_islands_move(SHARED_IP, SHARED_IP, Ports::from(47200));
+}